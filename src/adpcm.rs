@@ -0,0 +1,467 @@
+use alloc::vec::Vec;
+
+#[cfg(not(test))]
+use crate::{
+    mixer::Source,
+    pi::{self, CART_DOM1_ADDR2, DmaBuffer},
+    system::PhysAddr,
+};
+
+pub const VADPCM_FRAME_BYTES: usize = 9;
+pub const VADPCM_FRAME_SAMPLES: usize = 16;
+pub const VADPCM_MAX_ORDER: usize = 8;
+pub const IMA_FRAME_BYTES: usize = 8;
+pub const IMA_FRAME_SAMPLES: usize = 16;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum AdpcmError {
+    Predictor(u8),
+}
+
+// A frame based decoder that keeps its own prediction state between frames.
+pub trait Decoder {
+    const FRAME_BYTES: usize;
+    const FRAME_SAMPLES: usize;
+    fn reset(&mut self);
+    fn decode_frame(&mut self, frame: &[u8], out: &mut [i16]) -> Result<(), AdpcmError>;
+}
+
+#[inline]
+const fn clamp16(v: i32) -> i16 {
+    if v > i16::MAX as i32 {
+        i16::MAX
+    } else if v < i16::MIN as i32 {
+        i16::MIN
+    } else {
+        v as i16
+    }
+}
+
+// Sign extends a 4 bit nibble.
+#[inline]
+const fn nibble(v: u8) -> i32 {
+    ((v as i32) << 28) >> 28
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct VadpcmBook {
+    order: usize,
+    // Per predictor, the 8 rows of `order + 8` taps that produce each output
+    // sample from the previous `order` samples and the 8 residuals, laid out
+    // the same way as the SDK tools expand the codebook.
+    rows: Vec<[[i32; VADPCM_MAX_ORDER + 8]; 8]>,
+}
+
+impl VadpcmBook {
+    // `coefs` is the codebook as stored in the AIFC chunk: for each
+    // predictor, `order` vectors of 8 coefficients.
+    pub fn new(order: usize, coefs: &[i16]) -> Option<Self> {
+        if order == 0 || order > VADPCM_MAX_ORDER || !coefs.len().is_multiple_of(order * 8) {
+            return None;
+        }
+        let rows = coefs
+            .chunks_exact(order * 8)
+            .map(|book| {
+                let mut t = [[0; VADPCM_MAX_ORDER + 8]; 8];
+                for j in 0..order {
+                    for k in 0..8 {
+                        t[k][j] = book[j * 8 + k] as i32;
+                    }
+                }
+                for k in 1..8 {
+                    t[k][order] = t[k - 1][order - 1];
+                }
+                t[0][order] = 1 << 11;
+                for k in 1..8 {
+                    for j in k..8 {
+                        t[j][k + order] = t[j - k][order];
+                    }
+                }
+                t
+            })
+            .collect();
+        Some(Self { order, rows })
+    }
+    #[inline]
+    pub const fn order(&self) -> usize {
+        self.order
+    }
+    #[inline]
+    pub fn predictors(&self) -> usize {
+        self.rows.len()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct VadpcmDecoder {
+    book: VadpcmBook,
+    state: [i16; VADPCM_FRAME_SAMPLES],
+}
+
+impl VadpcmDecoder {
+    #[inline]
+    pub const fn new(book: VadpcmBook) -> Self {
+        Self {
+            book,
+            state: [0; VADPCM_FRAME_SAMPLES],
+        }
+    }
+    #[inline]
+    pub const fn book(&self) -> &VadpcmBook {
+        &self.book
+    }
+}
+
+impl Decoder for VadpcmDecoder {
+    const FRAME_BYTES: usize = VADPCM_FRAME_BYTES;
+    const FRAME_SAMPLES: usize = VADPCM_FRAME_SAMPLES;
+
+    #[inline]
+    fn reset(&mut self) {
+        self.state = [0; VADPCM_FRAME_SAMPLES];
+    }
+    // Each half frame is predicted from the last `order` outputs before it.
+    // Outputs are clamped before being fed back, as the RSP microcode does.
+    fn decode_frame(&mut self, frame: &[u8], out: &mut [i16]) -> Result<(), AdpcmError> {
+        let order = self.book.order;
+        let scale = 1 << (frame[0] >> 4);
+        let predictor = frame[0] & 0x0F;
+        let Some(rows) = self.book.rows.get(predictor as usize) else {
+            return Err(AdpcmError::Predictor(predictor));
+        };
+        let mut residual = [0; VADPCM_FRAME_SAMPLES];
+        for (i, &b) in frame[1..VADPCM_FRAME_BYTES].iter().enumerate() {
+            residual[i * 2] = nibble(b >> 4) * scale;
+            residual[i * 2 + 1] = nibble(b & 0x0F) * scale;
+        }
+        let mut input = [0; VADPCM_MAX_ORDER + 8];
+        for half in 0..2 {
+            let history = match half {
+                0 => VADPCM_FRAME_SAMPLES - order,
+                _ => 8 - order,
+            };
+            for (x, &s) in input.iter_mut().zip(&self.state[history..history + order]) {
+                *x = s as i32;
+            }
+            input[order..order + 8].copy_from_slice(&residual[half * 8..half * 8 + 8]);
+            for (i, row) in rows.iter().enumerate() {
+                let total: i32 = row[..order + 8]
+                    .iter()
+                    .zip(&input)
+                    .map(|(&c, &x)| c * x)
+                    .sum();
+                self.state[half * 8 + i] = clamp16(total >> 11);
+            }
+        }
+        out[..VADPCM_FRAME_SAMPLES].copy_from_slice(&self.state);
+        Ok(())
+    }
+}
+
+const IMA_INDEX_TABLE: [i8; 8] = [-1, -1, -1, -1, 2, 4, 6, 8];
+const IMA_STEP_TABLE: [u16; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66,
+    73, 80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408, 449,
+    494, 544, 598, 658, 724, 796, 876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066, 2272,
+    2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132, 7845, 8630, 9493,
+    10442, 11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794, 32767,
+];
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct ImaDecoder {
+    pub predictor: i16,
+    pub index: u8,
+}
+
+impl ImaDecoder {
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            predictor: 0,
+            index: 0,
+        }
+    }
+    pub fn decode_nibble(&mut self, code: u8) -> i16 {
+        let step = IMA_STEP_TABLE[self.index as usize] as i32;
+        let mut diff = step >> 3;
+        if code & 4 != 0 {
+            diff += step;
+        }
+        if code & 2 != 0 {
+            diff += step >> 1;
+        }
+        if code & 1 != 0 {
+            diff += step >> 2;
+        }
+        let predictor = if code & 8 != 0 {
+            self.predictor as i32 - diff
+        } else {
+            self.predictor as i32 + diff
+        };
+        self.predictor = clamp16(predictor);
+        self.index =
+            (self.index as i32 + IMA_INDEX_TABLE[(code & 7) as usize] as i32).clamp(0, 88) as u8;
+        self.predictor
+    }
+    // Two samples per byte, low nibble first as in WAV files.
+    pub fn decode(&mut self, data: &[u8], out: &mut [i16]) {
+        for (&b, out) in data.iter().zip(out.chunks_exact_mut(2)) {
+            out[0] = self.decode_nibble(b & 0x0F);
+            out[1] = self.decode_nibble(b >> 4);
+        }
+    }
+}
+
+impl Decoder for ImaDecoder {
+    const FRAME_BYTES: usize = IMA_FRAME_BYTES;
+    const FRAME_SAMPLES: usize = IMA_FRAME_SAMPLES;
+
+    #[inline]
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+    #[inline]
+    fn decode_frame(&mut self, frame: &[u8], out: &mut [i16]) -> Result<(), AdpcmError> {
+        self.decode(&frame[..IMA_FRAME_BYTES], out);
+        Ok(())
+    }
+}
+
+#[cfg(not(test))]
+const STREAM_CHUNK_FRAMES: usize = 16;
+#[cfg(not(test))]
+const STREAM_CHUNK_MAX: usize = STREAM_CHUNK_FRAMES * VADPCM_FRAME_BYTES;
+
+// Compressed audio read from ROM a chunk of frames at a time, so that only a
+// few hundred bytes of it are ever in RAM. `rom` is an offset into the
+// cartridge ROM and must be even, as PI DMA requires. A frame that fails to
+// decode ends the stream, and the error is kept for `error`.
+#[cfg(not(test))]
+pub struct RomStream<D> {
+    decoder: D,
+    rom: PhysAddr,
+    frames: usize,
+    frame: usize,
+    rate: u32,
+    looping: bool,
+    chunk: DmaBuffer<STREAM_CHUNK_MAX>,
+    chunk_frame: usize,
+    chunk_frames: usize,
+    pcm: [i16; VADPCM_FRAME_SAMPLES],
+    pcm_pos: usize,
+    pcm_len: usize,
+    error: Option<AdpcmError>,
+}
+
+#[cfg(not(test))]
+impl<D: Decoder> RomStream<D> {
+    pub fn new(decoder: D, rom: PhysAddr, len: usize, rate: u32) -> Self {
+        assert!(rom.is_multiple_of(2) && D::FRAME_SAMPLES <= VADPCM_FRAME_SAMPLES);
+        Self {
+            decoder,
+            rom,
+            frames: len / D::FRAME_BYTES,
+            frame: 0,
+            rate,
+            looping: false,
+            chunk: DmaBuffer::new(),
+            chunk_frame: 0,
+            chunk_frames: 0,
+            pcm: [0; VADPCM_FRAME_SAMPLES],
+            pcm_pos: 0,
+            pcm_len: 0,
+            error: None,
+        }
+    }
+    #[inline]
+    pub fn set_looping(&mut self, looping: bool) {
+        self.looping = looping;
+    }
+    #[inline]
+    pub fn decoder(&mut self) -> &mut D {
+        &mut self.decoder
+    }
+    #[inline]
+    pub const fn error(&self) -> Option<AdpcmError> {
+        self.error
+    }
+    pub fn rewind(&mut self) {
+        self.decoder.reset();
+        self.frame = 0;
+        self.chunk_frames = 0;
+        self.pcm_pos = 0;
+        self.pcm_len = 0;
+    }
+    fn load_chunk(&mut self) {
+        let frames = (self.frames - self.frame).min(STREAM_CHUNK_FRAMES);
+        let len = (frames * D::FRAME_BYTES + 1) & !1;
+        let offset = (self.frame * D::FRAME_BYTES) as PhysAddr;
+        pi::dma_read(
+            CART_DOM1_ADDR2 + self.rom + offset,
+            &mut self.chunk.0[..len],
+        );
+        self.chunk_frame = 0;
+        self.chunk_frames = frames;
+    }
+    fn decode_next(&mut self) -> bool {
+        if self.error.is_some() {
+            return false;
+        }
+        if self.frame == self.frames {
+            if !self.looping || self.frames == 0 {
+                return false;
+            }
+            self.rewind();
+        }
+        if self.chunk_frame == self.chunk_frames {
+            self.load_chunk();
+        }
+        let start = self.chunk_frame * D::FRAME_BYTES;
+        let frame = &self.chunk.0[start..start + D::FRAME_BYTES];
+        if let Err(err) = self.decoder.decode_frame(frame, &mut self.pcm) {
+            self.error = Some(err);
+            return false;
+        }
+        self.chunk_frame += 1;
+        self.frame += 1;
+        self.pcm_pos = 0;
+        self.pcm_len = D::FRAME_SAMPLES;
+        true
+    }
+}
+
+#[cfg(not(test))]
+impl<D: Decoder> Source for RomStream<D> {
+    #[inline]
+    fn rate(&self) -> u32 {
+        self.rate
+    }
+    fn read(&mut self, out: &mut [i16]) -> usize {
+        let mut written = 0;
+        while written < out.len() {
+            if self.pcm_pos == self.pcm_len && !self.decode_next() {
+                break;
+            }
+            let n = (self.pcm_len - self.pcm_pos).min(out.len() - written);
+            out[written..written + n].copy_from_slice(&self.pcm[self.pcm_pos..self.pcm_pos + n]);
+            self.pcm_pos += n;
+            written += n;
+        }
+        written
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Encoded with the Intel/DVI reference encoder (Python's audioop), nibbles
+    // swapped into WAV order. The expected output is the encoder's own
+    // reconstruction.
+    const IMA_DATA: [u8; 32] = [
+        0x7F, 0x77, 0x77, 0x77, 0x84, 0x03, 0xAF, 0x09, 0xC9, 0x0B, 0x81, 0x8D, 0x41, 0x90, 0x60,
+        0x02, 0x18, 0x14, 0x98, 0x28, 0xD1, 0x8C, 0xA1, 0xBC, 0x18, 0xC9, 0x2A, 0x85, 0x09, 0x35,
+        0x80, 0x51,
+    ];
+    const IMA_OUT: [i16; 64] = [
+        -11, 19, 82, 218, 511, 1142, 2499, 5409, 9151, 8648, 11850, 12265, 6595, 2543, 334, 1003,
+        -822, -5803, -10490, -9882, -8222, -8725, -13757, -14426, -12601, -7620, -6951, -8776,
+        -8223, -1681, 2776, 3586, 2850, 4858, 10337, 12546, 11877, 10052, 9499, 12015, 13387, 8814,
+        3335, 2599, 4607, 1564, -3417, -8104, -8712, -7052, -8561, -12678, -15445, -12929, -7897,
+        -8566, -10391, -9838, -4303, 853, 1522, 914, 2574, 8109,
+    ];
+
+    // An order 2 book with two predictors, as tabledesign lays it out, and
+    // four frames encoded against it that pick both predictors, several
+    // scales and clip on the way back up.
+    const VADPCM_BOOK: [i16; 32] = [
+        -1434, -2294, -2666, -2661, -2391, -1963, -1467, -973, 3277, 3809, 3801, 3415, 2804, 2095,
+        1390, 757, 205, 102, 72, 46, 30, 20, 13, 8, 1024, 717, 461, 302, 197, 129, 84, 55,
+    ];
+    const VADPCM_DATA: [u8; 36] = [
+        0x80, 0x16, 0x7F, 0x52, 0x21, 0x11, 0x0F, 0x10, 0x11, 0x80, 0x23, 0x62, 0x65, 0x56, 0x53,
+        0x64, 0x21, 0x31, 0xC1, 0x00, 0x00, 0x10, 0x11, 0x78, 0x42, 0x11, 0x11, 0x70, 0x7C, 0x11,
+        0xCE, 0x0F, 0xD6, 0xE2, 0x56, 0xF7,
+    ];
+    const VADPCM_OUT: [i16; 64] = [
+        256, 1945, 4725, 5943, 7480, 8321, 8589, 8174, 7321, 6247, 4875, 3165, 1912, 839, 264, 90,
+        471, 1458, 3540, 5155, 7306, 9361, 11143, 12813, 13979, 14165, 14420, 14172, 13100, 11290,
+        9666, 7818, 4876, 3218, 2099, 1369, 4989, 2634, 5912, 7313, 32767, -15578, 11891, 12577,
+        11570, 11144, 10829, 10615, 10298, 8534, 6579, 4673, 2365, 252, -1248, -2303, -3196, -2732,
+        -2391, -1657, -338, 1388, 2329, 3650,
+    ];
+
+    fn decode_all<D: Decoder>(decoder: &mut D, data: &[u8]) -> Result<Vec<i16>, AdpcmError> {
+        let mut out = Vec::new();
+        let mut pcm = [0; VADPCM_FRAME_SAMPLES];
+        for frame in data.chunks_exact(D::FRAME_BYTES) {
+            decoder.decode_frame(frame, &mut pcm)?;
+            out.extend_from_slice(&pcm[..D::FRAME_SAMPLES]);
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn ima() {
+        let mut decoder = ImaDecoder::new();
+        assert_eq!(decode_all(&mut decoder, &IMA_DATA).unwrap(), IMA_OUT);
+        assert_eq!(decoder.predictor, 8109);
+        assert_eq!(decoder.index, 70);
+        decoder.reset();
+        assert_eq!(decoder, ImaDecoder::new());
+    }
+
+    #[test]
+    fn ima_clamp() {
+        let mut decoder = ImaDecoder {
+            predictor: 32000,
+            index: 88,
+        };
+        let mut out = [0; 2];
+        decoder.decode(&[0x77], &mut out);
+        assert_eq!(out, [i16::MAX, i16::MAX]);
+        assert_eq!(decoder.index, 88);
+        decoder.decode(&[0xFF], &mut out);
+        assert_eq!(out, [-28669, i16::MIN]);
+    }
+
+    #[test]
+    fn vadpcm() {
+        let book = VadpcmBook::new(2, &VADPCM_BOOK).unwrap();
+        assert_eq!(book.order(), 2);
+        assert_eq!(book.predictors(), 2);
+        let mut decoder = VadpcmDecoder::new(book);
+        assert_eq!(decode_all(&mut decoder, &VADPCM_DATA).unwrap(), VADPCM_OUT);
+        // Prediction state carries over between frames until reset.
+        decoder.reset();
+        let first = decode_all(&mut decoder, &VADPCM_DATA[..9]).unwrap();
+        assert_eq!(first, VADPCM_OUT[..16]);
+    }
+
+    #[test]
+    fn vadpcm_predictor() {
+        let book = VadpcmBook::new(2, &VADPCM_BOOK).unwrap();
+        let mut decoder = VadpcmDecoder::new(book);
+        let mut frame = VADPCM_DATA;
+        frame[9] = 0x82;
+        assert_eq!(
+            decode_all(&mut decoder, &frame),
+            Err(AdpcmError::Predictor(2))
+        );
+    }
+
+    #[test]
+    fn vadpcm_book() {
+        assert!(VadpcmBook::new(0, &[]).is_none());
+        assert!(VadpcmBook::new(9, &[0; 72]).is_none());
+        assert!(VadpcmBook::new(2, &VADPCM_BOOK[..24]).is_none());
+        // With no prediction the output is just the scaled residuals.
+        let mut decoder = VadpcmDecoder::new(VadpcmBook::new(1, &[0; 8]).unwrap());
+        let mut out = [0; VADPCM_FRAME_SAMPLES];
+        decoder
+            .decode_frame(&[0x20, 0x17, 0x8F, 0, 0, 0, 0, 0, 0x70], &mut out)
+            .unwrap();
+        assert_eq!(out[..4], [4, 28, -32, -4]);
+        assert_eq!(out[14..], [28, 0]);
+    }
+}
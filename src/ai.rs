@@ -0,0 +1,215 @@
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::ptr::NonNull;
+
+use crate::system::{self, TvType};
+
+const AI_REGS: NonNull<u32> = unsafe { NonNull::new_unchecked(0xA4500000 as *mut u32) };
+const AI_DRAM_ADDR_REG: usize = 0;
+const AI_LENGTH_REG: usize = 1;
+const AI_CONTROL_REG: usize = 2;
+const AI_STATUS_REG: usize = 3;
+const AI_DACRATE_REG: usize = 4;
+const AI_BITRATE_REG: usize = 5;
+
+const AI_CONTROL_DMA_ENABLE: u32 = 1 << 0;
+const AI_STATUS_BUSY: u32 = 1 << 30;
+const AI_STATUS_FULL: u32 = 1 << 31;
+const AI_MAX_LENGTH: usize = 0x3FFF8;
+
+pub const MAX_BUFFERS: usize = 8;
+// Each frame is a left and right i16 sample.
+pub const FRAME_SIZE: usize = 4;
+
+#[inline]
+pub fn dac_clock() -> u32 {
+    match system::tv_type() {
+        TvType::Pal => 49_656_530,
+        TvType::Ntsc => 48_681_812,
+        TvType::Mpal => 48_628_316,
+    }
+}
+
+pub type Callback = fn(&mut [i16]);
+
+struct State {
+    buffers: Vec<Box<[u64]>>,
+    frequency: u32,
+    callback: Option<Callback>,
+    // Next buffer for the application to fill, next buffer to hand to the
+    // AI, and how many of each are outstanding.
+    write: usize,
+    play: usize,
+    queued: usize,
+    in_flight: usize,
+}
+
+static mut STATE: State = State {
+    buffers: Vec::new(),
+    frequency: 0,
+    callback: None,
+    write: 0,
+    play: 0,
+    queued: 0,
+    in_flight: 0,
+};
+
+fn with_state<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    let status = system::interrupts_disable();
+    let r = f(unsafe { (&raw mut STATE).as_mut().unwrap_unchecked() });
+    system::interrupts_restore(status);
+    r
+}
+
+#[inline]
+fn samples(buf: &mut [u64]) -> &mut [i16] {
+    unsafe { core::slice::from_raw_parts_mut(buf.as_mut_ptr().cast(), buf.len() * 4) }
+}
+
+impl State {
+    // The AI only reports whether its two entry DMA queue is busy or full,
+    // so buffers are retired by comparing that against what was queued.
+    fn pump(&mut self) {
+        if self.buffers.is_empty() {
+            return;
+        }
+        let status = unsafe { AI_REGS.add(AI_STATUS_REG).read_volatile() };
+        let in_flight = if status & AI_STATUS_FULL != 0 {
+            2
+        } else if status & AI_STATUS_BUSY != 0 {
+            1
+        } else {
+            0
+        };
+        let done = self.in_flight.saturating_sub(in_flight);
+        self.queued -= done;
+        self.in_flight = in_flight.min(self.in_flight);
+        if let Some(callback) = self.callback {
+            while self.queued < self.buffers.len() {
+                let buf = &mut self.buffers[self.write];
+                callback(samples(buf));
+                self.submit();
+            }
+        }
+        while self.in_flight < 2 && self.in_flight < self.queued {
+            let buf = &self.buffers[self.play];
+            let addr = system::physical_addr(NonNull::from(&**buf).cast::<u64>());
+            unsafe {
+                AI_REGS.add(AI_DRAM_ADDR_REG).write_volatile(addr);
+                AI_REGS
+                    .add(AI_LENGTH_REG)
+                    .write_volatile((buf.len() * 8) as u32);
+                AI_REGS
+                    .add(AI_CONTROL_REG)
+                    .write_volatile(AI_CONTROL_DMA_ENABLE);
+            }
+            self.play = (self.play + 1) % self.buffers.len();
+            self.in_flight += 1;
+        }
+    }
+    fn submit(&mut self) {
+        system::data_cache_hit_writeback(&self.buffers[self.write]);
+        self.write = (self.write + 1) % self.buffers.len();
+        self.queued += 1;
+    }
+}
+
+// Sets up playback at the closest rate the DAC can produce and returns it.
+// `frames` is the number of stereo frames per buffer and is rounded up to an
+// even number to keep DMA lengths a multiple of 8 bytes.
+pub fn init(frequency: u32, buffers: usize, frames: usize) -> u32 {
+    assert!((2..=MAX_BUFFERS).contains(&buffers));
+    let frames = (frames + 1) & !1;
+    assert!(frames > 0 && frames * FRAME_SIZE <= AI_MAX_LENGTH);
+    close();
+    let clock = dac_clock();
+    let dacrate = (2 * clock / frequency).div_ceil(2) - 1;
+    let bitrate = (dacrate / 66).clamp(1, 16) - 1;
+    unsafe {
+        AI_REGS.add(AI_DACRATE_REG).write_volatile(dacrate);
+        AI_REGS.add(AI_BITRATE_REG).write_volatile(bitrate);
+    }
+    let frequency = clock / (dacrate + 1);
+    let buffers = (0..buffers)
+        .map(|_| vec![0u64; frames * FRAME_SIZE / 8].into_boxed_slice())
+        .collect();
+    with_state(|state| {
+        *state = State {
+            buffers,
+            frequency,
+            callback: None,
+            write: 0,
+            play: 0,
+            queued: 0,
+            in_flight: 0,
+        };
+    });
+    system::mi_interrupt_enable(system::MI_INTERRUPT_AI);
+    frequency
+}
+
+pub fn close() {
+    system::mi_interrupt_disable(system::MI_INTERRUPT_AI);
+    unsafe { AI_REGS.add(AI_CONTROL_REG).write_volatile(0) };
+    let buffers = with_state(|state| {
+        state.callback = None;
+        core::mem::take(&mut state.buffers)
+    });
+    drop(buffers);
+}
+
+#[inline]
+pub fn frequency() -> u32 {
+    with_state(|state| state.frequency)
+}
+
+#[inline]
+pub fn buffer_frames() -> usize {
+    with_state(|state| {
+        state
+            .buffers
+            .first()
+            .map_or(0, |buf| buf.len() * 8 / FRAME_SIZE)
+    })
+}
+
+// Push mode: the callback runs in interrupt context whenever a buffer frees
+// up and must fill the whole block of interleaved stereo samples.
+pub fn set_callback(callback: Option<Callback>) {
+    with_state(|state| {
+        state.callback = callback;
+        state.pump();
+    });
+}
+
+#[inline]
+pub fn free_buffers() -> usize {
+    with_state(|state| state.buffers.len() - state.queued)
+}
+
+// Pull mode: fills the next free buffer, if there is one, and queues it.
+// Not to be mixed with a callback.
+pub fn fill(f: impl FnOnce(&mut [i16])) -> bool {
+    let buf = with_state(|state| {
+        if state.queued == state.buffers.len() {
+            return None;
+        }
+        let buf = &mut state.buffers[state.write];
+        Some((buf.as_mut_ptr(), buf.len()))
+    });
+    let Some((ptr, len)) = buf else {
+        return false;
+    };
+    f(samples(unsafe {
+        core::slice::from_raw_parts_mut(ptr, len)
+    }));
+    with_state(|state| {
+        state.submit();
+        state.pump();
+    });
+    true
+}
+
+pub(crate) fn handle_interrupt() {
+    unsafe { AI_REGS.add(AI_STATUS_REG).write_volatile(0) };
+    with_state(State::pump);
+}
@@ -0,0 +1,294 @@
+use crate::joybus::{self, Bus, DeviceInfo, JoybusError, PifBlock, Slot};
+
+pub const PORTS: usize = 4;
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+#[repr(transparent)]
+pub struct Buttons(u16);
+
+impl Buttons {
+    pub const NONE: Self = Self(0);
+    pub const A: Self = Self(0x8000);
+    pub const B: Self = Self(0x4000);
+    pub const Z: Self = Self(0x2000);
+    pub const START: Self = Self(0x1000);
+    pub const D_UP: Self = Self(0x0800);
+    pub const D_DOWN: Self = Self(0x0400);
+    pub const D_LEFT: Self = Self(0x0200);
+    pub const D_RIGHT: Self = Self(0x0100);
+    pub const RESET: Self = Self(0x0080);
+    pub const L: Self = Self(0x0020);
+    pub const R: Self = Self(0x0010);
+    pub const C_UP: Self = Self(0x0008);
+    pub const C_DOWN: Self = Self(0x0004);
+    pub const C_LEFT: Self = Self(0x0002);
+    pub const C_RIGHT: Self = Self(0x0001);
+
+    #[inline]
+    pub const fn from_bits(bits: u16) -> Self {
+        Self(bits)
+    }
+    #[inline]
+    pub const fn bits(self) -> u16 {
+        self.0
+    }
+    #[inline]
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+    #[inline]
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+    #[inline]
+    pub const fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+    #[inline]
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+    #[inline]
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+}
+
+impl core::ops::BitOr for Buttons {
+    type Output = Self;
+    #[inline]
+    fn bitor(self, rhs: Self) -> Self {
+        self.union(rhs)
+    }
+}
+
+impl core::ops::BitAnd for Buttons {
+    type Output = Self;
+    #[inline]
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl core::ops::Not for Buttons {
+    type Output = Self;
+    #[inline]
+    fn not(self) -> Self {
+        Self(!self.0)
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct ControllerState {
+    pub buttons: Buttons,
+    pub stick_x: i8,
+    pub stick_y: i8,
+}
+
+impl ControllerState {
+    pub fn parse(rx: &[u8]) -> Result<Self, JoybusError> {
+        let [hi, lo, x, y] = *rx else {
+            return Err(JoybusError::BadResponse);
+        };
+        Ok(Self {
+            buttons: Buttons(u16::from_be_bytes([hi, lo])),
+            stick_x: x as i8,
+            stick_y: y as i8,
+        })
+    }
+    #[inline]
+    pub const fn stick(&self, deadzone: u8) -> (i8, i8) {
+        (
+            apply_deadzone(self.stick_x, deadzone),
+            apply_deadzone(self.stick_y, deadzone),
+        )
+    }
+}
+
+// Values inside the deadzone snap to 0 and the rest of the range is rescaled
+// so that the output still reaches the full i8 range.
+pub const fn apply_deadzone(v: i8, deadzone: u8) -> i8 {
+    let mag = if v == i8::MIN {
+        127
+    } else {
+        v.unsigned_abs() as i32
+    };
+    let dz = deadzone as i32;
+    if mag <= dz || dz >= 127 {
+        return 0;
+    }
+    let scaled = ((mag - dz) * 127 / (127 - dz)) as i8;
+    if v < 0 { -scaled } else { scaled }
+}
+
+pub fn read(block: &mut PifBlock) -> Result<Slot, JoybusError> {
+    block.command(&[joybus::CMD_CONTROLLER_READ], 4)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Controllers {
+    current: [Option<ControllerState>; PORTS],
+    previous: [Option<ControllerState>; PORTS],
+}
+
+impl Controllers {
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            current: [None; PORTS],
+            previous: [None; PORTS],
+        }
+    }
+    pub fn build(block: &mut PifBlock) -> [Slot; PORTS] {
+        core::array::from_fn(|_| read(block).unwrap())
+    }
+    pub fn update(&mut self, block: &PifBlock, slots: &[Slot; PORTS]) {
+        self.previous = self.current;
+        for (state, &slot) in self.current.iter_mut().zip(slots) {
+            *state = block.response(slot).and_then(ControllerState::parse).ok();
+        }
+    }
+    #[cfg(not(test))]
+    #[inline]
+    pub fn poll(&mut self) {
+        self.poll_on(crate::si::Si);
+    }
+    pub fn poll_on(&mut self, mut bus: impl Bus) {
+        let mut block = PifBlock::new();
+        let slots = Self::build(&mut block);
+        block.finish();
+        bus.exchange(&mut block);
+        self.update(&block, &slots);
+    }
+    #[inline]
+    pub fn state(&self, port: usize) -> Option<&ControllerState> {
+        self.current[port].as_ref()
+    }
+    #[inline]
+    pub fn connected(&self, port: usize) -> bool {
+        self.current[port].is_some()
+    }
+    #[inline]
+    pub fn held(&self, port: usize) -> Buttons {
+        self.current[port].map_or(Buttons::NONE, |s| s.buttons)
+    }
+    #[inline]
+    fn held_before(&self, port: usize) -> Buttons {
+        self.previous[port].map_or(Buttons::NONE, |s| s.buttons)
+    }
+    #[inline]
+    pub fn pressed(&self, port: usize) -> Buttons {
+        self.held(port).difference(self.held_before(port))
+    }
+    #[inline]
+    pub fn released(&self, port: usize) -> Buttons {
+        self.held_before(port).difference(self.held(port))
+    }
+    #[inline]
+    pub fn stick(&self, port: usize, deadzone: u8) -> (i8, i8) {
+        self.current[port].map_or((0, 0), |s| s.stick(deadzone))
+    }
+}
+
+pub fn identify(mut bus: impl Bus) -> [Option<DeviceInfo>; PORTS] {
+    let mut block = PifBlock::new();
+    let slots: [Slot; PORTS] = core::array::from_fn(|_| joybus::info(&mut block).unwrap());
+    block.finish();
+    bus.exchange(&mut block);
+    slots.map(|slot| block.response(slot).and_then(DeviceInfo::parse).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Answers controller reads on the ports that have a pad plugged in.
+    struct Pads([Option<[u8; 4]>; PORTS]);
+
+    impl Bus for Pads {
+        fn exchange(&mut self, block: &mut PifBlock) {
+            block.for_each_command(|channel, tx, rx| match (tx, self.0.get(channel)) {
+                ([joybus::CMD_CONTROLLER_READ], Some(Some(state))) => {
+                    rx.copy_from_slice(state);
+                    true
+                }
+                ([joybus::CMD_INFO], Some(Some(_))) => {
+                    rx.copy_from_slice(&[0x05, 0x00, 0x00]);
+                    true
+                }
+                _ => false,
+            });
+        }
+    }
+
+    #[test]
+    fn read_block() {
+        let mut block = PifBlock::new();
+        let slots = Controllers::build(&mut block);
+        block.finish();
+        for (i, slot) in slots.iter().enumerate() {
+            assert_eq!(slot.channel(), i);
+            assert_eq!(
+                block.as_bytes()[i * 7..i * 7 + 3],
+                [1, 4, joybus::CMD_CONTROLLER_READ]
+            );
+        }
+        assert_eq!(block.as_bytes()[28], 0xFE);
+    }
+
+    #[test]
+    fn poll() {
+        let mut pads = Pads([Some([0x90, 0x00, 0x50, 0xB0]), None, None, None]);
+        let mut controllers = Controllers::new();
+        controllers.poll_on(&mut pads);
+        assert!(controllers.connected(0));
+        assert!(!controllers.connected(1));
+        assert_eq!(controllers.held(0), Buttons::A | Buttons::START);
+        assert_eq!(controllers.pressed(0), Buttons::A | Buttons::START);
+        assert_eq!(controllers.stick(0, 0), (80, -80));
+        assert_eq!(controllers.state(0).unwrap().stick_y, -80);
+
+        pads.0[0] = Some([0x80, 0x10, 0, 0]);
+        controllers.poll_on(&mut pads);
+        assert_eq!(controllers.pressed(0), Buttons::R);
+        assert_eq!(controllers.released(0), Buttons::START);
+        assert_eq!(controllers.held(1), Buttons::NONE);
+    }
+
+    #[test]
+    fn identify_pads() {
+        let pads = Pads([None, Some([0; 4]), None, Some([0; 4])]);
+        let info = identify(pads);
+        assert_eq!(info[0], None);
+        assert!(info[1].unwrap().is_controller());
+        assert_eq!(info[2], None);
+        assert!(info[3].unwrap().is_controller());
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            ControllerState::parse(&[0x00, 0x0F, 0x7F, 0x80]),
+            Ok(ControllerState {
+                buttons: Buttons::C_UP | Buttons::C_DOWN | Buttons::C_LEFT | Buttons::C_RIGHT,
+                stick_x: 127,
+                stick_y: -128,
+            })
+        );
+        assert_eq!(
+            ControllerState::parse(&[0; 3]),
+            Err(JoybusError::BadResponse)
+        );
+    }
+
+    #[test]
+    fn deadzone() {
+        assert_eq!(apply_deadzone(10, 10), 0);
+        assert_eq!(apply_deadzone(-10, 10), 0);
+        assert_eq!(apply_deadzone(127, 10), 127);
+        assert_eq!(apply_deadzone(i8::MIN, 10), -127);
+        assert_eq!(apply_deadzone(50, 0), 50);
+        assert_eq!(apply_deadzone(68, 10), 62);
+        assert_eq!(apply_deadzone(1, 127), 0);
+    }
+}
@@ -0,0 +1,183 @@
+use core::{
+    fmt::Write,
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use crate::system;
+
+pub const LOG_LINES: usize = 16;
+pub const LOG_LINE_LEN: usize = 80;
+pub const CRASH_LEN: usize = 512;
+const CRASHLOG_MAGIC: u32 = 0x43524C47;
+
+#[repr(C)]
+struct CrashLog {
+    magic: u32,
+    head: u16,
+    count: u16,
+    crash_len: u16,
+    line_lens: [u8; LOG_LINES],
+    lines: [[u8; LOG_LINE_LEN]; LOG_LINES],
+    crash: [u8; CRASH_LEN],
+}
+
+#[unsafe(link_section = ".noinit.crashlog")]
+static mut CRASHLOG: core::mem::MaybeUninit<CrashLog> = core::mem::MaybeUninit::uninit();
+static RECOVERED: AtomicBool = AtomicBool::new(false);
+static READY: AtomicBool = AtomicBool::new(false);
+static REVISION: AtomicU32 = AtomicU32::new(0);
+
+// The log is always accessed uncached so that nothing is left sitting in a
+// dirty cache line when the console resets.
+#[inline]
+fn log() -> &'static mut CrashLog {
+    let ptr = unsafe { NonNull::new_unchecked(&raw mut CRASHLOG as *mut CrashLog) };
+    unsafe { &mut *system::uncached_addr(ptr).as_ptr() }
+}
+
+pub fn init() {
+    let log = log();
+    let valid = log.magic == CRASHLOG_MAGIC
+        && (log.head as usize) < LOG_LINES
+        && (log.count as usize) <= LOG_LINES
+        && (log.crash_len as usize) <= CRASH_LEN
+        && log
+            .line_lens
+            .iter()
+            .all(|&len| len as usize <= LOG_LINE_LEN);
+    if system::is_warm_boot() && valid {
+        if log.line_lens[log.head as usize] != 0 {
+            newline(log);
+        }
+        RECOVERED.store(true, Ordering::Relaxed);
+    } else {
+        log.magic = CRASHLOG_MAGIC;
+        log.head = 0;
+        log.count = 0;
+        log.crash_len = 0;
+        log.line_lens = [0; LOG_LINES];
+    }
+    READY.store(true, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn recovered() -> bool {
+    RECOVERED.load(Ordering::Relaxed)
+}
+
+fn newline(log: &mut CrashLog) {
+    log.head = ((log.head as usize + 1) % LOG_LINES) as u16;
+    log.count = (log.count + 1).min(LOG_LINES as u16 - 1);
+    log.line_lens[log.head as usize] = 0;
+}
+
+pub fn put(s: &[u8]) {
+    if !READY.load(Ordering::Relaxed) {
+        return;
+    }
+    let log = log();
+    for &c in s {
+        if c == b'\n' {
+            newline(log);
+            continue;
+        }
+        if log.line_lens[log.head as usize] as usize == LOG_LINE_LEN {
+            newline(log);
+        }
+        let head = log.head as usize;
+        let len = log.line_lens[head] as usize;
+        log.lines[head][len] = c;
+        log.line_lens[head] = len as u8 + 1;
+    }
+    REVISION.store(revision().wrapping_add(1), Ordering::Relaxed);
+}
+
+// Changes whenever text is added, so that on-screen logs know to redraw.
+#[inline]
+pub fn revision() -> u32 {
+    REVISION.load(Ordering::Relaxed)
+}
+
+pub fn for_each_line(mut f: impl FnMut(&[u8])) {
+    if !READY.load(Ordering::Relaxed) {
+        return;
+    }
+    let log = log();
+    let head = log.head as usize;
+    let count = log.count as usize;
+    for i in (LOG_LINES + head - count)..(LOG_LINES + head) {
+        let i = i % LOG_LINES;
+        f(&log.lines[i][..log.line_lens[i] as usize]);
+    }
+    if log.line_lens[head] != 0 {
+        f(&log.lines[head][..log.line_lens[head] as usize]);
+    }
+}
+
+pub fn crash() -> Option<&'static [u8]> {
+    if !READY.load(Ordering::Relaxed) {
+        return None;
+    }
+    let log = log();
+    match log.crash_len as usize {
+        0 => None,
+        len => Some(&log.crash[..len]),
+    }
+}
+
+pub fn clear_crash() {
+    if READY.load(Ordering::Relaxed) {
+        log().crash_len = 0;
+    }
+}
+
+struct CrashWriter<'a> {
+    buf: &'a mut [u8; CRASH_LEN],
+    len: usize,
+}
+
+impl Write for CrashWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let n = s.len().min(CRASH_LEN - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+pub fn record_crash(args: core::fmt::Arguments) {
+    if !READY.load(Ordering::Relaxed) {
+        return;
+    }
+    let log = log();
+    log.crash_len = 0;
+    let mut w = CrashWriter {
+        buf: &mut log.crash,
+        len: 0,
+    };
+    let _ = w.write_fmt(args);
+    log.crash_len = w.len as u16;
+}
+
+// Lines are copied out of uncached memory before being handed to the PI.
+pub fn report() {
+    if !recovered() {
+        return;
+    }
+    crate::isv::emit(b"--- log before reset ---\n");
+    for_each_line(|line| {
+        let mut buf = [0u8; LOG_LINE_LEN + 1];
+        buf[..line.len()].copy_from_slice(line);
+        buf[line.len()] = b'\n';
+        crate::isv::emit(&buf[..line.len() + 1]);
+    });
+    if let Some(crash) = crash() {
+        let mut buf = [0u8; CRASH_LEN + 1];
+        buf[..crash.len()].copy_from_slice(crash);
+        buf[crash.len()] = b'\n';
+        crate::isv::emit(b"--- crash before reset ---\n");
+        crate::isv::emit(&buf[..crash.len() + 1]);
+    }
+    crate::isv::emit(b"------------------------\n");
+}
@@ -0,0 +1,285 @@
+use crate::{
+    joybus::{self, Bus, CART_CHANNEL, DeviceInfo, JoybusError, PifBlock, Slot},
+    save::{SaveError, SaveMedia},
+};
+
+pub const BLOCK_SIZE: usize = 8;
+const STATUS_BUSY: u8 = 0x80;
+const MAX_BUSY_POLLS: usize = 4096;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum EepromType {
+    Eeprom4K,
+    Eeprom16K,
+}
+
+impl EepromType {
+    #[inline]
+    pub const fn from_identifier(identifier: u16) -> Option<Self> {
+        match identifier {
+            DeviceInfo::EEPROM_4K => Some(Self::Eeprom4K),
+            DeviceInfo::EEPROM_16K => Some(Self::Eeprom16K),
+            _ => None,
+        }
+    }
+    #[inline]
+    pub const fn blocks(self) -> usize {
+        match self {
+            Self::Eeprom4K => 64,
+            Self::Eeprom16K => 256,
+        }
+    }
+    #[inline]
+    pub const fn size(self) -> usize {
+        self.blocks() * BLOCK_SIZE
+    }
+}
+
+pub fn read_command(block: &mut PifBlock, index: u8) -> Result<Slot, JoybusError> {
+    block.skip_to(CART_CHANNEL)?;
+    block.command(&[joybus::CMD_EEPROM_READ, index], BLOCK_SIZE)
+}
+
+pub fn write_command(
+    block: &mut PifBlock,
+    index: u8,
+    data: &[u8; BLOCK_SIZE],
+) -> Result<Slot, JoybusError> {
+    block.skip_to(CART_CHANNEL)?;
+    let mut tx = [0; 2 + BLOCK_SIZE];
+    tx[..2].copy_from_slice(&[joybus::CMD_EEPROM_WRITE, index]);
+    tx[2..].copy_from_slice(data);
+    block.command(&tx, 1)
+}
+
+fn info(bus: &mut impl Bus) -> Result<DeviceInfo, JoybusError> {
+    let mut block = PifBlock::new();
+    block.skip_to(CART_CHANNEL)?;
+    let slot = joybus::info(&mut block)?;
+    block.finish();
+    bus.exchange(&mut block);
+    DeviceInfo::parse(block.response(slot)?)
+}
+
+pub fn detect(mut bus: impl Bus) -> Option<EepromType> {
+    info(&mut bus)
+        .ok()
+        .and_then(|info| EepromType::from_identifier(info.identifier))
+}
+
+pub struct Eeprom<B> {
+    bus: B,
+    ty: EepromType,
+}
+
+impl<B: Bus> Eeprom<B> {
+    pub fn new(mut bus: B) -> Result<Self, SaveError> {
+        let ty = detect(&mut bus).ok_or(SaveError::NotPresent)?;
+        Ok(Self { bus, ty })
+    }
+    #[inline]
+    pub const fn with_type(bus: B, ty: EepromType) -> Self {
+        Self { bus, ty }
+    }
+    #[inline]
+    pub const fn eeprom_type(&self) -> EepromType {
+        self.ty
+    }
+    // A write keeps the chip busy for up to ~15ms, during which it reports
+    // the busy bit in its status byte.
+    fn wait_ready(&mut self) -> Result<(), SaveError> {
+        for _ in 0..MAX_BUSY_POLLS {
+            if info(&mut self.bus)?.status & STATUS_BUSY == 0 {
+                return Ok(());
+            }
+        }
+        Err(SaveError::Busy)
+    }
+    pub fn read_block(
+        &mut self,
+        index: usize,
+        data: &mut [u8; BLOCK_SIZE],
+    ) -> Result<(), SaveError> {
+        if index >= self.ty.blocks() {
+            return Err(SaveError::OutOfRange);
+        }
+        let mut block = PifBlock::new();
+        let slot = read_command(&mut block, index as u8)?;
+        block.finish();
+        self.bus.exchange(&mut block);
+        data.copy_from_slice(block.response(slot)?);
+        Ok(())
+    }
+    pub fn write_block(&mut self, index: usize, data: &[u8; BLOCK_SIZE]) -> Result<(), SaveError> {
+        if index >= self.ty.blocks() {
+            return Err(SaveError::OutOfRange);
+        }
+        self.wait_ready()?;
+        let mut block = PifBlock::new();
+        let slot = write_command(&mut block, index as u8, data)?;
+        block.finish();
+        self.bus.exchange(&mut block);
+        block.response(slot)?;
+        Ok(())
+    }
+}
+
+impl<B: Bus> SaveMedia for Eeprom<B> {
+    #[inline]
+    fn capacity(&self) -> usize {
+        self.ty.size()
+    }
+    #[inline]
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+    fn read_blocks(&mut self, block: usize, buf: &mut [u8]) -> Result<(), SaveError> {
+        assert!(buf.len().is_multiple_of(BLOCK_SIZE), "partial EEPROM block");
+        for (i, chunk) in buf.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            self.read_block(block + i, chunk.try_into().unwrap())?;
+        }
+        Ok(())
+    }
+    fn write_blocks(&mut self, block: usize, buf: &[u8]) -> Result<(), SaveError> {
+        assert!(buf.len().is_multiple_of(BLOCK_SIZE), "partial EEPROM block");
+        for (i, chunk) in buf.chunks_exact(BLOCK_SIZE).enumerate() {
+            self.write_block(block + i, chunk.try_into().unwrap())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A cartridge EEPROM that stays busy for a few status polls after each
+    // write, like the real chip does while it programs a block.
+    struct SimEeprom {
+        ty: Option<EepromType>,
+        data: Vec<[u8; BLOCK_SIZE]>,
+        busy: usize,
+        busy_polls: usize,
+        polls: usize,
+    }
+
+    impl SimEeprom {
+        fn new(ty: Option<EepromType>) -> Self {
+            let blocks = ty.map_or(0, EepromType::blocks);
+            Self {
+                ty,
+                data: (0..blocks).map(|i| [i as u8; BLOCK_SIZE]).collect(),
+                busy: 0,
+                busy_polls: 3,
+                polls: 0,
+            }
+        }
+    }
+
+    impl Bus for SimEeprom {
+        fn exchange(&mut self, block: &mut PifBlock) {
+            block.for_each_command(|channel, tx, rx| {
+                let Some(ty) = self.ty.filter(|_| channel == CART_CHANNEL) else {
+                    return false;
+                };
+                match *tx {
+                    [joybus::CMD_INFO] => {
+                        let identifier = match ty {
+                            EepromType::Eeprom4K => DeviceInfo::EEPROM_4K,
+                            EepromType::Eeprom16K => DeviceInfo::EEPROM_16K,
+                        };
+                        let status = if self.busy > 0 { STATUS_BUSY } else { 0 };
+                        self.busy = self.busy.saturating_sub(1);
+                        self.polls += 1;
+                        rx[..2].copy_from_slice(&identifier.to_be_bytes());
+                        rx[2] = status;
+                    }
+                    [joybus::CMD_EEPROM_READ, index] => {
+                        rx.copy_from_slice(&self.data[index as usize]);
+                    }
+                    [joybus::CMD_EEPROM_WRITE, index, ref data @ ..] => {
+                        assert_eq!(self.busy, 0, "write while busy");
+                        self.data[index as usize].copy_from_slice(data);
+                        self.busy = self.busy_polls;
+                        rx[0] = 0;
+                    }
+                    _ => return false,
+                }
+                true
+            });
+        }
+    }
+
+    #[test]
+    fn detection() {
+        for ty in [EepromType::Eeprom4K, EepromType::Eeprom16K] {
+            assert_eq!(detect(SimEeprom::new(Some(ty))), Some(ty));
+            let eeprom = Eeprom::new(SimEeprom::new(Some(ty))).unwrap();
+            assert_eq!(eeprom.eeprom_type(), ty);
+            assert_eq!(eeprom.capacity(), ty.size());
+        }
+        assert_eq!(detect(SimEeprom::new(None)), None);
+        assert!(matches!(
+            Eeprom::new(SimEeprom::new(None)),
+            Err(SaveError::NotPresent)
+        ));
+    }
+
+    #[test]
+    fn read_write() {
+        let mut eeprom = Eeprom::new(SimEeprom::new(Some(EepromType::Eeprom4K))).unwrap();
+        let mut block = [0; BLOCK_SIZE];
+        eeprom.read_block(5, &mut block).unwrap();
+        assert_eq!(block, [5; BLOCK_SIZE]);
+
+        let data: Vec<u8> = (0..3 * BLOCK_SIZE as u8).collect();
+        eeprom.write_blocks(62, &data[..2 * BLOCK_SIZE]).unwrap();
+        eeprom.write_blocks(10, &data[2 * BLOCK_SIZE..]).unwrap();
+        let mut buf = [0; 2 * BLOCK_SIZE];
+        eeprom.read_blocks(62, &mut buf).unwrap();
+        assert_eq!(buf[..], data[..2 * BLOCK_SIZE]);
+        eeprom.read_block(10, &mut block).unwrap();
+        assert_eq!(block[..], data[2 * BLOCK_SIZE..]);
+        eeprom.read_block(11, &mut block).unwrap();
+        assert_eq!(block, [11; BLOCK_SIZE]);
+
+        // Detection, then each write after the first waited out the previous one.
+        assert_eq!(eeprom.bus.polls, 1 + 1 + 2 * (3 + 1));
+
+        assert!(matches!(
+            eeprom.read_block(64, &mut block),
+            Err(SaveError::OutOfRange)
+        ));
+        assert!(matches!(
+            eeprom.write_blocks(63, &data[..2 * BLOCK_SIZE]),
+            Err(SaveError::OutOfRange)
+        ));
+        let mut eeprom = Eeprom::with_type(
+            SimEeprom::new(Some(EepromType::Eeprom16K)),
+            EepromType::Eeprom16K,
+        );
+        eeprom.write_block(255, &[0xAA; BLOCK_SIZE]).unwrap();
+        eeprom.read_block(255, &mut block).unwrap();
+        assert_eq!(block, [0xAA; BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn stuck_busy() {
+        let mut sim = SimEeprom::new(Some(EepromType::Eeprom4K));
+        sim.busy = usize::MAX;
+        let mut eeprom = Eeprom::with_type(sim, EepromType::Eeprom4K);
+        assert!(matches!(
+            eeprom.write_block(0, &[1; BLOCK_SIZE]),
+            Err(SaveError::Busy)
+        ));
+        assert_eq!(eeprom.bus.polls, MAX_BUSY_POLLS);
+        assert_eq!(eeprom.bus.data[0], [0; BLOCK_SIZE]);
+    }
+
+    #[test]
+    #[should_panic(expected = "partial EEPROM block")]
+    fn partial_block() {
+        let mut eeprom = Eeprom::new(SimEeprom::new(Some(EepromType::Eeprom4K))).unwrap();
+        eeprom.write_blocks(0, &[0; BLOCK_SIZE + 1]).unwrap();
+    }
+}
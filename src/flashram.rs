@@ -0,0 +1,221 @@
+use alloc::vec;
+
+use crate::{
+    pi::{self, CART_DOM2_ADDR2, DmaBuffer, DomainTiming},
+    save::{SaveError, SaveMedia},
+    system::PhysAddr,
+};
+
+pub const PAGE_SIZE: usize = 128;
+pub const SECTOR_PAGES: usize = 128;
+pub const SECTOR_SIZE: usize = PAGE_SIZE * SECTOR_PAGES;
+pub const PAGES: usize = 1024;
+pub const SIZE: usize = PAGE_SIZE * PAGES;
+
+const FLASH_CMD_REG: PhysAddr = CART_DOM2_ADDR2 + 0x10000;
+const FLASH_CMD_SECTOR_ERASE: u32 = 0x4B000000;
+const FLASH_CMD_ERASE_MODE: u32 = 0x78000000;
+const FLASH_CMD_PAGE_PROGRAM: u32 = 0xA5000000;
+const FLASH_CMD_WRITE_MODE: u32 = 0xB4000000;
+const FLASH_CMD_EXECUTE: u32 = 0xD2000000;
+const FLASH_CMD_STATUS_MODE: u32 = 0xE1000000;
+const FLASH_CMD_READ_MODE: u32 = 0xF0000000;
+
+const FLASH_STATUS_PROGRAM_BUSY: u32 = 0x01;
+const FLASH_STATUS_ERASE_BUSY: u32 = 0x02;
+const FLASH_STATUS_PROGRAM_OK: u32 = 0x04;
+const FLASH_STATUS_ERASE_OK: u32 = 0x08;
+const MAX_BUSY_POLLS: usize = 0x100000;
+
+const FLASH_TIMING: DomainTiming = DomainTiming {
+    latency: 0x05,
+    pulse_width: 0x0C,
+    page_size: 0x0F,
+    release: 0x02,
+};
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct FlashId {
+    pub kind: u32,
+    pub chip: u32,
+}
+
+impl FlashId {
+    pub const FLASHRAM: u32 = 0x11118001;
+    pub const MX_PROTO: u32 = 0x00C20000;
+    pub const MX_A: u32 = 0x00C20001;
+    pub const MX_B_D: u32 = 0x00C2001D;
+    pub const MX_C: u32 = 0x00C2001E;
+    pub const MEI: u32 = 0x003200F1;
+
+    // Only the first Macronix parts take byte addresses in read mode, every
+    // later chip is addressed in 16 bit words.
+    #[inline]
+    pub const fn word_addressed(&self) -> bool {
+        !matches!(self.chip, Self::MX_PROTO | Self::MX_A)
+    }
+}
+
+#[inline]
+fn command(cmd: u32) {
+    pi::io_write(FLASH_CMD_REG, cmd);
+}
+
+#[inline]
+fn status() -> u32 {
+    command(FLASH_CMD_STATUS_MODE);
+    pi::io_read(CART_DOM2_ADDR2) & 0xFF
+}
+
+#[inline]
+fn clear_status() {
+    command(FLASH_CMD_STATUS_MODE);
+    pi::io_write(CART_DOM2_ADDR2, 0);
+}
+
+fn wait_ready(busy: u32) -> Result<u32, SaveError> {
+    for _ in 0..MAX_BUSY_POLLS {
+        let status = status();
+        if status & busy == 0 {
+            clear_status();
+            return Ok(status);
+        }
+    }
+    Err(SaveError::Busy)
+}
+
+pub fn identify() -> Result<FlashId, SaveError> {
+    pi::set_dom2_timing(FLASH_TIMING);
+    let mut id = DmaBuffer::<8>::new();
+    command(FLASH_CMD_STATUS_MODE);
+    pi::dma_read(CART_DOM2_ADDR2, &mut id.0);
+    let [k0, k1, k2, k3, c0, c1, c2, c3] = id.0;
+    let id = FlashId {
+        kind: u32::from_be_bytes([k0, k1, k2, k3]),
+        chip: u32::from_be_bytes([c0, c1, c2, c3]),
+    };
+    if id.kind != FlashId::FLASHRAM {
+        return Err(SaveError::NotPresent);
+    }
+    Ok(id)
+}
+
+pub struct FlashRam {
+    id: FlashId,
+}
+
+impl FlashRam {
+    #[inline]
+    pub fn new() -> Result<Self, SaveError> {
+        Ok(Self { id: identify()? })
+    }
+    #[inline]
+    pub const fn id(&self) -> FlashId {
+        self.id
+    }
+    pub fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), SaveError> {
+        match offset.checked_add(buf.len()) {
+            Some(end) if end <= SIZE => {}
+            _ => return Err(SaveError::OutOfRange),
+        }
+        let shift = self.id.word_addressed() as u32;
+        let mut dma = DmaBuffer::<PAGE_SIZE>::new();
+        let mut pos = 0;
+        command(FLASH_CMD_READ_MODE);
+        while pos < buf.len() {
+            let addr = offset + pos;
+            let start = addr % PAGE_SIZE;
+            let n = (PAGE_SIZE - start).min(buf.len() - pos);
+            let page = (addr - start) as PhysAddr;
+            pi::dma_read(CART_DOM2_ADDR2 + (page >> shift), &mut dma.0);
+            buf[pos..pos + n].copy_from_slice(&dma.0[start..start + n]);
+            pos += n;
+        }
+        Ok(())
+    }
+    pub fn erase_sector(&mut self, sector: usize) -> Result<(), SaveError> {
+        if sector >= PAGES / SECTOR_PAGES {
+            return Err(SaveError::OutOfRange);
+        }
+        command(FLASH_CMD_SECTOR_ERASE | (sector * SECTOR_PAGES) as u32);
+        command(FLASH_CMD_ERASE_MODE);
+        command(FLASH_CMD_EXECUTE);
+        if wait_ready(FLASH_STATUS_ERASE_BUSY)? & FLASH_STATUS_ERASE_OK == 0 {
+            return Err(SaveError::Failed);
+        }
+        Ok(())
+    }
+    // Programming can only clear bits, the page must have been erased first.
+    pub fn program_page(&mut self, page: usize, data: &[u8; PAGE_SIZE]) -> Result<(), SaveError> {
+        if page >= PAGES {
+            return Err(SaveError::OutOfRange);
+        }
+        let dma = DmaBuffer(*data);
+        command(FLASH_CMD_WRITE_MODE);
+        pi::dma_write(CART_DOM2_ADDR2, &dma.0);
+        command(FLASH_CMD_PAGE_PROGRAM | page as u32);
+        command(FLASH_CMD_EXECUTE);
+        if wait_ready(FLASH_STATUS_PROGRAM_BUSY)? & FLASH_STATUS_PROGRAM_OK == 0 {
+            return Err(SaveError::Failed);
+        }
+        Ok(())
+    }
+    fn rewrite_sector(&mut self, page: usize, data: &[u8; PAGE_SIZE]) -> Result<(), SaveError> {
+        let sector = page / SECTOR_PAGES;
+        let first = sector * SECTOR_PAGES;
+        let mut contents = vec![0; SECTOR_SIZE];
+        self.read(first * PAGE_SIZE, &mut contents)?;
+        let start = (page - first) * PAGE_SIZE;
+        contents[start..start + PAGE_SIZE].copy_from_slice(data);
+        self.erase_sector(sector)?;
+        for (i, chunk) in contents.chunks_exact(PAGE_SIZE).enumerate() {
+            if chunk.iter().all(|&b| b == 0xFF) {
+                continue;
+            }
+            self.program_page(first + i, chunk.try_into().unwrap())?;
+        }
+        Ok(())
+    }
+    // Pages that only need bits cleared are programmed in place, anything
+    // else costs an erase of the whole sector around them.
+    pub fn write_page(&mut self, page: usize, data: &[u8; PAGE_SIZE]) -> Result<(), SaveError> {
+        if page >= PAGES {
+            return Err(SaveError::OutOfRange);
+        }
+        let mut old = [0; PAGE_SIZE];
+        self.read(page * PAGE_SIZE, &mut old)?;
+        if old == *data {
+            return Ok(());
+        }
+        if old.iter().zip(data).all(|(&o, &n)| o & n == n) {
+            self.program_page(page, data)
+        } else {
+            self.rewrite_sector(page, data)
+        }
+    }
+}
+
+impl SaveMedia for FlashRam {
+    #[inline]
+    fn capacity(&self) -> usize {
+        SIZE
+    }
+    #[inline]
+    fn block_size(&self) -> usize {
+        PAGE_SIZE
+    }
+    #[inline]
+    fn erase_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+    #[inline]
+    fn read_blocks(&mut self, block: usize, buf: &mut [u8]) -> Result<(), SaveError> {
+        self.read(block * PAGE_SIZE, buf)
+    }
+    fn write_blocks(&mut self, block: usize, buf: &[u8]) -> Result<(), SaveError> {
+        for (i, page) in buf.chunks_exact(PAGE_SIZE).enumerate() {
+            self.write_page(block + i, page.try_into().unwrap())?;
+        }
+        Ok(())
+    }
+}
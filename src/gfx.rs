@@ -1,14 +1,27 @@
-use crate::system;
+#[cfg(not(test))]
+use crate::system::{cached_addr, uncached_addr};
+use alloc::vec::Vec;
 use arbitrary_int::prelude::*;
-use core::{alloc::Layout, ptr::NonNull};
+use core::{alloc::Layout, marker::PhantomData, ptr::NonNull};
 use embedded_graphics::{
     pixelcolor::{
         Rgb555, Rgb888,
-        raw::{RawU16, RawU32},
+        raw::{RawU4, RawU8, RawU16, RawU32},
     },
     prelude::*,
+    primitives::Rectangle,
 };
 
+// The host has no uncached segment, tests use memory as allocated.
+#[cfg(test)]
+fn uncached_addr<T>(addr: NonNull<T>) -> NonNull<T> {
+    addr
+}
+#[cfg(test)]
+unsafe fn cached_addr<T>(addr: NonNull<T>) -> NonNull<T> {
+    addr
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
 #[repr(transparent)]
 pub struct RGBA8888(RawU32);
@@ -26,17 +39,10 @@ impl RGBA8888 {
     pub const fn new(r: u8, g: u8, b: u8, a: u8) -> Self {
         Self::from_u32(((r as u32) << 24) | ((g as u32) << 16) | ((b as u32) << 8) | a as u32)
     }
+    // Color with sRGB-encoded `r`, `g` and `b` converted to linear.
     #[inline]
     pub const fn linear(r: u8, g: u8, b: u8, a: u8) -> Self {
-        let r = r as u32;
-        let g = g as u32;
-        let b = b as u32;
-        Self::new(
-            ((r * r) >> 8) as u8,
-            ((g * g) >> 8) as u8,
-            ((b * b) >> 8) as u8,
-            a,
-        )
+        Self::new(srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b), a)
     }
     #[inline]
     pub const fn into_u32(self) -> u32 {
@@ -154,14 +160,12 @@ impl RGBA5551 {
         let a = a.value() as u16;
         Self::from_u16((r << 11) | (g << 6) | (b << 1) | a)
     }
+    // Color with sRGB-encoded `r`, `g` and `b` converted to linear.
     #[inline]
     pub const fn linear(r: u5, g: u5, b: u5, a: u1) -> Self {
-        let r = r.value() as u32;
-        let g = g.value() as u32;
-        let b = b.value() as u32;
-        let r = unsafe { u5::new_unchecked(((r * r) >> 5) as _) };
-        let g = unsafe { u5::new_unchecked(((g * g) >> 5) as _) };
-        let b = unsafe { u5::new_unchecked(((b * b) >> 5) as _) };
+        let r = unsafe { u5::new_unchecked(SRGB5_TO_LINEAR5[r.value() as usize]) };
+        let g = unsafe { u5::new_unchecked(SRGB5_TO_LINEAR5[g.value() as usize]) };
+        let b = unsafe { u5::new_unchecked(SRGB5_TO_LINEAR5[b.value() as usize]) };
         Self::new(r, g, b, a)
     }
     #[inline]
@@ -254,114 +258,2952 @@ impl From<RawU16> for RGBA5551 {
     }
 }
 
-pub struct Surface<P> {
-    ptr: NonNull<P>,
-    width: u16,
-    height: u16,
+// RDP texel formats.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Format {
+    Rgba16,
+    Rgba32,
+    Ia4,
+    Ia8,
+    Ia16,
+    I4,
+    I8,
+    Ci4,
+    Ci8,
 }
 
-impl<P> Surface<P> {
+impl Format {
     #[inline]
-    const unsafe fn layout(width: u16, height: u16, mut align: usize) -> Layout {
-        let size = width as usize * height as usize * size_of::<P>();
-        if align < 16 {
-            align = 16;
-        }
-        if align < size_of::<P>() {
-            align = size_of::<P>();
+    pub const fn bits(self) -> usize {
+        match self {
+            Self::Ia4 | Self::I4 | Self::Ci4 => 4,
+            Self::Ia8 | Self::I8 | Self::Ci8 => 8,
+            Self::Rgba16 | Self::Ia16 => 16,
+            Self::Rgba32 => 32,
         }
-        unsafe { Layout::from_size_align_unchecked(size, align) }
     }
-    fn _new(width: u16, height: u16, align: usize) -> Self {
-        let layout = unsafe { Self::layout(width, height, align) };
-        let ptr = unsafe { alloc::alloc::GlobalAlloc::alloc(&system::SYSTEM, layout) };
-        let Some(ptr) = NonNull::new(ptr as _) else {
-            panic!("memory allocation of {} bytes failed", layout.size());
-        };
-        Self {
-            ptr: system::uncached_addr(ptr),
-            width,
-            height,
-        }
+}
+
+// Pixel types a `Surface` can hold. 4-bit texels are packed two to a byte,
+// the first in the high nibble.
+pub trait Texel: embedded_graphics::pixelcolor::PixelColor {
+    const FORMAT: Format;
+    const BITS: usize = Self::FORMAT.bits();
+    fn from_bits(bits: u32) -> Self;
+    fn into_bits(self) -> u32;
+    // Reinterprets as another texel type of the same format.
+    #[inline]
+    fn convert<T: Texel>(self) -> T {
+        debug_assert!(Self::FORMAT == T::FORMAT);
+        T::from_bits(self.into_bits())
     }
+}
+
+/// Formats of 8 bits or more, which can be handled as slices of texels.
+///
+/// # Safety
+///
+/// Implementors must occupy exactly `BITS / 8` bytes.
+pub unsafe trait ByteTexel: Texel {}
+
+// Pixel formats that convert through RGBA8888.
+pub trait Rgba: Texel {
+    fn to_rgba8888(self) -> RGBA8888;
+    fn from_rgba8888(c: RGBA8888) -> Self;
+    // 8-bit alpha; 0 or 0xFF for formats with a 1-bit alpha.
+    fn alpha(self) -> u8;
+}
+
+impl Texel for RGBA8888 {
+    const FORMAT: Format = Format::Rgba32;
     #[inline]
-    pub fn framebuffer(width: u16, height: u16) -> Self {
-        Self::_new(width, height, 0x100000)
+    fn from_bits(bits: u32) -> Self {
+        Self::from_u32(bits)
     }
     #[inline]
-    pub fn new(width: u16, height: u16) -> Self {
-        Self::_new(width, height, 16)
+    fn into_bits(self) -> u32 {
+        self.into_u32()
     }
+}
+
+unsafe impl ByteTexel for RGBA8888 {}
+
+impl Rgba for RGBA8888 {
     #[inline]
-    pub const fn as_ptr(&self) -> *const P {
-        self.ptr.as_ptr() as _
+    fn to_rgba8888(self) -> RGBA8888 {
+        self
     }
     #[inline]
-    pub const fn as_mut_ptr(&mut self) -> *mut P {
-        self.ptr.as_ptr()
+    fn from_rgba8888(c: RGBA8888) -> Self {
+        c
     }
     #[inline]
-    pub const fn width(&self) -> u16 {
-        self.width
+    fn alpha(self) -> u8 {
+        self.a()
     }
+}
+
+impl Texel for RGBA5551 {
+    const FORMAT: Format = Format::Rgba16;
     #[inline]
-    pub const fn height(&self) -> u16 {
-        self.height
+    fn from_bits(bits: u32) -> Self {
+        Self::from_u16(bits as u16)
     }
     #[inline]
-    pub const fn is_empty(&self) -> bool {
-        self.width != 0 && self.height != 0
+    fn into_bits(self) -> u32 {
+        self.into_u16() as u32
     }
+}
+
+unsafe impl ByteTexel for RGBA5551 {}
+
+impl Rgba for RGBA5551 {
     #[inline]
-    pub const fn len(&self) -> usize {
-        self.width as usize * self.height as usize
+    fn to_rgba8888(self) -> RGBA8888 {
+        RGBA8888::from_rgba5551(self)
     }
     #[inline]
-    pub fn as_slice(&self) -> &[P] {
-        unsafe { core::slice::from_raw_parts(self.as_ptr(), self.len()) }
+    fn from_rgba8888(c: RGBA8888) -> Self {
+        RGBA5551::from_rgba8888(c)
     }
     #[inline]
-    pub fn as_mut_slice(&mut self) -> &mut [P] {
-        unsafe { core::slice::from_raw_parts_mut(self.as_mut_ptr(), self.len()) }
+    fn alpha(self) -> u8 {
+        if self.a().value() != 0 { 0xFF } else { 0 }
     }
 }
 
-impl<P> Drop for Surface<P> {
-    fn drop(&mut self) {
-        unsafe {
-            let ptr = system::cached_addr(self.ptr).as_ptr();
-            let layout = Self::layout(self.width, self.height, 16);
-            alloc::alloc::GlobalAlloc::dealloc(&system::SYSTEM, ptr as _, layout);
+// Rec. 601 luma.
+#[inline]
+const fn luma(c: RGBA8888) -> u8 {
+    ((c.r() as u32 * 77 + c.g() as u32 * 150 + c.b() as u32 * 29 + 0x80) >> 8) as u8
+}
+
+macro_rules! texel {
+    ($ty:ident, $raw:ident, $storage:ty, $format:ident) => {
+        impl embedded_graphics::pixelcolor::PixelColor for $ty {
+            type Raw = $raw;
+        }
+
+        impl From<$raw> for $ty {
+            #[inline]
+            fn from(value: $raw) -> Self {
+                Self(value)
+            }
+        }
+
+        impl Texel for $ty {
+            const FORMAT: Format = Format::$format;
+            #[inline]
+            fn from_bits(bits: u32) -> Self {
+                Self($raw::new(bits as $storage))
+            }
+            #[inline]
+            fn into_bits(self) -> u32 {
+                self.into_bits() as u32
+            }
+        }
+    };
+}
+
+macro_rules! rgba_texel {
+    ($ty:ident) => {
+        impl Rgba for $ty {
+            #[inline]
+            fn to_rgba8888(self) -> RGBA8888 {
+                self.into_rgba8888()
+            }
+            #[inline]
+            fn from_rgba8888(c: RGBA8888) -> Self {
+                $ty::from_rgba8888(c)
+            }
+            #[inline]
+            fn alpha(self) -> u8 {
+                self.into_rgba8888().a()
+            }
+        }
+
+        impl From<$ty> for RGBA8888 {
+            #[inline]
+            fn from(value: $ty) -> Self {
+                value.into_rgba8888()
+            }
+        }
+
+        impl From<RGBA8888> for $ty {
+            #[inline]
+            fn from(value: RGBA8888) -> Self {
+                $ty::from_rgba8888(value)
+            }
+        }
+
+        impl From<$ty> for RGBA5551 {
+            #[inline]
+            fn from(value: $ty) -> Self {
+                RGBA5551::from_rgba8888(value.into_rgba8888())
+            }
         }
+
+        impl From<RGBA5551> for $ty {
+            #[inline]
+            fn from(value: RGBA5551) -> Self {
+                $ty::from_rgba8888(RGBA8888::from_rgba5551(value))
+            }
+        }
+    };
+}
+
+// 4-bit intensity, also used as alpha.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+#[repr(transparent)]
+pub struct I4(RawU4);
+
+texel!(I4, RawU4, u8, I4);
+rgba_texel!(I4);
+
+impl I4 {
+    #[inline]
+    pub const fn new(i: u4) -> Self {
+        Self(RawU4::new(i.value()))
+    }
+    #[inline]
+    const fn into_bits(self) -> u8 {
+        unsafe { core::mem::transmute(self.0) }
+    }
+    #[inline]
+    pub const fn i(self) -> u4 {
+        unsafe { u4::new_unchecked(self.into_bits()) }
+    }
+    #[inline]
+    pub const fn into_rgba8888(self) -> RGBA8888 {
+        let i = self.into_bits() * 0x11;
+        RGBA8888::new(i, i, i, i)
+    }
+    #[inline]
+    pub const fn from_rgba8888(c: RGBA8888) -> Self {
+        Self(RawU4::new(luma(c) >> 4))
     }
 }
 
-impl<P> embedded_graphics::geometry::OriginDimensions for Surface<P> {
+// 8-bit intensity, also used as alpha.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+#[repr(transparent)]
+pub struct I8(RawU8);
+
+texel!(I8, RawU8, u8, I8);
+rgba_texel!(I8);
+unsafe impl ByteTexel for I8 {}
+
+impl I8 {
     #[inline]
-    fn size(&self) -> embedded_graphics::geometry::Size {
-        embedded_graphics::geometry::Size::new(self.width as u32, self.height as u32)
+    pub const fn new(i: u8) -> Self {
+        Self(RawU8::new(i))
+    }
+    #[inline]
+    const fn into_bits(self) -> u8 {
+        unsafe { core::mem::transmute(self.0) }
+    }
+    #[inline]
+    pub const fn i(self) -> u8 {
+        self.into_bits()
+    }
+    #[inline]
+    pub const fn into_rgba8888(self) -> RGBA8888 {
+        let i = self.into_bits();
+        RGBA8888::new(i, i, i, i)
+    }
+    #[inline]
+    pub const fn from_rgba8888(c: RGBA8888) -> Self {
+        Self::new(luma(c))
     }
 }
 
-impl<P: embedded_graphics::pixelcolor::PixelColor> embedded_graphics::draw_target::DrawTarget
-    for Surface<P>
-{
-    type Color = P;
-    type Error = core::convert::Infallible;
-    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
-    where
-        I: IntoIterator<Item = embedded_graphics::Pixel<Self::Color>>,
-    {
-        for embedded_graphics::Pixel(p, c) in pixels {
-            if p.x >= 0 && p.x <= self.width as i32 && p.y >= 0 && p.y <= self.height as i32 {
-                unsafe {
-                    self.ptr
-                        .as_ptr()
-                        .add(p.x as usize + p.y as usize * self.width as usize)
-                        .write(c)
-                };
-            }
+// 3-bit intensity and 1-bit alpha.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+#[repr(transparent)]
+pub struct IA4(RawU4);
+
+texel!(IA4, RawU4, u8, Ia4);
+rgba_texel!(IA4);
+
+impl IA4 {
+    #[inline]
+    pub const fn new(i: u3, a: u1) -> Self {
+        Self(RawU4::new((i.value() << 1) | a.value()))
+    }
+    #[inline]
+    const fn into_bits(self) -> u8 {
+        unsafe { core::mem::transmute(self.0) }
+    }
+    #[inline]
+    pub const fn i(self) -> u3 {
+        unsafe { u3::new_unchecked(self.into_bits() >> 1) }
+    }
+    #[inline]
+    pub const fn a(self) -> u1 {
+        unsafe { u1::new_unchecked(self.into_bits() & 1) }
+    }
+    #[inline]
+    pub const fn into_rgba8888(self) -> RGBA8888 {
+        let i = self.i().value();
+        let i = (i << 5) | (i << 2) | (i >> 1);
+        RGBA8888::new(i, i, i, if self.a().value() != 0 { 0xFF } else { 0 })
+    }
+    #[inline]
+    pub const fn from_rgba8888(c: RGBA8888) -> Self {
+        Self(RawU4::new(((luma(c) >> 5) << 1) | (c.a() >> 7)))
+    }
+}
+
+// 4-bit intensity and 4-bit alpha.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+#[repr(transparent)]
+pub struct IA8(RawU8);
+
+texel!(IA8, RawU8, u8, Ia8);
+rgba_texel!(IA8);
+unsafe impl ByteTexel for IA8 {}
+
+impl IA8 {
+    #[inline]
+    pub const fn new(i: u4, a: u4) -> Self {
+        Self(RawU8::new((i.value() << 4) | a.value()))
+    }
+    #[inline]
+    const fn into_bits(self) -> u8 {
+        unsafe { core::mem::transmute(self.0) }
+    }
+    #[inline]
+    pub const fn i(self) -> u4 {
+        unsafe { u4::new_unchecked(self.into_bits() >> 4) }
+    }
+    #[inline]
+    pub const fn a(self) -> u4 {
+        unsafe { u4::new_unchecked(self.into_bits() & 0xF) }
+    }
+    #[inline]
+    pub const fn into_rgba8888(self) -> RGBA8888 {
+        let i = self.i().value() * 0x11;
+        RGBA8888::new(i, i, i, self.a().value() * 0x11)
+    }
+    #[inline]
+    pub const fn from_rgba8888(c: RGBA8888) -> Self {
+        Self(RawU8::new((luma(c) & 0xF0) | (c.a() >> 4)))
+    }
+}
+
+// 8-bit intensity and 8-bit alpha.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+#[repr(transparent)]
+pub struct IA16(RawU16);
+
+texel!(IA16, RawU16, u16, Ia16);
+rgba_texel!(IA16);
+unsafe impl ByteTexel for IA16 {}
+
+impl IA16 {
+    #[inline]
+    pub const fn new(i: u8, a: u8) -> Self {
+        Self(RawU16::new(((i as u16) << 8) | a as u16))
+    }
+    #[inline]
+    const fn into_bits(self) -> u16 {
+        unsafe { core::mem::transmute(self.0) }
+    }
+    #[inline]
+    pub const fn i(self) -> u8 {
+        (self.into_bits() >> 8) as u8
+    }
+    #[inline]
+    pub const fn a(self) -> u8 {
+        self.into_bits() as u8
+    }
+    #[inline]
+    pub const fn into_rgba8888(self) -> RGBA8888 {
+        let i = self.i();
+        RGBA8888::new(i, i, i, self.a())
+    }
+    #[inline]
+    pub const fn from_rgba8888(c: RGBA8888) -> Self {
+        Self::new(luma(c), c.a())
+    }
+}
+
+// 4-bit palette index.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+#[repr(transparent)]
+pub struct CI4(RawU4);
+
+texel!(CI4, RawU4, u8, Ci4);
+
+impl CI4 {
+    #[inline]
+    pub const fn new(index: u4) -> Self {
+        Self(RawU4::new(index.value()))
+    }
+    #[inline]
+    const fn into_bits(self) -> u8 {
+        unsafe { core::mem::transmute(self.0) }
+    }
+    #[inline]
+    pub const fn index(self) -> u8 {
+        self.into_bits()
+    }
+}
+
+// 8-bit palette index.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+#[repr(transparent)]
+pub struct CI8(RawU8);
+
+texel!(CI8, RawU8, u8, Ci8);
+unsafe impl ByteTexel for CI8 {}
+
+impl CI8 {
+    #[inline]
+    pub const fn new(index: u8) -> Self {
+        Self(RawU8::new(index))
+    }
+    #[inline]
+    const fn into_bits(self) -> u8 {
+        unsafe { core::mem::transmute(self.0) }
+    }
+    #[inline]
+    pub const fn index(self) -> u8 {
+        self.into_bits()
+    }
+}
+
+#[inline]
+const fn mix8(s: u8, d: u8, a: u32) -> u8 {
+    ((s as u32 * a + d as u32 * (0xFF - a) + 0x7F) / 0xFF) as u8
+}
+
+// Source-over blend of `src` onto `dst` by the source alpha.
+#[inline]
+pub const fn blend(src: RGBA8888, dst: RGBA8888) -> RGBA8888 {
+    let a = src.a() as u32;
+    RGBA8888::new(
+        mix8(src.r(), dst.r(), a),
+        mix8(src.g(), dst.g(), a),
+        mix8(src.b(), dst.b(), a),
+        (a + (dst.a() as u32 * (0xFF - a) + 0x7F) / 0xFF) as u8,
+    )
+}
+
+// `x^(num/den)` for `x` in [0, 1], by Newton's method on `y^den = x^num`.
+const fn root(x: f64, num: i32, den: i32) -> f64 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    let c = powi(x, num);
+    let mut y = 1.0;
+    let mut i = 0;
+    while i < 200 {
+        let p = powi(y, den - 1);
+        let next = y - (p * y - c) / (den as f64 * p);
+        if next >= y {
+            break;
         }
-        Ok(())
+        y = next;
+        i += 1;
+    }
+    y
+}
+
+const fn powi(x: f64, n: i32) -> f64 {
+    let mut y = 1.0;
+    let mut i = 0;
+    while i < n {
+        y *= x;
+        i += 1;
+    }
+    y
+}
+
+// sRGB to 16-bit linear.
+const SRGB_TO_LINEAR: [u16; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let s = i as f64 / 255.0;
+        let l = if s <= 0.04045 {
+            s / 12.92
+        } else {
+            let x = (s + 0.055) / 1.055;
+            x * x * root(x, 2, 5)
+        };
+        table[i] = (l * 65535.0 + 0.5) as u16;
+        i += 1;
+    }
+    table
+};
+
+// 12-bit linear to sRGB, picking the entry of `SRGB_TO_LINEAR` nearest in
+// linear terms.
+const LINEAR_TO_SRGB: [u8; 4096] = {
+    let mut table = [0; 4096];
+    let (mut i, mut s) = (0, 0);
+    while i < 4096 {
+        let l = (i * 65535 / 4095) as u32;
+        while s < 255 && (SRGB_TO_LINEAR[s] as u32 + SRGB_TO_LINEAR[s + 1] as u32) / 2 < l {
+            s += 1;
+        }
+        table[i] = s as u8;
+        i += 1;
+    }
+    table
+};
+
+const SRGB5_TO_LINEAR5: [u8; 32] = {
+    let mut table = [0; 32];
+    let mut i = 0;
+    while i < 32 {
+        let l = SRGB_TO_LINEAR[(i << 3) | (i >> 2)] as u32;
+        table[i] = ((l * 31 + 32767) / 65535) as u8;
+        i += 1;
+    }
+    table
+};
+
+const LINEAR5_TO_SRGB5: [u8; 32] = {
+    let mut table = [0; 32];
+    let mut i = 0;
+    while i < 32 {
+        let s = LINEAR_TO_SRGB[i * 4095 / 31] as u32;
+        table[i] = ((s * 31 + 127) / 255) as u8;
+        i += 1;
+    }
+    table
+};
+
+#[inline]
+pub const fn srgb_to_linear(v: u8) -> u8 {
+    ((SRGB_TO_LINEAR[v as usize] as u32 * 255 + 32767) / 65535) as u8
+}
+
+#[inline]
+pub const fn linear_to_srgb(v: u8) -> u8 {
+    LINEAR_TO_SRGB[(v as usize * 4095 + 127) / 255]
+}
+
+#[inline]
+const fn mul8(a: u8, b: u8) -> u8 {
+    ((a as u32 * b as u32 + 0x7F) / 0xFF) as u8
+}
+
+// `a + b * w / 255`, saturating.
+#[inline]
+const fn add8(a: u8, b: u8, w: u8) -> u8 {
+    let v = a as u32 + mul8(b, w) as u32;
+    if v > 0xFF { 0xFF } else { v as u8 }
+}
+
+// Hue in degrees [0, 360), saturation and value in [0, 255].
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct Hsv {
+    pub h: u16,
+    pub s: u8,
+    pub v: u8,
+}
+
+impl RGBA8888 {
+    // Decodes sRGB color channels to linear; alpha is unchanged.
+    #[inline]
+    pub const fn to_linear(self) -> Self {
+        Self::linear(self.r(), self.g(), self.b(), self.a())
+    }
+    // Encodes linear color channels as sRGB; alpha is unchanged.
+    #[inline]
+    pub const fn to_srgb(self) -> Self {
+        Self::new(
+            linear_to_srgb(self.r()),
+            linear_to_srgb(self.g()),
+            linear_to_srgb(self.b()),
+            self.a(),
+        )
+    }
+    // Moves every channel towards `other` by `t`/255.
+    #[inline]
+    pub const fn lerp(self, other: Self, t: u8) -> Self {
+        let t = t as u32;
+        Self::new(
+            mix8(other.r(), self.r(), t),
+            mix8(other.g(), self.g(), t),
+            mix8(other.b(), self.b(), t),
+            mix8(other.a(), self.a(), t),
+        )
+    }
+    #[inline]
+    pub const fn premultiply(self) -> Self {
+        let a = self.a();
+        Self::new(mul8(self.r(), a), mul8(self.g(), a), mul8(self.b(), a), a)
+    }
+    #[inline]
+    pub const fn with_alpha(self, a: u8) -> Self {
+        Self::from_u32((self.into_u32() & !0xFF) | a as u32)
+    }
+    // Source-over blend of premultiplied `self` onto premultiplied `dst`.
+    #[inline]
+    pub const fn blend_premultiplied(self, dst: Self) -> Self {
+        let ia = 0xFF - self.a();
+        Self::new(
+            add8(self.r(), dst.r(), ia),
+            add8(self.g(), dst.g(), ia),
+            add8(self.b(), dst.b(), ia),
+            add8(self.a(), dst.a(), ia),
+        )
+    }
+    // Adds `self` weighted by its alpha to `dst`, saturating. Keeps the
+    // alpha of `dst`.
+    #[inline]
+    pub const fn blend_add(self, dst: Self) -> Self {
+        let a = self.a();
+        Self::new(
+            add8(dst.r(), self.r(), a),
+            add8(dst.g(), self.g(), a),
+            add8(dst.b(), self.b(), a),
+            dst.a(),
+        )
+    }
+    // Multiplies `dst` by `self`, weighted by the alpha of `self`. Keeps the
+    // alpha of `dst`.
+    #[inline]
+    pub const fn blend_multiply(self, dst: Self) -> Self {
+        let a = self.a() as u32;
+        Self::new(
+            mix8(mul8(self.r(), dst.r()), dst.r(), a),
+            mix8(mul8(self.g(), dst.g()), dst.g(), a),
+            mix8(mul8(self.b(), dst.b()), dst.b(), a),
+            dst.a(),
+        )
+    }
+    pub const fn to_hsv(self) -> Hsv {
+        let (r, g, b) = (self.r() as i32, self.g() as i32, self.b() as i32);
+        let max = if r > g { r } else { g };
+        let max = if max > b { max } else { b };
+        let min = if r < g { r } else { g };
+        let min = if min < b { min } else { b };
+        let delta = max - min;
+        if delta == 0 {
+            return Hsv {
+                h: 0,
+                s: 0,
+                v: max as u8,
+            };
+        }
+        let h = if max == r {
+            60 * (g - b) / delta
+        } else if max == g {
+            120 + 60 * (b - r) / delta
+        } else {
+            240 + 60 * (r - g) / delta
+        };
+        Hsv {
+            h: h.rem_euclid(360) as u16,
+            s: (delta * 255 / max) as u8,
+            v: max as u8,
+        }
+    }
+    pub const fn from_hsv(hsv: Hsv, a: u8) -> Self {
+        let v = hsv.v as u32;
+        if hsv.s == 0 {
+            return Self::new(hsv.v, hsv.v, hsv.v, a);
+        }
+        let (s, h) = (hsv.s as u32, hsv.h as u32 % 360);
+        let f = (h % 60) * 255 / 60;
+        let p = (v * (255 - s) / 255) as u8;
+        let q = (v * (255 - s * f / 255) / 255) as u8;
+        let t = (v * (255 - s * (255 - f) / 255) / 255) as u8;
+        let v = v as u8;
+        let (r, g, b) = match h / 60 {
+            0 => (v, t, p),
+            1 => (q, v, p),
+            2 => (p, v, t),
+            3 => (p, q, v),
+            4 => (t, p, v),
+            _ => (v, p, q),
+        };
+        Self::new(r, g, b, a)
+    }
+}
+
+impl RGBA5551 {
+    // Decodes sRGB color channels to linear; alpha is unchanged.
+    #[inline]
+    pub const fn to_linear(self) -> Self {
+        Self::linear(self.r(), self.g(), self.b(), self.a())
+    }
+    // Encodes linear color channels as sRGB; alpha is unchanged.
+    #[inline]
+    pub const fn to_srgb(self) -> Self {
+        let r = unsafe { u5::new_unchecked(LINEAR5_TO_SRGB5[self.r().value() as usize]) };
+        let g = unsafe { u5::new_unchecked(LINEAR5_TO_SRGB5[self.g().value() as usize]) };
+        let b = unsafe { u5::new_unchecked(LINEAR5_TO_SRGB5[self.b().value() as usize]) };
+        Self::new(r, g, b, self.a())
+    }
+    #[inline]
+    pub const fn lerp(self, other: Self, t: u8) -> Self {
+        let a = RGBA8888::from_rgba5551(self);
+        let b = RGBA8888::from_rgba5551(other);
+        Self::from_rgba8888(a.lerp(b, t))
+    }
+    #[inline]
+    pub const fn blend_add(self, dst: Self) -> Self {
+        let s = RGBA8888::from_rgba5551(self);
+        Self::from_rgba8888(s.blend_add(RGBA8888::from_rgba5551(dst)))
+    }
+    #[inline]
+    pub const fn blend_multiply(self, dst: Self) -> Self {
+        let s = RGBA8888::from_rgba5551(self);
+        Self::from_rgba8888(s.blend_multiply(RGBA8888::from_rgba5551(dst)))
+    }
+    #[inline]
+    pub const fn to_hsv(self) -> Hsv {
+        RGBA8888::from_rgba5551(self).to_hsv()
+    }
+    #[inline]
+    pub const fn from_hsv(hsv: Hsv, a: u1) -> Self {
+        let a = if a.value() != 0 { 0xFF } else { 0 };
+        Self::from_rgba8888(RGBA8888::from_hsv(hsv, a))
+    }
+}
+
+// Fills `len` pixels with 64-bit stores once the destination is 8-byte
+// aligned. Pixel sizes that don't divide 8 fall back to single stores.
+unsafe fn fill_span<P: Copy>(dst: *mut P, len: usize, color: P) {
+    let size = size_of::<P>();
+    let mut p = dst;
+    let end = unsafe { dst.add(len) };
+    if size == 0 || 8 % size != 0 {
+        while p < end {
+            unsafe {
+                p.write(color);
+                p = p.add(1);
+            }
+        }
+        return;
+    }
+    while p < end && p as usize & 7 != 0 {
+        unsafe {
+            p.write(color);
+            p = p.add(1);
+        }
+    }
+    let mut pattern = [0u8; 8];
+    for chunk in pattern.chunks_exact_mut(size) {
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                &color as *const P as *const u8,
+                chunk.as_mut_ptr(),
+                size,
+            )
+        };
+    }
+    let pattern = u64::from_ne_bytes(pattern);
+    let words = (end as usize - p as usize) / 8;
+    let mut q = p as *mut u64;
+    for _ in 0..words {
+        unsafe {
+            q.write(pattern);
+            q = q.add(1);
+        }
+    }
+    p = q as *mut P;
+    while p < end {
+        unsafe {
+            p.write(color);
+            p = p.add(1);
+        }
+    }
+}
+
+#[inline]
+unsafe fn get<P: Texel>(base: *const P, i: usize) -> P {
+    match P::BITS {
+        4 => {
+            let b = unsafe { (base as *const u8).add(i / 2).read() };
+            P::from_bits(if i.is_multiple_of(2) { b >> 4 } else { b & 0xF } as u32)
+        }
+        _ => unsafe { base.add(i).read() },
+    }
+}
+
+#[inline]
+unsafe fn put<P: Texel>(base: *mut P, i: usize, c: P) {
+    match P::BITS {
+        4 => unsafe {
+            let p = (base as *mut u8).add(i / 2);
+            let n = c.into_bits() as u8 & 0xF;
+            let b = p.read();
+            p.write(if i.is_multiple_of(2) {
+                (b & 0x0F) | (n << 4)
+            } else {
+                (b & 0xF0) | n
+            });
+        },
+        _ => unsafe { base.add(i).write(c) },
+    }
+}
+
+unsafe fn fill<P: Texel>(base: *mut P, mut i: usize, len: usize, c: P) {
+    if P::BITS != 4 {
+        return unsafe { fill_span(base.add(i), len, c) };
+    }
+    let end = i + len;
+    if i % 2 == 1 && i < end {
+        unsafe { put(base, i, c) };
+        i += 1;
+    }
+    let n = c.into_bits() as u8 & 0xF;
+    let bytes = (end - i) / 2;
+    unsafe { fill_span((base as *mut u8).add(i / 2), bytes, (n << 4) | n) };
+    i += bytes * 2;
+    if i < end {
+        unsafe { put(base, i, c) };
+    }
+}
+
+pub struct Surface<P: Texel> {
+    ptr: NonNull<P>,
+    width: u16,
+    height: u16,
+    stride: u16,
+    // Drop has to free with the layout the buffer was allocated with.
+    align: usize,
+    clip: Rectangle,
+    clips: Vec<Rectangle>,
+}
+
+impl<P: Texel> Surface<P> {
+    #[inline]
+    const unsafe fn layout(stride: u16, height: u16, mut align: usize) -> Layout {
+        let size = (stride as usize * height as usize * P::BITS).div_ceil(8);
+        if align < 16 {
+            align = 16;
+        }
+        if align < size_of::<P>() {
+            align = size_of::<P>();
+        }
+        unsafe { Layout::from_size_align_unchecked(size, align) }
+    }
+    // Smallest stride of at least `width` pixels whose rows start 8-byte
+    // aligned, as the RDP requires.
+    #[inline]
+    pub const fn aligned_stride(width: u16) -> u16 {
+        let n = (64 / P::BITS) as u16;
+        width.div_ceil(n) * n
+    }
+    fn _new(width: u16, height: u16, stride: u16, align: usize) -> Self {
+        assert!(
+            stride >= width,
+            "stride {stride} is less than width {width}"
+        );
+        assert!(
+            (stride as usize * P::BITS).is_multiple_of(8),
+            "stride {stride} is not a whole number of bytes"
+        );
+        let layout = unsafe { Self::layout(stride, height, align) };
+        let ptr = unsafe { alloc::alloc::alloc(layout) };
+        let Some(ptr) = NonNull::new(ptr as _) else {
+            panic!("memory allocation of {} bytes failed", layout.size());
+        };
+        Self {
+            ptr: uncached_addr(ptr),
+            width,
+            height,
+            stride,
+            align,
+            clip: Rectangle::new(Point::zero(), Size::new(width as u32, height as u32)),
+            clips: Vec::new(),
+        }
+    }
+    #[inline]
+    pub fn framebuffer(width: u16, height: u16) -> Self {
+        Self::_new(width, height, Self::aligned_stride(width), 0x100000)
+    }
+    #[inline]
+    pub fn framebuffer_with_stride(width: u16, height: u16, stride: u16) -> Self {
+        Self::_new(width, height, stride, 0x100000)
+    }
+    #[inline]
+    pub fn new(width: u16, height: u16) -> Self {
+        Self::_new(width, height, Self::aligned_stride(width), 16)
+    }
+    #[inline]
+    pub fn with_stride(width: u16, height: u16, stride: u16) -> Self {
+        Self::_new(width, height, stride, 16)
+    }
+    #[inline]
+    pub const fn as_ptr(&self) -> *const P {
+        self.ptr.as_ptr() as _
+    }
+    #[inline]
+    pub const fn as_mut_ptr(&mut self) -> *mut P {
+        self.ptr.as_ptr()
+    }
+    #[inline]
+    pub const fn width(&self) -> u16 {
+        self.width
+    }
+    #[inline]
+    pub const fn height(&self) -> u16 {
+        self.height
+    }
+    // Distance between rows in pixels.
+    #[inline]
+    pub const fn stride(&self) -> u16 {
+        self.stride
+    }
+    // Distance between rows in bytes.
+    #[inline]
+    pub const fn pitch(&self) -> usize {
+        self.stride as usize * P::BITS / 8
+    }
+    #[inline]
+    pub const fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+    // Pixels in the whole buffer, including any padding at the end of rows.
+    #[inline]
+    pub const fn len(&self) -> usize {
+        self.stride as usize * self.height as usize
+    }
+    #[inline]
+    pub const fn clip(&self) -> Rectangle {
+        self.clip
+    }
+    #[inline]
+    fn bounds(&self) -> Rectangle {
+        Rectangle::new(
+            Point::zero(),
+            Size::new(self.width as u32, self.height as u32),
+        )
+    }
+    // Replaces the current clip rectangle, limited to the surface.
+    pub fn set_clip(&mut self, rect: Rectangle) {
+        self.clip = rect.intersection(&self.bounds());
+    }
+    pub fn reset_clip(&mut self) {
+        self.clips.clear();
+        self.clip = self.bounds();
+    }
+    // Narrows the clip to its intersection with `rect` until the matching
+    // `pop_clip`.
+    pub fn push_clip(&mut self, rect: Rectangle) {
+        self.clips.push(self.clip);
+        self.clip = self.clip.intersection(&rect);
+    }
+    pub fn pop_clip(&mut self) -> Option<Rectangle> {
+        let prev = self.clips.pop()?;
+        Some(core::mem::replace(&mut self.clip, prev))
+    }
+    #[inline]
+    pub fn pixel(&self, p: Point) -> Option<P> {
+        self.view(self.bounds()).pixel(p)
+    }
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        let len = self.pitch() * self.height as usize;
+        unsafe { core::slice::from_raw_parts(self.as_ptr() as *const u8, len) }
+    }
+    #[inline]
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        let len = self.pitch() * self.height as usize;
+        unsafe { core::slice::from_raw_parts_mut(self.as_mut_ptr() as *mut u8, len) }
+    }
+}
+
+impl<P: ByteTexel> Surface<P> {
+    #[inline]
+    pub fn row(&self, y: u16) -> &[P] {
+        assert!(y < self.height);
+        let start = y as usize * self.stride as usize;
+        &self.as_slice()[start..start + self.width as usize]
+    }
+    #[inline]
+    pub fn row_mut(&mut self, y: u16) -> &mut [P] {
+        assert!(y < self.height);
+        let start = y as usize * self.stride as usize;
+        let width = self.width as usize;
+        &mut self.as_mut_slice()[start..start + width]
+    }
+    // The whole buffer, `stride` pixels per row. Not available for 4-bit
+    // formats; use `as_bytes`.
+    #[inline]
+    pub fn as_slice(&self) -> &[P] {
+        unsafe { core::slice::from_raw_parts(self.as_ptr(), self.len()) }
+    }
+    #[inline]
+    pub fn as_mut_slice(&mut self) -> &mut [P] {
+        unsafe { core::slice::from_raw_parts_mut(self.as_mut_ptr(), self.len()) }
+    }
+}
+
+impl<P: Texel> Drop for Surface<P> {
+    fn drop(&mut self) {
+        unsafe {
+            let ptr = cached_addr(self.ptr).as_ptr();
+            let layout = Self::layout(self.stride, self.height, self.align);
+            alloc::alloc::dealloc(ptr as _, layout);
+        }
+    }
+}
+
+impl<P: Texel> embedded_graphics::geometry::OriginDimensions for Surface<P> {
+    #[inline]
+    fn size(&self) -> embedded_graphics::geometry::Size {
+        embedded_graphics::geometry::Size::new(self.width as u32, self.height as u32)
+    }
+}
+
+// Draws into a strided buffer. `offset` moves local coordinates into buffer
+// coordinates, where `clip` is applied.
+struct Target<P> {
+    base: *mut P,
+    stride: usize,
+    offset: Point,
+    clip: Rectangle,
+}
+
+impl<P: Texel> Target<P> {
+    unsafe fn draw_iter<I>(&self, pixels: I)
+    where
+        I: IntoIterator<Item = embedded_graphics::Pixel<P>>,
+    {
+        let (x0, y0) = (self.clip.top_left.x, self.clip.top_left.y);
+        let x1 = x0 + self.clip.size.width as i32;
+        let y1 = y0 + self.clip.size.height as i32;
+        for embedded_graphics::Pixel(p, c) in pixels {
+            let p = p + self.offset;
+            if p.x >= x0 && p.x < x1 && p.y >= y0 && p.y < y1 {
+                unsafe { put(self.base, p.x as usize + p.y as usize * self.stride, c) };
+            }
+        }
+    }
+    unsafe fn fill_contiguous<I>(&self, area: &Rectangle, colors: I)
+    where
+        I: IntoIterator<Item = P>,
+    {
+        let abs = Rectangle::new(area.top_left + self.offset, area.size);
+        if abs.intersection(&self.clip) != abs {
+            return unsafe {
+                self.draw_iter(
+                    area.points()
+                        .zip(colors)
+                        .map(|(p, c)| embedded_graphics::Pixel(p, c)),
+                )
+            };
+        }
+        let (x, y) = (abs.top_left.x as usize, abs.top_left.y as usize);
+        let (w, h) = (abs.size.width as usize, abs.size.height as usize);
+        let mut colors = colors.into_iter();
+        for row in y..y + h {
+            let start = x + row * self.stride;
+            for i in start..start + w {
+                let Some(c) = colors.next() else {
+                    return;
+                };
+                unsafe { put(self.base, i, c) };
+            }
+        }
+    }
+    unsafe fn fill_solid(&self, area: &Rectangle, color: P) {
+        let abs = Rectangle::new(area.top_left + self.offset, area.size);
+        let abs = abs.intersection(&self.clip);
+        if abs.is_zero_sized() {
+            return;
+        }
+        let (x, y) = (abs.top_left.x as usize, abs.top_left.y as usize);
+        let (w, h) = (abs.size.width as usize, abs.size.height as usize);
+        for row in y..y + h {
+            unsafe { fill(self.base, x + row * self.stride, w, color) };
+        }
+    }
+}
+
+impl<P: Texel> Surface<P> {
+    #[inline]
+    fn target(&self) -> Target<P> {
+        Target {
+            base: self.ptr.as_ptr(),
+            stride: self.stride as usize,
+            offset: Point::zero(),
+            clip: self.clip,
+        }
+    }
+    // Read-only view of `rect`, limited to the surface, for use as a blit
+    // source. It can't be drawn to; `view_mut` gives a drawable view.
+    pub fn view(&self, rect: Rectangle) -> SurfaceView<'_, P> {
+        let rect = rect.intersection(&self.bounds());
+        let (x, y) = (rect.top_left.x as usize, rect.top_left.y as usize);
+        SurfaceView {
+            ptr: self.ptr,
+            origin: x + y * self.stride as usize,
+            width: rect.size.width as u16,
+            height: rect.size.height as u16,
+            stride: self.stride as usize,
+            _marker: PhantomData,
+        }
+    }
+    // Drawable view of `rect`, limited to the surface like `view`, with
+    // its origin at the top left of what remains. Drawing is also clipped to
+    // the surface's current clip.
+    pub fn view_mut(&mut self, rect: Rectangle) -> SurfaceViewMut<'_, P> {
+        let rect = rect.intersection(&self.bounds());
+        SurfaceViewMut {
+            target: Target {
+                clip: self.clip.intersection(&rect),
+                offset: rect.top_left,
+                ..self.target()
+            },
+            size: rect.size,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<P: Texel> embedded_graphics::draw_target::DrawTarget for Surface<P> {
+    type Color = P;
+    type Error = core::convert::Infallible;
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = embedded_graphics::Pixel<Self::Color>>,
+    {
+        unsafe { self.target().draw_iter(pixels) };
+        Ok(())
+    }
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Self::Color>,
+    {
+        unsafe { self.target().fill_contiguous(area, colors) };
+        Ok(())
+    }
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        unsafe { self.target().fill_solid(area, color) };
+        Ok(())
+    }
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        // A single fill only when it can't touch the padding after rows.
+        if self.clip != self.bounds() || self.stride != self.width {
+            return self.fill_solid(&self.clip(), color);
+        }
+        unsafe { fill(self.ptr.as_ptr(), 0, self.len(), color) };
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct BlitOptions<S> {
+    // Source pixels equal to the key are skipped.
+    pub color_key: Option<S>,
+    // Skip transparent pixels and blend partially transparent ones.
+    pub alpha: bool,
+}
+
+impl<S> BlitOptions<S> {
+    pub const OPAQUE: Self = Self {
+        color_key: None,
+        alpha: false,
+    };
+    pub const ALPHA: Self = Self {
+        color_key: None,
+        alpha: true,
+    };
+}
+
+impl<D: Rgba> Surface<D> {
+    // Copies `src_rect` of `src` to `dst` in this surface, converting the
+    // format. Both rectangles are clipped.
+    pub fn blit<S: Rgba>(
+        &mut self,
+        src: &Surface<S>,
+        src_rect: Rectangle,
+        dst: Point,
+        options: &BlitOptions<S>,
+    ) {
+        let clipped = src_rect.intersection(&src.bounds());
+        let dst = dst + (clipped.top_left - src_rect.top_left);
+        self.blit_view(src.view(clipped), dst, options);
+    }
+    pub fn blit_view<S: Rgba>(
+        &mut self,
+        src: SurfaceView<'_, S>,
+        dst: Point,
+        options: &BlitOptions<S>,
+    ) {
+        let area = Rectangle::new(dst, src.size()).intersection(&self.clip);
+        if area.is_zero_sized() {
+            return;
+        }
+        let (sx, sy) = (
+            (area.top_left.x - dst.x) as usize,
+            (area.top_left.y - dst.y) as usize,
+        );
+        let (dx, dy) = (area.top_left.x as usize, area.top_left.y as usize);
+        let w = area.size.width as usize;
+        let raw = options.color_key.is_none() && !options.alpha && S::FORMAT == D::FORMAT;
+        let (s, d) = (src.ptr.as_ptr(), self.ptr.as_ptr());
+        for row in 0..area.size.height as usize {
+            let si = src.origin + sx + (sy + row) * src.stride;
+            let di = dx + (dy + row) * self.stride as usize;
+            if raw && (D::BITS >= 8 || (si.is_multiple_of(2) && di.is_multiple_of(2))) {
+                let bytes = w * D::BITS / 8;
+                unsafe {
+                    core::ptr::copy_nonoverlapping(
+                        (s as *const u8).add(si * D::BITS / 8),
+                        (d as *mut u8).add(di * D::BITS / 8),
+                        bytes,
+                    )
+                };
+                if bytes * 8 != w * D::BITS {
+                    unsafe { put(d, di + w - 1, get(s, si + w - 1).convert()) };
+                }
+                continue;
+            }
+            for i in 0..w {
+                let c = unsafe { get(s, si + i) };
+                if raw {
+                    unsafe { put(d, di + i, c.convert()) };
+                    continue;
+                }
+                if options.color_key == Some(c) {
+                    continue;
+                }
+                unsafe { store(d, di + i, c.to_rgba8888(), options.alpha) };
+            }
+        }
+    }
+    // Scales `src_rect` of `src` to fill `dst_rect`, optionally mirrored.
+    pub fn blit_scaled<S: Rgba>(
+        &mut self,
+        src: &Surface<S>,
+        src_rect: Rectangle,
+        dst_rect: Rectangle,
+        flip: Flip,
+        filter: Filter,
+        options: &BlitOptions<S>,
+    ) {
+        if dst_rect.is_zero_sized() {
+            return;
+        }
+        let axis = |src0: i32, len: u32, dst0: i32, dst_len: u32, flip: bool| {
+            let mut step = ((len as i64) << 16) / dst_len as i64;
+            let mut base = (src0 as i64) << 16;
+            if flip {
+                base += (len as i64) << 16;
+                step = -step;
+            }
+            (step as i32, (base - step * dst0 as i64) as i32)
+        };
+        let (a, tx) = axis(
+            src_rect.top_left.x,
+            src_rect.size.width,
+            dst_rect.top_left.x,
+            dst_rect.size.width,
+            flip.horizontal,
+        );
+        let (d, ty) = axis(
+            src_rect.top_left.y,
+            src_rect.size.height,
+            dst_rect.top_left.y,
+            dst_rect.size.height,
+            flip.vertical,
+        );
+        let src_rect = src_rect.intersection(&src.bounds());
+        let m = Affine::new(a, 0, 0, d, tx, ty)
+            .translate(-src_rect.top_left.x << 16, -src_rect.top_left.y << 16);
+        self.blit_affine(src.view(src_rect), dst_rect, &m, filter, options);
+    }
+    // Fills `area` by mapping each destination pixel through `m` into `src`.
+    // Pixels that map outside `src` are left untouched.
+    pub fn blit_affine<S: Rgba>(
+        &mut self,
+        src: SurfaceView<'_, S>,
+        area: Rectangle,
+        m: &Affine,
+        filter: Filter,
+        options: &BlitOptions<S>,
+    ) {
+        self.blit_lines(src, area, filter, false, options, |_| *m);
+    }
+    // Mode 7 style layer: `line` gives the transform for each destination
+    // row and the source repeats in both directions.
+    pub fn blit_mode7<S: Rgba>(
+        &mut self,
+        src: SurfaceView<'_, S>,
+        area: Rectangle,
+        filter: Filter,
+        options: &BlitOptions<S>,
+        line: impl FnMut(i32) -> Affine,
+    ) {
+        self.blit_lines(src, area, filter, true, options, line);
+    }
+    fn blit_lines<S: Rgba>(
+        &mut self,
+        src: SurfaceView<'_, S>,
+        area: Rectangle,
+        filter: Filter,
+        wrap: bool,
+        options: &BlitOptions<S>,
+        mut line: impl FnMut(i32) -> Affine,
+    ) {
+        let area = area.intersection(&self.clip);
+        if area.is_zero_sized() || src.width == 0 || src.height == 0 {
+            return;
+        }
+        let x0 = area.top_left.x;
+        // Coordinates saturate rather than wrap, so a transform that runs
+        // far outside the source keeps missing it.
+        let edge = |a: i32, b: i32, t: i32, y: i32| {
+            let (a, b) = (a as i64, b as i64);
+            saturate(a * x0 as i64 + b * y as i64 + t as i64 + (a + b) / 2)
+        };
+        for y in area.top_left.y..area.top_left.y + area.size.height as i32 {
+            let m = line(y);
+            // Sample at pixel centres.
+            let mut u = edge(m.a, m.b, m.tx, y);
+            let mut v = edge(m.c, m.d, m.ty, y);
+            let start = x0 as usize + y as usize * self.stride as usize;
+            for i in start..start + area.size.width as usize {
+                if let Some(c) = src.sample(u, v, filter, wrap, options.color_key) {
+                    unsafe { store(self.ptr.as_ptr(), i, c, options.alpha) };
+                }
+                u = u.saturating_add(m.a);
+                v = v.saturating_add(m.c);
+            }
+        }
+    }
+}
+
+#[inline]
+unsafe fn store<D: Rgba>(base: *mut D, i: usize, c: RGBA8888, alpha: bool) {
+    let out = match alpha {
+        true => match c.a() {
+            0 => return,
+            0xFF => c,
+            _ => blend(c, unsafe { get(base, i) }.to_rgba8888()),
+        },
+        false => c,
+    };
+    unsafe { put(base, i, D::from_rgba8888(out)) };
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub enum Filter {
+    #[default]
+    Nearest,
+    Bilinear,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct Flip {
+    pub horizontal: bool,
+    pub vertical: bool,
+}
+
+impl Flip {
+    pub const NONE: Self = Self {
+        horizontal: false,
+        vertical: false,
+    };
+    pub const HORIZONTAL: Self = Self {
+        horizontal: true,
+        vertical: false,
+    };
+    pub const VERTICAL: Self = Self {
+        horizontal: false,
+        vertical: true,
+    };
+    pub const BOTH: Self = Self {
+        horizontal: true,
+        vertical: true,
+    };
+}
+
+// Quarter sine wave in 16.16, 256 steps.
+const SINE: [i32; 257] = {
+    let mut table = [0; 257];
+    let mut i = 0;
+    while i <= 256 {
+        let x = i as f64 * core::f64::consts::FRAC_PI_2 / 256.0;
+        let (mut term, mut sum, mut n) = (x, x, 1);
+        while n < 10 {
+            term = -term * x * x / ((2 * n) as f64 * (2 * n + 1) as f64);
+            sum += term;
+            n += 1;
+        }
+        table[i] = (sum * 65536.0 + 0.5) as i32;
+        i += 1;
+    }
+    table
+};
+
+// Sine and cosine in 16.16 of `angle`, where 0x10000 is a full turn.
+pub const fn sin_cos(angle: u16) -> (i32, i32) {
+    let i = ((angle >> 6) & 0xFF) as usize;
+    let (s, c) = (SINE[i], SINE[256 - i]);
+    match angle >> 14 {
+        0 => (s, c),
+        1 => (c, -s),
+        2 => (-s, -c),
+        _ => (-c, s),
+    }
+}
+
+#[inline]
+const fn saturate(n: i64) -> i32 {
+    if n > i32::MAX as i64 {
+        i32::MAX
+    } else if n < i32::MIN as i64 {
+        i32::MIN
+    } else {
+        n as i32
+    }
+}
+
+#[inline]
+const fn fixed_div(n: i32, d: i32) -> i32 {
+    saturate(((n as i64) << 16) / d as i64)
+}
+
+// Maps destination pixels to source pixels in 16.16 fixed point:
+// `u = a * x + b * y + tx`, `v = c * x + d * y + ty`. Source coordinates must
+// stay within ±32768 pixels.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Affine {
+    pub a: i32,
+    pub b: i32,
+    pub c: i32,
+    pub d: i32,
+    pub tx: i32,
+    pub ty: i32,
+}
+
+impl Affine {
+    pub const ONE: i32 = 1 << 16;
+    pub const IDENTITY: Self = Self::new(Self::ONE, 0, 0, Self::ONE, 0, 0);
+    #[inline]
+    pub const fn new(a: i32, b: i32, c: i32, d: i32, tx: i32, ty: i32) -> Self {
+        Self { a, b, c, d, tx, ty }
+    }
+    // Offsets the source coordinates, in 16.16.
+    #[inline]
+    pub const fn translate(self, du: i32, dv: i32) -> Self {
+        Self {
+            tx: self.tx + du,
+            ty: self.ty + dv,
+            ..self
+        }
+    }
+    // Draws the source scaled by `scale_x`/`scale_y` (16.16) and rotated
+    // clockwise by `angle` (0x10000 per turn) around `pivot`, which lands on
+    // `at` in the destination. A zero scale maps everything outside the
+    // source, so nothing is drawn.
+    pub const fn rotate_scale(
+        pivot: Point,
+        at: Point,
+        angle: u16,
+        scale_x: i32,
+        scale_y: i32,
+    ) -> Self {
+        if scale_x == 0 || scale_y == 0 {
+            return Self::new(0, 0, 0, 0, i32::MIN, i32::MIN);
+        }
+        let (sin, cos) = sin_cos(angle);
+        let (a, b) = (fixed_div(cos, scale_x), fixed_div(sin, scale_x));
+        let (c, d) = (fixed_div(-sin, scale_y), fixed_div(cos, scale_y));
+        let tx = ((pivot.x as i64) << 16) - a as i64 * at.x as i64 - b as i64 * at.y as i64;
+        let ty = ((pivot.y as i64) << 16) - c as i64 * at.x as i64 - d as i64 * at.y as i64;
+        Self::new(a, b, c, d, saturate(tx), saturate(ty))
+    }
+}
+
+#[inline]
+fn bilerp(t: [RGBA8888; 4], fx: u32, fy: u32) -> RGBA8888 {
+    let w = [
+        (256 - fx) * (256 - fy),
+        fx * (256 - fy),
+        (256 - fx) * fy,
+        fx * fy,
+    ];
+    let mut out = 0;
+    for shift in [0, 8, 16, 24] {
+        let mut sum = 0;
+        for (c, w) in t.iter().zip(w) {
+            sum += ((c.into_u32() >> shift) & 0xFF) * w;
+        }
+        out |= ((sum + 0x8000) >> 16) << shift;
+    }
+    RGBA8888::from_u32(out)
+}
+
+pub struct SurfaceView<'a, P> {
+    ptr: NonNull<P>,
+    origin: usize,
+    width: u16,
+    height: u16,
+    stride: usize,
+    _marker: PhantomData<&'a [P]>,
+}
+
+impl<'a, P: Texel> SurfaceView<'a, P> {
+    #[inline]
+    pub const fn width(&self) -> u16 {
+        self.width
+    }
+    #[inline]
+    pub const fn height(&self) -> u16 {
+        self.height
+    }
+    #[inline]
+    pub const fn stride(&self) -> usize {
+        self.stride
+    }
+    #[inline]
+    pub fn pixel(&self, p: Point) -> Option<P> {
+        if p.x < 0 || p.y < 0 || p.x >= self.width as i32 || p.y >= self.height as i32 {
+            return None;
+        }
+        Some(self.texel(p.x, p.y, false))
+    }
+    #[inline]
+    fn texel(&self, x: i32, y: i32, wrap: bool) -> P {
+        let (w, h) = (self.width as i32, self.height as i32);
+        let (x, y) = match wrap {
+            true => (x.rem_euclid(w), y.rem_euclid(h)),
+            false => (x.clamp(0, w - 1), y.clamp(0, h - 1)),
+        };
+        unsafe {
+            get(
+                self.ptr.as_ptr(),
+                self.origin + x as usize + y as usize * self.stride,
+            )
+        }
+    }
+    // Samples at 16.16 coordinates `u`, `v`. Without `wrap`, coordinates
+    // outside the view give `None` and bilinear filtering clamps to the
+    // edge. The color key is tested against the nearest texel.
+    fn sample(&self, u: i32, v: i32, filter: Filter, wrap: bool, key: Option<P>) -> Option<RGBA8888>
+    where
+        P: Rgba,
+    {
+        let (x, y) = (u >> 16, v >> 16);
+        if !wrap && (x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32) {
+            return None;
+        }
+        let c = self.texel(x, y, wrap);
+        if key == Some(c) {
+            return None;
+        }
+        match filter {
+            Filter::Nearest => Some(c.to_rgba8888()),
+            Filter::Bilinear => {
+                let (u, v) = (u.saturating_sub(0x8000), v.saturating_sub(0x8000));
+                let (x, y) = (u >> 16, v >> 16);
+                let (fx, fy) = (((u >> 8) & 0xFF) as u32, ((v >> 8) & 0xFF) as u32);
+                Some(bilerp(
+                    [
+                        self.texel(x, y, wrap).to_rgba8888(),
+                        self.texel(x + 1, y, wrap).to_rgba8888(),
+                        self.texel(x, y + 1, wrap).to_rgba8888(),
+                        self.texel(x + 1, y + 1, wrap).to_rgba8888(),
+                    ],
+                    fx,
+                    fy,
+                ))
+            }
+        }
+    }
+    pub fn view(&self, rect: Rectangle) -> SurfaceView<'a, P> {
+        let bounds = Rectangle::new(Point::zero(), self.size());
+        let rect = rect.intersection(&bounds);
+        let (x, y) = (rect.top_left.x as usize, rect.top_left.y as usize);
+        SurfaceView {
+            ptr: self.ptr,
+            origin: self.origin + x + y * self.stride,
+            width: rect.size.width as u16,
+            height: rect.size.height as u16,
+            stride: self.stride,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, P: ByteTexel> SurfaceView<'a, P> {
+    #[inline]
+    pub fn row(&self, y: u16) -> &'a [P] {
+        assert!(y < self.height);
+        unsafe {
+            core::slice::from_raw_parts(
+                self.ptr
+                    .as_ptr()
+                    .add(self.origin + y as usize * self.stride),
+                self.width as usize,
+            )
+        }
+    }
+}
+
+impl<P> Clone for SurfaceView<'_, P> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<P> Copy for SurfaceView<'_, P> {}
+
+impl<P> embedded_graphics::geometry::OriginDimensions for SurfaceView<'_, P> {
+    #[inline]
+    fn size(&self) -> embedded_graphics::geometry::Size {
+        embedded_graphics::geometry::Size::new(self.width as u32, self.height as u32)
+    }
+}
+
+pub struct SurfaceViewMut<'a, P> {
+    target: Target<P>,
+    size: Size,
+    _marker: PhantomData<&'a mut [P]>,
+}
+
+impl<P> SurfaceViewMut<'_, P> {
+    // Nested view; `rect` is in this view's coordinates and limited to it.
+    pub fn view_mut(&mut self, rect: Rectangle) -> SurfaceViewMut<'_, P> {
+        let local = Rectangle::new(Point::zero(), self.size).intersection(&rect);
+        let abs = Rectangle::new(local.top_left + self.target.offset, local.size);
+        SurfaceViewMut {
+            target: Target {
+                base: self.target.base,
+                stride: self.target.stride,
+                offset: abs.top_left,
+                clip: self.target.clip.intersection(&abs),
+            },
+            size: local.size,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<P> embedded_graphics::geometry::OriginDimensions for SurfaceViewMut<'_, P> {
+    #[inline]
+    fn size(&self) -> embedded_graphics::geometry::Size {
+        self.size
+    }
+}
+
+impl<P: Texel> embedded_graphics::draw_target::DrawTarget for SurfaceViewMut<'_, P> {
+    type Color = P;
+    type Error = core::convert::Infallible;
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = embedded_graphics::Pixel<Self::Color>>,
+    {
+        unsafe { self.target.draw_iter(pixels) };
+        Ok(())
+    }
+    fn fill_contiguous<I>(&mut self, area: &Rectangle, colors: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Self::Color>,
+    {
+        unsafe { self.target.fill_contiguous(area, colors) };
+        Ok(())
+    }
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        unsafe { self.target.fill_solid(area, color) };
+        Ok(())
+    }
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+// Colors the RDP can hold in its TLUT. Sealed, the TLUT only takes these
+// two 16-bit formats.
+pub trait TlutColor: Rgba + sealed::Sealed {}
+
+impl sealed::Sealed for RGBA5551 {}
+
+impl TlutColor for RGBA5551 {}
+
+impl sealed::Sealed for IA16 {}
+
+impl TlutColor for IA16 {}
+
+// Color-indexed texels.
+pub trait ColorIndex: Texel {
+    const COLORS: usize;
+    fn from_index(index: u8) -> Self;
+    fn index(self) -> u8;
+}
+
+impl ColorIndex for CI4 {
+    const COLORS: usize = 16;
+    #[inline]
+    fn from_index(index: u8) -> Self {
+        Self(RawU4::new(index))
+    }
+    #[inline]
+    fn index(self) -> u8 {
+        self.into_bits()
+    }
+}
+
+impl ColorIndex for CI8 {
+    const COLORS: usize = 256;
+    #[inline]
+    fn from_index(index: u8) -> Self {
+        Self::new(index)
+    }
+    #[inline]
+    fn index(self) -> u8 {
+        self.into_bits()
+    }
+}
+
+// 256 TLUT entries laid out as the RDP loads them. CI4 textures use one
+// bank of 16 entries.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+#[repr(C, align(8))]
+pub struct Palette<C> {
+    entries: [C; 256],
+}
+
+impl<C: TlutColor> Palette<C> {
+    #[inline]
+    pub const fn new(entries: [C; 256]) -> Self {
+        Self { entries }
+    }
+    #[inline]
+    pub const fn entries(&self) -> &[C; 256] {
+        &self.entries
+    }
+    #[inline]
+    pub const fn entries_mut(&mut self) -> &mut [C; 256] {
+        &mut self.entries
+    }
+    #[inline]
+    pub const fn get(&self, index: u8) -> C {
+        self.entries[index as usize]
+    }
+    #[inline]
+    pub const fn set(&mut self, index: u8, color: C) {
+        self.entries[index as usize] = color;
+    }
+    // Rotates `range` by `shift` entries towards the end, for color cycling.
+    pub fn cycle(&mut self, range: core::ops::Range<u8>, shift: usize) {
+        let entries = &mut self.entries[range.start as usize..range.end as usize];
+        if !entries.is_empty() {
+            entries.rotate_right(shift % entries.len());
+        }
+    }
+    // Sets every entry to `base` moved towards `target` by `amount`/255,
+    // keeping the alpha of `base`.
+    pub fn fade(&mut self, base: &Self, target: RGBA8888, amount: u8) {
+        let t = amount as u32;
+        for (c, b) in self.entries.iter_mut().zip(base.entries) {
+            let b = b.to_rgba8888();
+            *c = C::from_rgba8888(RGBA8888::new(
+                mix8(target.r(), b.r(), t),
+                mix8(target.g(), b.g(), t),
+                mix8(target.b(), b.b(), t),
+                b.a(),
+            ));
+        }
+    }
+    // The palette as loaded by the TLUT.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        let len = size_of_val(&self.entries);
+        unsafe { core::slice::from_raw_parts(self.entries.as_ptr() as *const u8, len) }
+    }
+}
+
+impl<C: TlutColor + Default> Default for Palette<C> {
+    fn default() -> Self {
+        Self::new([C::default(); 256])
+    }
+}
+
+// A color-indexed surface and the palette it is drawn with. Drawing through
+// `DrawTarget` picks the nearest palette entry; draw indices directly with
+// `surface_mut`.
+pub struct IndexedSurface<I: ColorIndex, C> {
+    surface: Surface<I>,
+    palette: Palette<C>,
+    bank: u8,
+}
+
+impl<I: ColorIndex, C: TlutColor> IndexedSurface<I, C> {
+    pub fn new(width: u16, height: u16, palette: Palette<C>) -> Self {
+        Self {
+            surface: Surface::new(width, height),
+            palette,
+            bank: 0,
+        }
+    }
+    #[inline]
+    pub const fn surface(&self) -> &Surface<I> {
+        &self.surface
+    }
+    #[inline]
+    pub const fn surface_mut(&mut self) -> &mut Surface<I> {
+        &mut self.surface
+    }
+    #[inline]
+    pub const fn palette(&self) -> &Palette<C> {
+        &self.palette
+    }
+    #[inline]
+    pub const fn palette_mut(&mut self) -> &mut Palette<C> {
+        &mut self.palette
+    }
+    // TLUT bank used by CI4 surfaces.
+    #[inline]
+    pub const fn bank(&self) -> u8 {
+        self.bank
+    }
+    #[inline]
+    fn first(&self) -> usize {
+        self.bank as usize * 16
+    }
+    #[inline]
+    pub fn color(&self, index: I) -> C {
+        self.palette.entries[self.first() + index.index() as usize]
+    }
+    // Index of the palette entry closest to `color`.
+    pub fn nearest(&self, color: C) -> I {
+        let c = color.to_rgba8888();
+        let dist = |e: &C| {
+            let e = e.to_rgba8888();
+            let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2) as u32;
+            d(c.r(), e.r()) + d(c.g(), e.g()) + d(c.b(), e.b()) + d(c.a(), e.a())
+        };
+        let entries = &self.palette.entries[self.first()..self.first() + I::COLORS];
+        let mut best = (u32::MAX, 0);
+        for (i, e) in entries.iter().enumerate() {
+            let d = dist(e);
+            if d < best.0 {
+                best = (d, i);
+            }
+            if d == 0 {
+                break;
+            }
+        }
+        I::from_index(best.1 as u8)
+    }
+    // The active palette entries resolved to RGBA8888.
+    fn lut(&self) -> [RGBA8888; 256] {
+        let mut lut = [RGBA8888::default(); 256];
+        let first = self.first();
+        for (l, e) in lut
+            .iter_mut()
+            .zip(&self.palette.entries[first..first + I::COLORS])
+        {
+            *l = e.to_rgba8888();
+        }
+        lut
+    }
+}
+
+impl<C: TlutColor> IndexedSurface<CI4, C> {
+    #[inline]
+    pub fn set_bank(&mut self, bank: u8) {
+        assert!(bank < 16);
+        self.bank = bank;
+    }
+}
+
+impl<I: ColorIndex, C> embedded_graphics::geometry::OriginDimensions for IndexedSurface<I, C> {
+    #[inline]
+    fn size(&self) -> embedded_graphics::geometry::Size {
+        self.surface.size()
+    }
+}
+
+impl<I: ColorIndex, C: TlutColor> embedded_graphics::draw_target::DrawTarget
+    for IndexedSurface<I, C>
+{
+    type Color = C;
+    type Error = core::convert::Infallible;
+    fn draw_iter<T>(&mut self, pixels: T) -> Result<(), Self::Error>
+    where
+        T: IntoIterator<Item = embedded_graphics::Pixel<Self::Color>>,
+    {
+        let mut last = None;
+        let pixels = pixels.into_iter().map(|embedded_graphics::Pixel(p, c)| {
+            let index = match last {
+                Some((l, index)) if l == c => index,
+                _ => self.nearest(c),
+            };
+            last = Some((c, index));
+            embedded_graphics::Pixel(p, index)
+        });
+        unsafe { self.surface.target().draw_iter(pixels) };
+        Ok(())
+    }
+    fn fill_solid(&mut self, area: &Rectangle, color: Self::Color) -> Result<(), Self::Error> {
+        let index = self.nearest(color);
+        self.surface.fill_solid(area, index)
+    }
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        let index = self.nearest(color);
+        self.surface.clear(index)
+    }
+}
+
+impl<D: Rgba> Surface<D> {
+    // Copies `src_rect` of `src` through its palette. The color key compares
+    // indices; alpha comes from the palette entries.
+    pub fn blit_indexed<I: ColorIndex, C: TlutColor>(
+        &mut self,
+        src: &IndexedSurface<I, C>,
+        src_rect: Rectangle,
+        dst: Point,
+        options: &BlitOptions<I>,
+    ) {
+        let clipped = src_rect.intersection(&src.surface.bounds());
+        let dst = dst + (clipped.top_left - src_rect.top_left);
+        let area = Rectangle::new(dst, clipped.size).intersection(&self.clip);
+        if area.is_zero_sized() {
+            return;
+        }
+        let lut = src.lut();
+        let sx = (clipped.top_left.x + area.top_left.x - dst.x) as usize;
+        let sy = (clipped.top_left.y + area.top_left.y - dst.y) as usize;
+        let (dx, dy) = (area.top_left.x as usize, area.top_left.y as usize);
+        let (s, d) = (src.surface.ptr.as_ptr(), self.ptr.as_ptr());
+        for row in 0..area.size.height as usize {
+            let si = sx + (sy + row) * src.surface.stride as usize;
+            let di = dx + (dy + row) * self.stride as usize;
+            for i in 0..area.size.width as usize {
+                let c = unsafe { get(s, si + i) };
+                if options.color_key == Some(c) {
+                    continue;
+                }
+                unsafe { store(d, di + i, lut[c.index() as usize], options.alpha) };
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub enum Dither {
+    #[default]
+    None,
+    // 4x4 ordered dither with the RDP's magic square.
+    Bayer,
+    FloydSteinberg,
+}
+
+const MAGIC_SQUARE: [u8; 16] = [0, 6, 1, 7, 4, 2, 5, 3, 3, 5, 2, 4, 7, 1, 6, 0];
+
+// Quantizes an 8-bit channel to 5 bits, rounding up when the dropped bits
+// exceed the threshold as the RDP does.
+#[inline]
+const fn dither5(v: u8, threshold: u8) -> u16 {
+    let q = (v >> 3) as u16;
+    if (v & 7) > threshold && q < 31 {
+        q + 1
+    } else {
+        q
+    }
+}
+
+#[inline]
+const fn expand5(q: i16) -> i16 {
+    (q << 3) | (q >> 2)
+}
+
+// Converts RGBA8888 rows to RGBA5551 one at a time, keeping the error
+// carried between rows by Floyd-Steinberg.
+pub struct Quantizer {
+    dither: Dither,
+    width: usize,
+    y: usize,
+    // Error for the current and next row, three channels per pixel with a
+    // pixel of padding at each end.
+    errors: [Vec<i16>; 2],
+}
+
+impl Quantizer {
+    pub fn new(width: usize, dither: Dither) -> Self {
+        let len = match dither {
+            Dither::FloydSteinberg => (width + 2) * 3,
+            _ => 0,
+        };
+        Self {
+            dither,
+            width,
+            y: 0,
+            errors: [alloc::vec![0; len], alloc::vec![0; len]],
+        }
+    }
+    // Converts `src` into `dst`, which must be no wider than the quantizer.
+    pub fn row(&mut self, src: &[RGBA8888], dst: &mut [RGBA5551]) {
+        let n = src.len().min(dst.len());
+        assert!(
+            n <= self.width,
+            "row of {n} pixels is wider than {}",
+            self.width
+        );
+        let y = self.y;
+        self.y += 1;
+        match self.dither {
+            Dither::None => {
+                for (d, s) in dst.iter_mut().zip(src) {
+                    *d = RGBA5551::from_rgba8888(*s);
+                }
+            }
+            Dither::Bayer => {
+                let matrix = &MAGIC_SQUARE[(y & 3) * 4..(y & 3) * 4 + 4];
+                for (x, (d, s)) in dst.iter_mut().zip(src).enumerate() {
+                    let t = matrix[x & 3];
+                    *d = RGBA5551::from_u16(
+                        (dither5(s.r(), t) << 11)
+                            | (dither5(s.g(), t) << 6)
+                            | (dither5(s.b(), t) << 1)
+                            | (s.a() >> 7) as u16,
+                    );
+                }
+            }
+            Dither::FloydSteinberg => {
+                let [cur, next] = &mut self.errors;
+                next.fill(0);
+                for (x, (d, s)) in dst.iter_mut().zip(src).enumerate() {
+                    let mut out = (s.a() >> 7) as u16;
+                    for (c, v) in [s.r(), s.g(), s.b()].into_iter().enumerate() {
+                        let i = (x + 1) * 3 + c;
+                        // Error is carried in sixteenths so that small
+                        // remainders still add up.
+                        let v = (v as i16 * 16 + cur[i]).clamp(0, 0xFF * 16);
+                        let q = v >> 7;
+                        let e = v - expand5(q) * 16;
+                        let (right, below_left, below) = (e * 7 / 16, e * 3 / 16, e * 5 / 16);
+                        cur[i + 3] += right;
+                        next[i - 3] += below_left;
+                        next[i] += below;
+                        next[i + 3] += e - right - below_left - below;
+                        out |= (q as u16) << (11 - 5 * c);
+                    }
+                    *d = RGBA5551::from_u16(out);
+                }
+                core::mem::swap(cur, next);
+            }
+        }
+    }
+}
+
+impl Surface<RGBA5551> {
+    // Converts the overlapping area of `src` into this surface.
+    pub fn convert_from(&mut self, src: &Surface<RGBA8888>, dither: Dither) {
+        let width = self.width.min(src.width) as usize;
+        let mut q = Quantizer::new(width, dither);
+        for y in 0..self.height.min(src.height) {
+            q.row(&src.row(y)[..width], &mut self.row_mut(y)[..width]);
+        }
+    }
+}
+
+// Converts `src` into `dst` as rows of `width` pixels.
+pub fn convert_slice(src: &[RGBA8888], dst: &mut [RGBA5551], width: usize, dither: Dither) {
+    if width == 0 {
+        return;
+    }
+    let mut q = Quantizer::new(width, dither);
+    for (s, d) in src.chunks(width).zip(dst.chunks_mut(width)) {
+        q.row(s, d);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_graphics::primitives::{PrimitiveStyle, StyledDrawable};
+
+    fn rect(x: i32, y: i32, w: u32, h: u32) -> Rectangle {
+        Rectangle::new(Point::new(x, y), Size::new(w, h))
+    }
+
+    fn surface(w: u16, h: u16) -> Surface<RGBA8888> {
+        let mut s = Surface::new(w, h);
+        s.clear(RGBA8888::BLACK).unwrap();
+        s
+    }
+
+    // One string per row, `#` for white pixels.
+    fn mask(s: &Surface<RGBA8888>) -> Vec<String> {
+        (0..s.height())
+            .map(|y| {
+                s.row(y)
+                    .iter()
+                    .map(|&c| if c == RGBA8888::WHITE { '#' } else { '.' })
+                    .collect()
+            })
+            .collect()
+    }
+
+    // Draws through every path a clip has to cover.
+    fn draw_all(s: &mut Surface<RGBA8888>) {
+        let area = s.bounding_box();
+        s.fill_solid(&rect(-4, -4, 40, 40), RGBA8888::WHITE)
+            .unwrap();
+        s.fill_contiguous(&area, core::iter::repeat(RGBA8888::WHITE))
+            .unwrap();
+        s.draw_iter(area.points().map(|p| Pixel(p, RGBA8888::WHITE)))
+            .unwrap();
+        s.clear(RGBA8888::WHITE).unwrap();
+    }
+
+    #[test]
+    fn format_round_trip() {
+        for i in 0..16 {
+            let c = I4::new(u4::new(i));
+            assert_eq!(I4::from(RGBA8888::from(c)), c);
+            let c = CI4::new(u4::new(i));
+            assert_eq!(c.index(), i);
+            assert_eq!(CI4::from_bits(Texel::into_bits(c)), c);
+            for a in 0..16 {
+                let c = IA8::new(u4::new(i), u4::new(a));
+                assert_eq!(IA8::from(RGBA8888::from(c)), c);
+            }
+        }
+        for i in 0..8 {
+            for a in 0..2 {
+                let c = IA4::new(u3::new(i), u1::new(a));
+                assert_eq!(IA4::from(RGBA8888::from(c)), c);
+            }
+        }
+        for i in 0..=255 {
+            let c = I8::new(i);
+            assert_eq!(I8::from(RGBA8888::from(c)), c);
+            assert_eq!(CI8::new(i).index(), i);
+            for a in [0, 1, 0x7F, 0x80, 0xFF] {
+                let c = IA16::new(i, a);
+                assert_eq!(IA16::from(RGBA8888::from(c)), c);
+            }
+        }
+
+        let white = RGBA8888::new(255, 255, 255, 255);
+        assert_eq!(RGBA8888::from(I4::new(u4::new(0xF))), white);
+        assert_eq!(I4::from(RGBA8888::new(0x80, 0x80, 0x80, 0)).i().value(), 8);
+        assert_eq!(IA4::from(white), IA4::new(u3::new(7), u1::new(1)));
+        assert_eq!(
+            RGBA8888::from(IA4::new(u3::new(5), u1::new(0))),
+            RGBA8888::new(0xB6, 0xB6, 0xB6, 0)
+        );
+        assert_eq!(
+            RGBA8888::from(IA8::new(u4::new(8), u4::new(4))),
+            RGBA8888::new(0x88, 0x88, 0x88, 0x44)
+        );
+        assert_eq!(IA16::from(RGBA5551::WHITE), IA16::new(255, 255));
+    }
+
+    #[test]
+    fn nibble_packing() {
+        // The first pixel of each byte is the high nibble.
+        let mut s = Surface::<I4>::new(5, 3);
+        assert_eq!((s.stride(), s.pitch()), (16, 8));
+        s.as_bytes_mut().fill(0);
+        for (x, y, i) in [(0, 0, 0xA), (1, 0, 0x3), (4, 1, 0x7), (3, 2, 0x5)] {
+            Pixel(Point::new(x, y), I4::new(u4::new(i)))
+                .draw(&mut s)
+                .unwrap();
+        }
+        assert_eq!(s.as_bytes()[..3], [0xA3, 0, 0]);
+        assert_eq!(s.as_bytes()[8..11], [0, 0, 0x70]);
+        assert_eq!(s.as_bytes()[16..19], [0, 0x05, 0]);
+
+        // Fills from every start parity and length leave the other half of
+        // shared bytes alone.
+        for x in 0..5 {
+            for w in 0..7 {
+                let mut s = Surface::<CI4>::with_stride(10, 1, 16);
+                s.as_bytes_mut().fill(0x11);
+                let area = rect(x, 0, w, 1);
+                s.fill_solid(&area, CI4::new(u4::new(9))).unwrap();
+                let mut t = Surface::<CI4>::with_stride(10, 1, 16);
+                t.as_bytes_mut().fill(0x11);
+                t.fill_contiguous(&area, core::iter::repeat(CI4::new(u4::new(9))))
+                    .unwrap();
+                for i in 0..16 {
+                    let want = if area.contains(Point::new(i, 0)) {
+                        9
+                    } else {
+                        1
+                    };
+                    let nibble = s.as_bytes()[i as usize / 2] >> (4 - i % 2 * 4) & 0xF;
+                    assert_eq!(nibble, want, "{x} {w} {i}");
+                    let nibble = t.as_bytes()[i as usize / 2] >> (4 - i % 2 * 4) & 0xF;
+                    assert_eq!(nibble, want, "{x} {w} {i}");
+                }
+            }
+        }
+    }
+
+    const fn hsv(h: u16, s: u8, v: u8) -> Hsv {
+        Hsv { h, s, v }
+    }
+
+    // Built at compile time, as a game's palette would be.
+    const PALETTE: [RGBA8888; 4] = [
+        RGBA8888::from_hsv(hsv(0, 255, 255), 255),
+        RGBA8888::from_hsv(hsv(240, 255, 128), 255),
+        RGBA8888::linear(128, 188, 255, 255),
+        RGBA8888::new(10, 20, 30, 255).lerp(RGBA8888::new(255, 255, 255, 255), 128),
+    ];
+    const GREEN: RGBA5551 = RGBA5551::from_hsv(hsv(120, 255, 128), u1::new(1));
+
+    #[test]
+    fn const_palette() {
+        assert_eq!(
+            PALETTE,
+            [
+                RGBA8888::new(255, 0, 0, 255),
+                RGBA8888::new(0, 0, 128, 255),
+                RGBA8888::new(55, 128, 255, 255),
+                RGBA8888::new(133, 138, 143, 255),
+            ]
+        );
+        assert_eq!(channels(GREEN), [0, 16, 0, 1]);
+        assert_eq!(channels(GREEN.to_linear()), [0, 7, 0, 1]);
+    }
+
+    #[test]
+    fn srgb() {
+        for (srgb, linear) in [
+            (0, 0),
+            (1, 0),
+            (10, 1),
+            (64, 13),
+            (128, 55),
+            (188, 128),
+            (255, 255),
+        ] {
+            assert_eq!(srgb_to_linear(srgb), linear, "{srgb}");
+        }
+        for (linear, srgb) in [(0, 0), (1, 13), (13, 64), (55, 128), (128, 188), (255, 255)] {
+            assert_eq!(linear_to_srgb(linear), srgb, "{linear}");
+        }
+        // A trip through the other encoding is off by at most a step, and a
+        // second trip changes nothing.
+        for v in 0..=255 {
+            let linear = srgb_to_linear(linear_to_srgb(v));
+            assert!(linear.abs_diff(v) <= 1, "{v}");
+            assert_eq!(srgb_to_linear(linear_to_srgb(linear)), linear, "{v}");
+            let srgb = linear_to_srgb(srgb_to_linear(v));
+            assert_eq!(linear_to_srgb(srgb_to_linear(srgb)), srgb, "{v}");
+        }
+        let c = RGBA8888::new(200, 100, 50, 77);
+        assert_eq!(c.to_linear(), RGBA8888::new(147, 32, 8, 77));
+        assert_eq!(c.to_linear().to_srgb(), RGBA8888::new(200, 99, 49, 77));
+        for i in 0..32 {
+            let c = RGBA5551::new(u5::new(i), u5::new(i), u5::new(i), u1::new(0));
+            assert_eq!(c.to_linear().to_srgb().to_linear(), c.to_linear(), "{i}");
+        }
+    }
+
+    #[test]
+    fn hsv_round_trip() {
+        for (h, rgb) in [
+            (0, [255, 0, 0]),
+            (60, [255, 255, 0]),
+            (120, [0, 255, 0]),
+            (180, [0, 255, 255]),
+            (240, [0, 0, 255]),
+            (300, [255, 0, 255]),
+        ] {
+            let hsv = hsv(h, 255, 255);
+            let c = RGBA8888::from_hsv(hsv, 7);
+            assert_eq!([c.r(), c.g(), c.b(), c.a()], [rgb[0], rgb[1], rgb[2], 7]);
+            assert_eq!(c.to_hsv(), hsv);
+        }
+        for v in [0, 1, 128, 255] {
+            let grey = RGBA8888::new(v, v, v, 255);
+            assert_eq!(grey.to_hsv(), hsv(0, 0, v));
+            assert_eq!(RGBA8888::from_hsv(hsv(200, 0, v), 255), grey);
+        }
+        assert_eq!(
+            RGBA8888::from_hsv(hsv(390, 255, 255), 255),
+            RGBA8888::new(255, 127, 0, 255)
+        );
+        assert_eq!(
+            RGBA5551::from_hsv(hsv(240, 255, 255), u1::new(0)).to_hsv(),
+            hsv(240, 255, 255)
+        );
+    }
+
+    #[test]
+    fn blend_modes() {
+        let blue = RGBA8888::new(0, 0, 255, 255);
+        let half_red = RGBA8888::new(255, 0, 0, 128);
+        assert_eq!(
+            half_red.premultiply().blend_premultiplied(blue),
+            RGBA8888::new(128, 0, 127, 255)
+        );
+        assert_eq!(blend(half_red, blue), RGBA8888::new(128, 0, 127, 255));
+        assert_eq!(
+            RGBA8888::new(200, 100, 0, 255).blend_add(RGBA8888::new(100, 100, 100, 9)),
+            RGBA8888::new(255, 200, 100, 9)
+        );
+        assert_eq!(
+            RGBA8888::new(200, 100, 0, 128).blend_add(RGBA8888::new(100, 100, 100, 9)),
+            RGBA8888::new(200, 150, 100, 9)
+        );
+        let grey = RGBA8888::new(200, 200, 200, 255);
+        assert_eq!(
+            RGBA8888::new(128, 255, 0, 255).blend_multiply(grey),
+            RGBA8888::new(100, 200, 0, 255)
+        );
+        assert_eq!(RGBA8888::new(0, 0, 0, 0).blend_multiply(grey), grey);
+        assert_eq!(
+            RGBA5551::from_u16(0xF83F).blend_add(RGBA5551::from_u16(0x003F)),
+            RGBA5551::from_u16(0xF83F)
+        );
+
+        let (a, b) = (
+            RGBA8888::new(10, 20, 30, 0),
+            RGBA8888::new(210, 120, 30, 255),
+        );
+        assert_eq!(a.lerp(b, 0), a);
+        assert_eq!(a.lerp(b, 255), b);
+        assert_eq!(a.lerp(b, 64), RGBA8888::new(60, 45, 30, 64));
+    }
+
+    #[test]
+    fn clip_negative_origin() {
+        let mut s = surface(6, 4);
+        s.set_clip(rect(-3, -2, 5, 4));
+        assert_eq!(s.clip(), rect(0, 0, 2, 2));
+        draw_all(&mut s);
+        assert_eq!(mask(&s), ["##....", "##....", "......", "......"]);
+
+        let mut s = surface(6, 4);
+        s.push_clip(rect(-10, 1, 12, 1));
+        assert_eq!(s.clip(), rect(0, 1, 2, 1));
+        rect(-2, -2, 8, 8)
+            .draw_styled(&PrimitiveStyle::with_fill(RGBA8888::WHITE), &mut s)
+            .unwrap();
+        assert_eq!(mask(&s), ["......", "##....", "......", "......"]);
+    }
+
+    #[test]
+    fn clip_past_edge() {
+        let mut s = surface(6, 4);
+        s.set_clip(rect(4, 2, 100, 100));
+        assert_eq!(s.clip(), rect(4, 2, 2, 2));
+        draw_all(&mut s);
+        assert_eq!(mask(&s), ["......", "......", "....##", "....##"]);
+
+        let mut s = surface(6, 4);
+        s.push_clip(rect(3, -1, 10, 3));
+        draw_all(&mut s);
+        assert_eq!(mask(&s), ["...###", "...###", "......", "......"]);
+    }
+
+    #[test]
+    fn clip_zero_sized() {
+        for clip in [rect(2, 2, 0, 0), rect(2, 2, 3, 0), rect(10, 10, 4, 4)] {
+            let mut s = surface(6, 4);
+            s.set_clip(clip);
+            assert!(s.clip().is_zero_sized(), "{clip:?}");
+            draw_all(&mut s);
+            assert!(mask(&s).iter().all(|r| r == "......"), "{clip:?}");
+        }
+        // Narrowing to a rect outside the current clip leaves nothing.
+        let mut s = surface(6, 4);
+        s.push_clip(rect(0, 0, 2, 2));
+        s.push_clip(rect(3, 3, 2, 2));
+        assert!(s.clip().is_zero_sized());
+        draw_all(&mut s);
+        assert!(mask(&s).iter().all(|r| r == "......"));
+    }
+
+    #[test]
+    fn view_mut_size() {
+        let mut s = surface(6, 4);
+        let mut v = s.view_mut(rect(-2, -1, 4, 3));
+        assert_eq!(v.size(), Size::new(2, 2));
+        v.clear(RGBA8888::WHITE).unwrap();
+        assert_eq!(mask(&s), ["##....", "##....", "......", "......"]);
+
+        let mut s = surface(6, 4);
+        let mut v = s.view_mut(rect(4, 2, 10, 10));
+        assert_eq!(v.size(), Size::new(2, 2));
+        let mut inner = v.view_mut(rect(1, -1, 5, 5));
+        assert_eq!(inner.size(), Size::new(1, 2));
+        inner.clear(RGBA8888::WHITE).unwrap();
+        assert_eq!(mask(&s), ["......", "......", ".....#", ".....#"]);
+
+        // The surface clip limits drawing but not the reported size.
+        let mut s = surface(6, 4);
+        s.push_clip(rect(0, 0, 2, 4));
+        let mut v = s.view_mut(rect(1, 1, 3, 2));
+        assert_eq!(v.size(), Size::new(3, 2));
+        v.clear(RGBA8888::WHITE).unwrap();
+        assert_eq!(mask(&s), ["......", ".#....", ".#....", "......"]);
+        assert_eq!(s.view_mut(rect(7, 0, 2, 2)).size(), Size::zero());
+    }
+
+    #[test]
+    fn stride_padding() {
+        let mut s = Surface::<RGBA8888>::with_stride(3, 2, 6);
+        assert!(s.stride() > Surface::<RGBA8888>::aligned_stride(3));
+        assert_eq!(s.pitch(), 24);
+        assert_eq!(s.as_bytes().len(), 48);
+        s.as_bytes_mut().fill(0xAA);
+        s.row_mut(1).fill(RGBA8888::BLACK);
+        assert_eq!(mask(&s), ["...", "..."]);
+        draw_all(&mut s);
+        s.fill_contiguous(&rect(1, 0, 2, 2), core::iter::repeat(RGBA8888::BLACK))
+            .unwrap();
+        assert_eq!(mask(&s), ["#..", "#.."]);
+        for row in s.as_bytes().chunks_exact(s.pitch()) {
+            assert_eq!(row[12..], [0xAA; 12]);
+        }
+    }
+
+    // 5x3 source with a letter per pixel, `a` to `o` in row order.
+    fn letters() -> Surface<RGBA8888> {
+        let mut s = Surface::new(5, 3);
+        for y in 0..3 {
+            for x in 0..5 {
+                s.row_mut(y)[x] = RGBA8888::new((y * 5) as u8 * 16 + x as u8 * 16, 0, 0, 255);
+            }
+        }
+        s
+    }
+
+    // Untouched (transparent) pixels are `.`.
+    fn spell(s: &Surface<RGBA8888>) -> Vec<String> {
+        (0..s.height())
+            .map(|y| {
+                s.row(y)
+                    .iter()
+                    .map(|c| match c.a() {
+                        0 => '.',
+                        _ => (b'a' + c.r() / 16) as char,
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn blank(w: u16, h: u16) -> Surface<RGBA8888> {
+        let mut s = Surface::new(w, h);
+        s.clear(RGBA8888::new(0, 0, 0, 0)).unwrap();
+        s
+    }
+
+    #[test]
+    fn blit_formats() {
+        // 8888 to 5551 to 5551 to 8888 covers every conversion pair.
+        let src = letters();
+        let mut a = Surface::<RGBA5551>::new(5, 3);
+        a.blit(
+            &src,
+            src.bounding_box(),
+            Point::zero(),
+            &BlitOptions::OPAQUE,
+        );
+        assert_eq!(channels(a.row(1)[2]), [14, 0, 0, 1]);
+        let mut b = Surface::<RGBA5551>::new(6, 3);
+        b.clear(RGBA5551::from_u16(0)).unwrap();
+        b.blit(
+            &a,
+            rect(1, -1, 9, 3),
+            Point::new(2, 0),
+            &BlitOptions::OPAQUE,
+        );
+        let mut d = blank(6, 3);
+        d.blit(&b, b.bounding_box(), Point::zero(), &BlitOptions::OPAQUE);
+        assert_eq!(spell(&d), ["......", "..bcde", "..ghij"]);
+        let mut d = blank(7, 4);
+        d.blit(
+            &src,
+            rect(-1, 0, 3, 9),
+            Point::new(5, 2),
+            &BlitOptions::OPAQUE,
+        );
+        assert_eq!(spell(&d), [".......", ".......", "......a", "......f"]);
+    }
+
+    #[test]
+    fn blit_color_key() {
+        let src = letters();
+        let options = BlitOptions {
+            color_key: Some(src.row(1)[1]),
+            alpha: false,
+        };
+        let mut d = blank(5, 3);
+        d.blit(&src, src.bounding_box(), Point::zero(), &options);
+        assert_eq!(spell(&d), ["abcde", "f.hij", "klmno"]);
+
+        let mut a = Surface::<RGBA5551>::new(5, 3);
+        a.blit(
+            &src,
+            src.bounding_box(),
+            Point::zero(),
+            &BlitOptions::OPAQUE,
+        );
+        let options = BlitOptions {
+            color_key: Some(a.row(0)[4]),
+            alpha: false,
+        };
+        let mut d = blank(5, 3);
+        d.blit(&a, a.bounding_box(), Point::zero(), &options);
+        assert_eq!(spell(&d), ["abcd.", "fghij", "klmno"]);
+    }
+
+    #[test]
+    fn blit_alpha() {
+        // 1-bit alpha: transparent pixels leave the destination alone.
+        let mut src = Surface::<RGBA5551>::new(5, 3);
+        src.blit(
+            &letters(),
+            rect(0, 0, 5, 3),
+            Point::zero(),
+            &BlitOptions::OPAQUE,
+        );
+        for (x, y) in [(0, 0), (3, 1), (4, 2)] {
+            src.row_mut(y)[x] = RGBA5551::from_u16(0xFFFE);
+        }
+        let mut d = letters();
+        d.fill_solid(&rect(0, 0, 5, 3), RGBA8888::new(15 * 16, 0, 0, 255))
+            .unwrap();
+        d.blit(&src, src.bounding_box(), Point::zero(), &BlitOptions::ALPHA);
+        assert_eq!(spell(&d), ["pbcde", "fghpj", "klmnp"]);
+
+        // 8-bit alpha blends over the destination, skipping alpha 0.
+        let mut src = Surface::<RGBA8888>::new(3, 1);
+        src.row_mut(0).copy_from_slice(&[
+            RGBA8888::new(255, 0, 0, 128),
+            RGBA8888::new(255, 0, 0, 0),
+            RGBA8888::new(255, 0, 0, 255),
+        ]);
+        let mut d = Surface::<RGBA8888>::new(3, 1);
+        d.clear(RGBA8888::new(0, 0, 255, 255)).unwrap();
+        d.blit(&src, src.bounding_box(), Point::zero(), &BlitOptions::ALPHA);
+        assert_eq!(
+            d.row(0),
+            [
+                RGBA8888::new(128, 0, 127, 255),
+                RGBA8888::new(0, 0, 255, 255),
+                RGBA8888::new(255, 0, 0, 255),
+            ]
+        );
+        // Without the option alpha is copied like any other channel.
+        d.blit(
+            &src,
+            src.bounding_box(),
+            Point::zero(),
+            &BlitOptions::OPAQUE,
+        );
+        assert_eq!(d.row(0), src.row(0));
+    }
+
+    // One hex digit per I4 pixel.
+    fn nibbles(s: &Surface<I4>) -> Vec<String> {
+        (0..s.height() as i32)
+            .map(|y| {
+                (0..s.width() as i32)
+                    .map(|x| {
+                        let i = s.pixel(Point::new(x, y)).unwrap().i().value();
+                        char::from_digit(i as u32, 16).unwrap()
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn blit_raw_nibbles() {
+        let mut src = Surface::<I4>::new(5, 2);
+        for p in src.bounding_box().points() {
+            let i = (p.y * 5 + p.x + 1) as u8;
+            src.draw_iter([Pixel(p, I4::new(u4::new(i)))]).unwrap();
+        }
+        let filled = || {
+            let mut d = Surface::<I4>::new(8, 2);
+            d.clear(I4::new(u4::new(0xF))).unwrap();
+            d
+        };
+        // Even to even copies whole bytes, then the odd last pixel alone.
+        let mut d = filled();
+        d.blit(
+            &src,
+            src.bounding_box(),
+            Point::zero(),
+            &BlitOptions::OPAQUE,
+        );
+        assert_eq!(nibbles(&d), ["12345fff", "6789afff"]);
+        // Odd destinations or sources go a pixel at a time.
+        let mut d = filled();
+        d.blit(
+            &src,
+            src.bounding_box(),
+            Point::new(3, 0),
+            &BlitOptions::OPAQUE,
+        );
+        assert_eq!(nibbles(&d), ["fff12345", "fff6789a"]);
+        let mut d = filled();
+        d.blit(
+            &src,
+            rect(1, 0, 3, 2),
+            Point::new(2, 0),
+            &BlitOptions::OPAQUE,
+        );
+        assert_eq!(nibbles(&d), ["ff234fff", "ff789fff"]);
+        let mut d = filled();
+        d.blit(
+            &src,
+            rect(1, 1, 4, 1),
+            Point::new(4, 0),
+            &BlitOptions::OPAQUE,
+        );
+        assert_eq!(nibbles(&d), ["ffff789a", "ffffffff"]);
+    }
+
+    #[test]
+    fn blit_scaled_golden() {
+        let src = letters();
+        let golden = [
+            (Flip::NONE, ["aabbccddee", "ffgghhiijj", "kkllmmnnoo"]),
+            (Flip::HORIZONTAL, ["eeddccbbaa", "jjiihhggff", "oonnmmllkk"]),
+            (Flip::VERTICAL, ["kkllmmnnoo", "ffgghhiijj", "aabbccddee"]),
+            (Flip::BOTH, ["oonnmmllkk", "jjiihhggff", "eeddccbbaa"]),
+        ];
+        for (flip, rows) in golden {
+            let mut d = blank(14, 8);
+            let area = rect(2, 1, 10, 6);
+            d.blit_scaled(
+                &src,
+                src.bounding_box(),
+                area,
+                flip,
+                Filter::Nearest,
+                &BlitOptions::OPAQUE,
+            );
+            let mut expected = vec![String::from("..............")];
+            for row in rows {
+                expected.extend([format!("..{row}.."), format!("..{row}..")]);
+            }
+            expected.push(String::from(".............."));
+            assert_eq!(spell(&d), expected, "{flip:?}");
+        }
+        // Downscale of a sub-rectangle.
+        let mut d = blank(2, 1);
+        d.blit_scaled(
+            &src,
+            rect(1, 1, 4, 1),
+            d.bounding_box(),
+            Flip::NONE,
+            Filter::Nearest,
+            &BlitOptions::OPAQUE,
+        );
+        assert_eq!(spell(&d), ["hj"]);
+        // Bilinear upscale of a two pixel ramp.
+        let mut ramp = Surface::new(2, 1);
+        ramp.row_mut(0)
+            .copy_from_slice(&[RGBA8888::new(0, 0, 0, 255), RGBA8888::new(200, 0, 0, 255)]);
+        let mut d = blank(4, 1);
+        d.blit_scaled(
+            &ramp,
+            ramp.bounding_box(),
+            d.bounding_box(),
+            Flip::NONE,
+            Filter::Bilinear,
+            &BlitOptions::OPAQUE,
+        );
+        let r: Vec<u8> = d.row(0).iter().map(|c| c.r()).collect();
+        assert_eq!(r, [0, 50, 150, 200]);
+    }
+
+    #[test]
+    fn blit_affine_golden() {
+        let src = letters();
+        // A quarter turn clockwise, pivoting on the top left corner of `h`.
+        let m = Affine::rotate_scale(
+            Point::new(2, 1),
+            Point::new(3, 3),
+            0x4000,
+            Affine::ONE,
+            Affine::ONE,
+        );
+        let mut d = blank(7, 7);
+        d.blit_affine(
+            src.view(src.bounding_box()),
+            d.bounding_box(),
+            &m,
+            Filter::Nearest,
+            &BlitOptions::OPAQUE,
+        );
+        assert_eq!(
+            spell(&d),
+            [
+                ".......", ".kfa...", ".lgb...", ".mhc...", ".nid...", ".oje...", "......."
+            ]
+        );
+        // Twice the size, turned 30 degrees.
+        let m = Affine::rotate_scale(
+            Point::new(2, 1),
+            Point::new(6, 4),
+            0x1555,
+            2 * Affine::ONE,
+            2 * Affine::ONE,
+        );
+        let mut d = blank(13, 9);
+        d.blit_affine(
+            src.view(src.bounding_box()),
+            d.bounding_box(),
+            &m,
+            Filter::Nearest,
+            &BlitOptions::OPAQUE,
+        );
+        assert_eq!(
+            spell(&d),
+            [
+                "...a.........",
+                "...aab.......",
+                "..faabb......",
+                "..ffgbccc....",
+                ".kkggghcdde..",
+                ".kkllhhiidee.",
+                "..lllmhiije..",
+                "....mmnnijj..",
+                "......nnoo...",
+            ]
+        );
+        // Identity with bilinear filtering is a plain copy.
+        let mut d = blank(5, 3);
+        d.blit_affine(
+            src.view(src.bounding_box()),
+            d.bounding_box(),
+            &Affine::IDENTITY,
+            Filter::Bilinear,
+            &BlitOptions::OPAQUE,
+        );
+        for y in 0..3 {
+            assert_eq!(d.row(y), src.row(y));
+        }
+    }
+
+    #[test]
+    fn blit_mode7_golden() {
+        let src = letters();
+        let mut d = blank(8, 4);
+        d.blit_mode7(
+            src.view(src.bounding_box()),
+            d.bounding_box(),
+            Filter::Nearest,
+            &BlitOptions::OPAQUE,
+            |y| Affine::IDENTITY.translate(y << 16, 0),
+        );
+        assert_eq!(spell(&d), ["abcdeabc", "ghijfghi", "mnoklmno", "deabcdea"]);
+    }
+
+    #[test]
+    fn blit_degenerate() {
+        let src = letters();
+        let view = src.view(src.bounding_box());
+        for angle in [0, 0x2000, 0x4000, 0x9000] {
+            for (sx, sy) in [(0, Affine::ONE), (Affine::ONE, 0), (0, 0)] {
+                let m = Affine::rotate_scale(Point::new(2, 1), Point::new(4, 4), angle, sx, sy);
+                let mut d = blank(9, 9);
+                d.blit_affine(
+                    view,
+                    d.bounding_box(),
+                    &m,
+                    Filter::Bilinear,
+                    &BlitOptions::OPAQUE,
+                );
+                assert!(spell(&d).iter().all(|r| r == "........."));
+            }
+            // The smallest scales blow up past the 16.16 range.
+            let m = Affine::rotate_scale(Point::new(2, 1), Point::new(4, 4), angle, 1, -1);
+            let mut d = blank(9, 9);
+            d.blit_affine(
+                view,
+                d.bounding_box(),
+                &m,
+                Filter::Nearest,
+                &BlitOptions::OPAQUE,
+            );
+        }
+        // Steps that would overflow part way along a row.
+        let m = Affine::new(
+            i32::MAX / 3,
+            0,
+            0,
+            i32::MIN / 3,
+            i32::MAX - 10,
+            i32::MIN + 10,
+        );
+        let mut d = blank(9, 9);
+        d.blit_affine(
+            view,
+            d.bounding_box(),
+            &m,
+            Filter::Nearest,
+            &BlitOptions::OPAQUE,
+        );
+        d.blit_mode7(
+            view,
+            d.bounding_box(),
+            Filter::Bilinear,
+            &BlitOptions::OPAQUE,
+            |_| m,
+        );
+    }
+
+    #[test]
+    fn palette_banks() {
+        let mut palette = Palette::<IA16>::default();
+        for i in 0..=255 {
+            palette.set(i, IA16::new(i, 255));
+        }
+        assert_eq!(palette.as_bytes().len(), 512);
+        let mut s = IndexedSurface::<CI4, IA16>::new(5, 1, palette);
+        s.set_bank(2);
+        s.clear(IA16::new(40, 255)).unwrap();
+        assert_eq!(s.surface().pixel(Point::new(4, 0)).unwrap().index(), 8);
+        assert_eq!(s.color(CI4::from_index(8)), IA16::new(40, 255));
+        let mut d = Surface::<IA16>::new(5, 1);
+        d.blit_indexed(&s, s.bounding_box(), Point::zero(), &BlitOptions::OPAQUE);
+        assert_eq!(d.row(0), [IA16::new(40, 255); 5]);
+    }
+
+    fn gradient(w: u16, h: u16) -> Surface<RGBA8888> {
+        let mut s = Surface::new(w, h);
+        for y in 0..h {
+            for (x, c) in s.row_mut(y).iter_mut().enumerate() {
+                let x = x as u8;
+                *c = RGBA8888::new(x * 4 + y as u8, x * 2, 255 - x * 3, 255);
+            }
+        }
+        s
+    }
+
+    fn channels(c: RGBA5551) -> [u8; 4] {
+        [c.r().value(), c.g().value(), c.b().value(), c.a().value()]
+    }
+
+    #[test]
+    fn quantize_golden() {
+        let src = gradient(16, 4);
+        for dither in [Dither::None, Dither::Bayer, Dither::FloydSteinberg] {
+            let mut d = Surface::<RGBA5551>::new(16, 4);
+            d.convert_from(&src, dither);
+            let flat: Vec<_> = (0..4).flat_map(|y| src.row(y).to_vec()).collect();
+            let mut out = vec![RGBA5551::default(); flat.len()];
+            convert_slice(&flat, &mut out, 16, dither);
+            for y in 0..4 {
+                assert_eq!(d.row(y), &out[y as usize * 16..][..16], "{dither:?}");
+            }
+        }
+        // Red for every row, then green and blue for row 1.
+        let golden = [
+            (
+                Dither::None,
+                [[0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7]; 4],
+                [0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3],
+                [
+                    31, 31, 31, 30, 30, 30, 29, 29, 28, 28, 28, 27, 27, 27, 26, 26,
+                ],
+            ),
+            (
+                Dither::Bayer,
+                [
+                    [0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7],
+                    [0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8],
+                    [0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8],
+                    [0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8],
+                ],
+                [0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4],
+                [
+                    31, 31, 31, 31, 30, 30, 29, 29, 29, 29, 28, 28, 27, 27, 26, 26,
+                ],
+            ),
+        ];
+        for (dither, red, green, blue) in golden {
+            let mut d = Surface::<RGBA5551>::new(16, 4);
+            d.convert_from(&src, dither);
+            for (y, row) in red.iter().enumerate() {
+                let got: Vec<_> = d.row(y as u16).iter().map(|&c| channels(c)[0]).collect();
+                assert_eq!(got, row, "{dither:?} {y}");
+            }
+            let got: Vec<_> = d.row(1).iter().map(|&c| channels(c)).collect();
+            assert!(got.iter().all(|c| c[3] == 1), "{dither:?}");
+            assert_eq!(
+                got.iter().map(|c| c[1]).collect::<Vec<_>>(),
+                green,
+                "{dither:?}"
+            );
+            assert_eq!(
+                got.iter().map(|c| c[2]).collect::<Vec<_>>(),
+                blue,
+                "{dither:?}"
+            );
+        }
+        // A flat 68 lies a quarter of the way from 66 to 74, so about one in
+        // four pixels rounds up.
+        let mut flat = Surface::<RGBA8888>::new(64, 64);
+        flat.clear(RGBA8888::new(68, 68, 68, 255)).unwrap();
+        let mut d = Surface::<RGBA5551>::new(64, 64);
+        d.convert_from(&flat, Dither::FloydSteinberg);
+        let golden = [
+            [8, 8, 8, 8, 8, 8, 8, 8],
+            [8, 8, 9, 8, 8, 9, 8, 8],
+            [8, 8, 8, 8, 9, 8, 8, 8],
+            [8, 9, 8, 8, 8, 8, 9, 8],
+        ];
+        for (y, row) in golden.iter().enumerate() {
+            for (x, &r) in row.iter().enumerate() {
+                assert_eq!(channels(d.row(y as u16)[x]), [r, r, r, 1], "{x} {y}");
+            }
+        }
+        let up = (0..64)
+            .flat_map(|y| d.row(y).iter())
+            .filter(|&&c| channels(c) == [9, 9, 9, 1])
+            .count();
+        assert!((950..=1024).contains(&up), "{up}");
+    }
+
+    #[test]
+    fn quantize_width() {
+        let src = gradient(4, 2);
+        let flat: Vec<_> = (0..2).flat_map(|y| src.row(y).to_vec()).collect();
+        let mut out = vec![RGBA5551::default(); 8];
+        convert_slice(&flat, &mut out, 0, Dither::FloydSteinberg);
+        assert_eq!(out, [RGBA5551::default(); 8]);
+        // A narrower destination limits each row.
+        let mut q = Quantizer::new(4, Dither::FloydSteinberg);
+        q.row(&flat, &mut out[..4]);
+        assert_eq!(out[4..], [RGBA5551::default(); 4]);
+    }
+
+    #[test]
+    #[should_panic = "row of 8 pixels is wider than 4"]
+    fn quantize_wide_row() {
+        let src = [RGBA8888::WHITE; 8];
+        let mut dst = [RGBA5551::default(); 8];
+        Quantizer::new(4, Dither::FloydSteinberg).row(&src, &mut dst);
+    }
+
+    #[test]
+    fn clip_stack() {
+        let mut s = surface(6, 4);
+        assert_eq!(s.pop_clip(), None);
+        s.push_clip(rect(1, 0, 4, 4));
+        s.push_clip(rect(-1, 1, 4, 2));
+        assert_eq!(s.clip(), rect(1, 1, 2, 2));
+        assert_eq!(s.pop_clip(), Some(rect(1, 1, 2, 2)));
+        assert_eq!(s.clip(), rect(1, 0, 4, 4));
+        s.push_clip(rect(0, 3, 6, 6));
+        draw_all(&mut s);
+        assert_eq!(mask(&s), ["......", "......", "......", ".####."]);
+        s.reset_clip();
+        assert_eq!(s.clip(), s.bounding_box());
+        assert_eq!(s.pop_clip(), None);
     }
 }
@@ -1,11 +1,13 @@
 use core::{
     fmt::Write,
     ptr::NonNull,
-    sync::atomic::{AtomicBool, Ordering},
+    sync::atomic::{AtomicU8, Ordering},
 };
 
 use n64_pac::pi::PeripheralInterface;
 
+use crate::{pi, system};
+
 const ISV_REGS: NonNull<u32> = unsafe { NonNull::new_unchecked(0xB3FF0000 as *mut u32) };
 const ISV_BUFFER: NonNull<u32> = unsafe { NonNull::new_unchecked(0xB3FF0020 as *mut u32) };
 const ISV_TOKEN_REG: usize = 0;
@@ -14,36 +16,46 @@ const ISV_WRITE_REG: usize = 5;
 const ISV_BUFLEN: usize = 0x10000 - 0x20;
 const ISV_MAGIC: u32 = 0x49533634;
 
-#[inline(always)]
-fn pi_wait(pi: &PeripheralInterface) {
-    loop {
-        let status = unsafe { pi.status.read().read };
-        if !status.dma_busy() && !status.io_busy() {
-            break;
-        }
-    }
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+#[repr(u8)]
+pub enum Channel {
+    None = 0,
+    IsViewer = 1,
+    Screen = 2,
 }
 
-static ISV_ENABLED: AtomicBool = AtomicBool::new(false);
+static CHANNEL: AtomicU8 = AtomicU8::new(Channel::None as u8);
 
+#[inline]
+pub fn channel() -> Channel {
+    match CHANNEL.load(Ordering::Relaxed) {
+        1 => Channel::IsViewer,
+        2 => Channel::Screen,
+        _ => Channel::None,
+    }
+}
+
+// There is no cartridge bus to probe on the iQue, so its log is only kept in
+// the crash log ring and is meant to be drawn on screen instead.
 pub fn init() {
-    if crate::system::console_type() != 0 {
+    if system::is_ique() {
+        CHANNEL.store(Channel::Screen as u8, Ordering::Relaxed);
         return;
     }
     let isv = ISV_REGS;
     let pi = unsafe { PeripheralInterface::new() };
     unsafe {
         isv.add(ISV_TOKEN_REG).write_volatile(0);
-        pi_wait(&pi);
+        pi::wait(&pi);
         if isv.add(ISV_TOKEN_REG).read_volatile() == 0 {
             isv.add(ISV_READ_REG).write_volatile(0);
-            pi_wait(&pi);
+            pi::wait(&pi);
             isv.add(ISV_WRITE_REG).write_volatile(0);
-            pi_wait(&pi);
+            pi::wait(&pi);
             isv.add(ISV_TOKEN_REG).write_volatile(ISV_MAGIC);
-            pi_wait(&pi);
+            pi::wait(&pi);
             if isv.add(ISV_TOKEN_REG).read_volatile() == ISV_MAGIC {
-                ISV_ENABLED.store(true, Ordering::Relaxed);
+                CHANNEL.store(Channel::IsViewer as u8, Ordering::Relaxed);
             }
         }
     }
@@ -64,14 +76,20 @@ pub fn write(args: core::fmt::Arguments) {
     Writer.write_fmt(args).unwrap();
 }
 
+#[inline]
 pub fn put(s: &[u8]) {
-    if !ISV_ENABLED.load(Ordering::Relaxed) || s.is_empty() {
+    crate::crashlog::put(s);
+    emit(s);
+}
+
+pub(crate) fn emit(s: &[u8]) {
+    if channel() != Channel::IsViewer || s.is_empty() {
         return;
     }
-    crate::system::data_cache_hit_writeback(s);
+    system::data_cache_hit_writeback(s);
     let isv = ISV_REGS;
-    let pi = unsafe { n64_pac::pi::PeripheralInterface::new() };
-    pi_wait(&pi);
+    let pi = unsafe { PeripheralInterface::new() };
+    pi::wait(&pi);
     let read = s.as_ptr() as u32 & 7;
     for chunk in s.chunks(ISV_BUFLEN - 8) {
         unsafe {
@@ -81,18 +99,20 @@ pub fn put(s: &[u8]) {
         }
         let write = chunk.len() as u32 + read;
         let len = ((write + 1) & !1) - 1;
-        pi_wait(&pi);
-        pi.dram_addr.write(chunk.as_ptr() as u32 - read);
-        pi.cart_addr.write(crate::system::physical_addr(ISV_BUFFER));
-        pi.rd_len.write(len);
-        pi_wait(&pi);
+        pi::locked(|pi| {
+            pi::wait(pi);
+            pi.dram_addr.write(chunk.as_ptr() as u32 - read);
+            pi.cart_addr.write(system::physical_addr(ISV_BUFFER));
+            pi.rd_len.write(len);
+            pi::wait(pi);
+        });
         unsafe {
             isv.add(ISV_READ_REG).write_volatile(read);
-            pi_wait(&pi);
+            pi::wait(&pi);
             isv.add(ISV_WRITE_REG).write_volatile(write);
-            pi_wait(&pi);
+            pi::wait(&pi);
             isv.add(ISV_TOKEN_REG).write_volatile(ISV_MAGIC);
-            pi_wait(&pi);
+            pi::wait(&pi);
         }
     }
 }
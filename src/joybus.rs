@@ -0,0 +1,369 @@
+pub const PIF_RAM_SIZE: usize = 64;
+pub const PIF_CHANNELS: usize = 5;
+pub const CART_CHANNEL: usize = 4;
+
+pub const CMD_INFO: u8 = 0x00;
+pub const CMD_CONTROLLER_READ: u8 = 0x01;
+pub const CMD_PAK_READ: u8 = 0x02;
+pub const CMD_PAK_WRITE: u8 = 0x03;
+pub const CMD_EEPROM_READ: u8 = 0x04;
+pub const CMD_EEPROM_WRITE: u8 = 0x05;
+pub const CMD_RTC_STATUS: u8 = 0x06;
+pub const CMD_RTC_READ: u8 = 0x07;
+pub const CMD_RTC_WRITE: u8 = 0x08;
+pub const CMD_RESET: u8 = 0xFF;
+
+const BLOCK_SKIP: u8 = 0x00;
+const BLOCK_RESET: u8 = 0xFD;
+const BLOCK_END: u8 = 0xFE;
+const BLOCK_PAD: u8 = 0xFF;
+const CONTROL_RUN: u8 = 0x01;
+
+const RX_NO_DEVICE: u8 = 0x80;
+const RX_OVERRUN: u8 = 0x40;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum JoybusError {
+    NoDevice,
+    Overrun,
+    BlockFull,
+    BadResponse,
+}
+
+// Anything that can run a finished command block and fill in the responses,
+// so that device protocols can be driven by a simulated bus as well as the SI.
+pub trait Bus {
+    fn exchange(&mut self, block: &mut PifBlock);
+}
+
+impl<B: Bus + ?Sized> Bus for &mut B {
+    #[inline]
+    fn exchange(&mut self, block: &mut PifBlock) {
+        (**self).exchange(block)
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Slot {
+    channel: u8,
+    offset: u8,
+    rx_len: u8,
+}
+
+impl Slot {
+    #[inline]
+    pub const fn channel(&self) -> usize {
+        self.channel as usize
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+#[repr(C, align(16))]
+pub struct PifBlock {
+    data: [u8; PIF_RAM_SIZE],
+    pos: u8,
+    channel: u8,
+}
+
+impl Default for PifBlock {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PifBlock {
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            data: [0; PIF_RAM_SIZE],
+            pos: 0,
+            channel: 0,
+        }
+    }
+    #[inline]
+    pub const fn from_bytes(data: [u8; PIF_RAM_SIZE]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            channel: 0,
+        }
+    }
+    #[inline]
+    pub const fn as_bytes(&self) -> &[u8; PIF_RAM_SIZE] {
+        &self.data
+    }
+    #[inline]
+    pub const fn as_bytes_mut(&mut self) -> &mut [u8; PIF_RAM_SIZE] {
+        &mut self.data
+    }
+    #[inline]
+    pub const fn channel(&self) -> usize {
+        self.channel as usize
+    }
+    // The last byte of PIF RAM is the control byte and is never available.
+    #[inline]
+    const fn remaining(&self) -> usize {
+        PIF_RAM_SIZE - 1 - self.pos as usize
+    }
+    pub fn skip(&mut self) -> Result<(), JoybusError> {
+        if self.remaining() < 1 || self.channel as usize >= PIF_CHANNELS {
+            return Err(JoybusError::BlockFull);
+        }
+        self.data[self.pos as usize] = BLOCK_SKIP;
+        self.pos += 1;
+        self.channel += 1;
+        Ok(())
+    }
+    pub fn skip_to(&mut self, channel: usize) -> Result<(), JoybusError> {
+        while (self.channel as usize) < channel {
+            self.skip()?;
+        }
+        Ok(())
+    }
+    pub fn reset_channel(&mut self) -> Result<(), JoybusError> {
+        if self.remaining() < 1 || self.channel as usize >= PIF_CHANNELS {
+            return Err(JoybusError::BlockFull);
+        }
+        self.data[self.pos as usize] = BLOCK_RESET;
+        self.pos += 1;
+        self.channel += 1;
+        Ok(())
+    }
+    pub fn command(&mut self, tx: &[u8], rx_len: usize) -> Result<Slot, JoybusError> {
+        let len = 2 + tx.len() + rx_len;
+        if self.remaining() < len
+            || self.channel as usize >= PIF_CHANNELS
+            || tx.len() > 0x3F
+            || rx_len > 0x3F
+        {
+            return Err(JoybusError::BlockFull);
+        }
+        let pos = self.pos as usize;
+        self.data[pos] = tx.len() as u8;
+        self.data[pos + 1] = rx_len as u8;
+        self.data[pos + 2..pos + 2 + tx.len()].copy_from_slice(tx);
+        self.data[pos + 2 + tx.len()..pos + len].fill(BLOCK_PAD);
+        let slot = Slot {
+            channel: self.channel,
+            offset: pos as u8,
+            rx_len: rx_len as u8,
+        };
+        self.pos += len as u8;
+        self.channel += 1;
+        Ok(slot)
+    }
+    pub fn finish(&mut self) {
+        let pos = self.pos as usize;
+        if pos < PIF_RAM_SIZE - 1 {
+            self.data[pos] = BLOCK_END;
+            self.data[pos + 1..PIF_RAM_SIZE - 1].fill(0);
+        }
+        self.data[PIF_RAM_SIZE - 1] = CONTROL_RUN;
+    }
+    // Walks a block the way the PIF does, for simulated devices.
+    pub fn for_each_command(&mut self, mut f: impl FnMut(usize, &[u8], &mut [u8]) -> bool) {
+        let mut pos = 0;
+        let mut channel = 0;
+        while pos < PIF_RAM_SIZE - 1 && channel < PIF_CHANNELS {
+            match self.data[pos] {
+                BLOCK_END => break,
+                BLOCK_PAD => pos += 1,
+                BLOCK_SKIP | BLOCK_RESET => {
+                    pos += 1;
+                    channel += 1;
+                }
+                tx_len => {
+                    let tx_len = (tx_len & 0x3F) as usize;
+                    let rx_len = (self.data[pos + 1] & 0x3F) as usize;
+                    let start = pos + 2;
+                    if start + tx_len + rx_len > PIF_RAM_SIZE - 1 {
+                        break;
+                    }
+                    let (tx, rx) = self.data[start..start + tx_len + rx_len].split_at_mut(tx_len);
+                    if !f(channel, tx, rx) {
+                        self.data[pos + 1] |= RX_NO_DEVICE;
+                    }
+                    pos = start + tx_len + rx_len;
+                    channel += 1;
+                }
+            }
+        }
+    }
+    pub fn response(&self, slot: Slot) -> Result<&[u8], JoybusError> {
+        let pos = slot.offset as usize;
+        let tx_len = (self.data[pos] & 0x3F) as usize;
+        let rx = self.data[pos + 1];
+        if rx & RX_NO_DEVICE != 0 {
+            return Err(JoybusError::NoDevice);
+        }
+        if rx & RX_OVERRUN != 0 {
+            return Err(JoybusError::Overrun);
+        }
+        let start = pos + 2 + tx_len;
+        Ok(&self.data[start..start + slot.rx_len as usize])
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct DeviceInfo {
+    pub identifier: u16,
+    pub status: u8,
+}
+
+impl DeviceInfo {
+    pub const CONTROLLER: u16 = 0x0500;
+    pub const MOUSE: u16 = 0x0200;
+    pub const VOICE: u16 = 0x0001;
+    pub const EEPROM_4K: u16 = 0x0080;
+    pub const EEPROM_16K: u16 = 0x00C0;
+    pub const RTC: u16 = 0x1000;
+
+    pub const STATUS_PAK_PRESENT: u8 = 0x01;
+    pub const STATUS_PAK_CHANGED: u8 = 0x02;
+    pub const STATUS_ADDRESS_CRC_ERROR: u8 = 0x04;
+
+    #[inline]
+    pub const fn is_controller(&self) -> bool {
+        self.identifier == Self::CONTROLLER
+    }
+    #[inline]
+    pub const fn pak_present(&self) -> bool {
+        self.status & Self::STATUS_PAK_PRESENT != 0
+    }
+
+    pub fn parse(rx: &[u8]) -> Result<Self, JoybusError> {
+        let [hi, lo, status] = *rx else {
+            return Err(JoybusError::BadResponse);
+        };
+        Ok(Self {
+            identifier: u16::from_be_bytes([hi, lo]),
+            status,
+        })
+    }
+}
+
+pub fn info(block: &mut PifBlock) -> Result<Slot, JoybusError> {
+    block.command(&[CMD_INFO], 3)
+}
+
+pub fn reset(block: &mut PifBlock) -> Result<Slot, JoybusError> {
+    block.command(&[CMD_RESET], 3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_layout() {
+        let mut block = PifBlock::new();
+        info(&mut block).unwrap();
+        block.skip().unwrap();
+        block.command(&[CMD_PAK_READ, 0x80, 0x01], 33).unwrap();
+        block.finish();
+        let data = block.as_bytes();
+        assert_eq!(data[..6], [1, 3, CMD_INFO, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(data[6], BLOCK_SKIP);
+        assert_eq!(data[7..12], [3, 33, CMD_PAK_READ, 0x80, 0x01]);
+        assert!(data[12..45].iter().all(|&b| b == BLOCK_PAD));
+        assert_eq!(data[45], BLOCK_END);
+        assert!(data[46..63].iter().all(|&b| b == 0));
+        assert_eq!(data[63], CONTROL_RUN);
+    }
+
+    #[test]
+    fn skip_to_and_reset() {
+        let mut block = PifBlock::new();
+        block.skip_to(CART_CHANNEL).unwrap();
+        assert_eq!(block.channel(), CART_CHANNEL);
+        block.reset_channel().unwrap();
+        assert_eq!(block.as_bytes()[..5], [0, 0, 0, 0, BLOCK_RESET]);
+        assert_eq!(block.skip(), Err(JoybusError::BlockFull));
+        assert_eq!(reset(&mut block), Err(JoybusError::BlockFull));
+    }
+
+    #[test]
+    fn block_full() {
+        let mut block = PifBlock::new();
+        block.command(&[CMD_PAK_WRITE; 35], 1).unwrap();
+        assert_eq!(
+            block.command(&[CMD_PAK_WRITE; 35], 1),
+            Err(JoybusError::BlockFull)
+        );
+        // The last byte is the control byte, so 63 bytes fit exactly.
+        block.command(&[CMD_EEPROM_READ; 22], 1).unwrap();
+        assert_eq!(block.skip(), Err(JoybusError::BlockFull));
+        let mut block = PifBlock::new();
+        assert_eq!(block.command(&[], 0x40), Err(JoybusError::BlockFull));
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut block = PifBlock::new();
+        let slots: [Slot; 4] = core::array::from_fn(|_| info(&mut block).unwrap());
+        block.finish();
+        let mut seen = [false; PIF_CHANNELS];
+        block.for_each_command(|channel, tx, rx| {
+            assert_eq!(tx, [CMD_INFO]);
+            seen[channel] = true;
+            match channel {
+                0 | 2 => {
+                    rx.copy_from_slice(&[0x05, 0x00, channel as u8]);
+                    true
+                }
+                _ => false,
+            }
+        });
+        assert_eq!(seen, [true, true, true, true, false]);
+        assert_eq!(
+            block.response(slots[0]).and_then(DeviceInfo::parse),
+            Ok(DeviceInfo {
+                identifier: DeviceInfo::CONTROLLER,
+                status: 0,
+            })
+        );
+        assert_eq!(block.response(slots[1]), Err(JoybusError::NoDevice));
+        assert_eq!(block.response(slots[2]), Ok(&[0x05, 0x00, 0x02][..]));
+        assert_eq!(slots[3].channel(), 3);
+        assert_eq!(block.response(slots[3]), Err(JoybusError::NoDevice));
+    }
+
+    #[test]
+    fn round_trip_after_skip() {
+        let mut block = PifBlock::new();
+        block.skip_to(CART_CHANNEL).unwrap();
+        let slot = block.command(&[CMD_EEPROM_READ, 7], 8).unwrap();
+        block.finish();
+        let bytes = *block.as_bytes();
+        // A block read back from PIF RAM still decodes the same way.
+        let mut block = PifBlock::from_bytes(bytes);
+        block.for_each_command(|channel, tx, rx| {
+            assert_eq!(channel, CART_CHANNEL);
+            assert_eq!(tx, [CMD_EEPROM_READ, 7]);
+            rx.copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+            true
+        });
+        assert_eq!(block.response(slot), Ok(&[1, 2, 3, 4, 5, 6, 7, 8][..]));
+    }
+
+    #[test]
+    fn overrun() {
+        let mut block = PifBlock::new();
+        let slot = info(&mut block).unwrap();
+        block.finish();
+        block.as_bytes_mut()[1] |= RX_OVERRUN;
+        assert_eq!(block.response(slot), Err(JoybusError::Overrun));
+    }
+
+    #[test]
+    fn device_info() {
+        let info = DeviceInfo::parse(&[0x05, 0x00, 0x01]).unwrap();
+        assert!(info.is_controller());
+        assert!(info.pak_present());
+        assert_eq!(
+            DeviceInfo::parse(&[0x05, 0x00]),
+            Err(JoybusError::BadResponse)
+        );
+    }
+}
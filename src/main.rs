@@ -1,39 +1,156 @@
-#![no_std]
-#![no_main]
+// Host builds (`cargo test-host`) only compile the modules that don't touch
+// hardware, so that protocol and encoding logic can be unit tested.
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![feature(asm_experimental_arch)]
 
+#[cfg(not(test))]
 use n64_pac::vi::VideoInterface;
 
 extern crate alloc;
+#[cfg(not(test))]
 #[macro_use]
 pub mod isv;
+pub mod adpcm;
+#[cfg(not(test))]
+pub mod ai;
+pub mod controller;
+#[cfg(not(test))]
+pub mod crashlog;
+pub mod eeprom;
+#[cfg(not(test))]
+pub mod flashram;
 pub mod gfx;
+pub mod joybus;
+pub mod mempak;
+pub mod mixer;
+pub mod pak;
+#[cfg(not(test))]
+pub mod pi;
+pub mod rtc;
+pub mod save;
+#[cfg(not(test))]
+pub mod si;
+#[cfg(not(test))]
+pub mod sram;
+#[cfg(not(test))]
 pub mod system;
+pub mod tracker;
 
+#[cfg(not(test))]
 #[inline(never)]
 pub fn main() {
+    crashlog::init();
     isv::init();
+    crashlog::report();
+    crashlog::clear_crash();
     isv::put(b"Hello N64\n");
+    system::on_reset(|| {
+        let vi = unsafe { VideoInterface::new() };
+        vi.ctrl.write(n64_pac::vi::CtrlReg(0));
+    });
 
     let vi = unsafe { VideoInterface::new() };
     let mut fb = gfx::Surface::<gfx::RGBA5551>::framebuffer(320, 240);
+    #[cfg(feature = "bench")]
+    bench_clear(&mut fb);
     render(&mut fb);
     vid_setup_16bpp(&vi, &fb);
+    let mut pads = controller::Controllers::new();
+    let mut shown = None;
     loop {
         wait_vblank(&vi);
+        if isv::channel() == isv::Channel::Screen && shown != Some(crashlog::revision()) {
+            shown = Some(crashlog::revision());
+            draw_log(&mut fb);
+        }
+        pads.poll();
+        draw_start(&mut fb, pads.held(0).contains(controller::Buttons::START));
     }
 }
 
-fn render(fb: &mut gfx::Surface<gfx::RGBA5551>) {
-    use embedded_graphics::{mono_font::*, prelude::*, text::*};
+// Lights a square in the corner while START is held on the first pad.
+#[cfg(not(test))]
+fn draw_start(fb: &mut gfx::Surface<gfx::RGBA5551>, held: bool) {
+    use embedded_graphics::{prelude::*, primitives::Rectangle};
+
+    let color = if held {
+        gfx::RGBA5551::WHITE
+    } else {
+        gfx::RGBA5551::BLUE
+    };
+    let square = Rectangle::new(Point::new(fb.width() as i32 - 24, 12), Size::new(12, 12));
+    let _ = fb.fill_solid(&square, color);
+}
+
+// Count runs at half the CPU clock.
+#[cfg(all(not(test), feature = "bench"))]
+fn cycles(f: impl FnOnce()) -> u32 {
+    let start = system::c0_count();
+    f();
+    system::c0_count().wrapping_sub(start) * 2
+}
+
+#[cfg(all(not(test), feature = "bench"))]
+fn bench_clear(fb: &mut gfx::Surface<gfx::RGBA5551>) {
+    use embedded_graphics::prelude::*;
+
+    let area = fb.bounding_box();
+    let pixels = (area.size.width * area.size.height) as u64;
+    let draw = cycles(|| {
+        let pixels = area.points().map(|p| Pixel(p, gfx::RGBA5551::BLACK));
+        let _ = fb.draw_iter(pixels);
+    });
+    let clear = cycles(|| {
+        let _ = fb.clear(gfx::RGBA5551::BLACK);
+    });
+    // In hundredths, as the fast path takes well under a cycle per pixel.
+    let per_pixel = |cycles: u32| cycles as u64 * 100 / pixels;
+    let (draw, clear) = (per_pixel(draw), per_pixel(clear));
+    println!(
+        "clear: {}.{:02} cycles per pixel drawn, {}.{:02} for the fast path",
+        draw / 100,
+        draw % 100,
+        clear / 100,
+        clear % 100
+    );
+}
 
-    const STYLE: MonoTextStyle<'static, gfx::RGBA5551> =
-        MonoTextStyle::new(&profont::PROFONT_7_POINT, gfx::RGBA5551::WHITE);
+#[cfg(not(test))]
+const STYLE: embedded_graphics::mono_font::MonoTextStyle<'static, gfx::RGBA5551> =
+    embedded_graphics::mono_font::MonoTextStyle::new(
+        &profont::PROFONT_7_POINT,
+        <gfx::RGBA5551 as embedded_graphics::pixelcolor::RgbColor>::WHITE,
+    );
+
+#[cfg(not(test))]
+fn render(fb: &mut gfx::Surface<gfx::RGBA5551>) {
+    use embedded_graphics::{prelude::*, text::*};
 
     let _ = fb.clear(gfx::RGBA5551::BLUE);
     let _ = Text::new("Hello N64", Point::new(32, 32), STYLE).draw(fb);
 }
 
+// Redraws the log below the greeting, for consoles without an IS-Viewer.
+#[cfg(not(test))]
+fn draw_log(fb: &mut gfx::Surface<gfx::RGBA5551>) {
+    use embedded_graphics::{prelude::*, primitives::Rectangle, text::*};
+
+    let area = Rectangle::new(
+        Point::new(0, 40),
+        Size::new(fb.width() as u32, fb.height() as u32 - 40),
+    );
+    let _ = fb.fill_solid(&area, gfx::RGBA5551::BLUE);
+    let mut y = 48;
+    crashlog::for_each_line(|line| {
+        if let Ok(line) = core::str::from_utf8(line) {
+            let _ = Text::new(line, Point::new(8, y), STYLE).draw(fb);
+        }
+        y += 9;
+    });
+}
+
+#[cfg(not(test))]
 fn vid_setup_16bpp(vi: &VideoInterface, fb: &gfx::Surface<gfx::RGBA5551>) {
     use n64_pac::vi::{
         AntiAliasMode, BurstReg, ColorDepth, CtrlReg, HSyncLeapReg, HSyncReg, HVideoReg, VBurstReg,
@@ -52,11 +169,7 @@ fn vid_setup_16bpp(vi: &VideoInterface, fb: &gfx::Surface<gfx::RGBA5551>) {
         ],
     ];
 
-    let tv = match system::tv_type() {
-        tv @ 0..=2 => tv,
-        _ => 0,
-    };
-    let regs = &VI_REGS[tv as usize];
+    let regs = &VI_REGS[system::tv_type() as usize];
     let width = fb.width();
     let height = fb.height();
     vi.v_current.write(0);
@@ -77,12 +190,13 @@ fn vid_setup_16bpp(vi: &VideoInterface, fb: &gfx::Surface<gfx::RGBA5551>) {
         CtrlReg(0)
             .with_depth(ColorDepth::BPP16)
             .with_aa_mode(AntiAliasMode::ResamplingOnly)
-            .with_pixel_advance(if system::console_type() != 0 { 2 } else { 3 }),
+            .with_pixel_advance(system::vi_pixel_advance()),
     );
     wait_vblank(vi);
-    vi.width.write(width as u32);
+    vi.width.write(fb.stride() as u32);
 }
 
+#[cfg(not(test))]
 #[inline]
 fn wait_vblank(vi: &VideoInterface) {
     while (vi.v_current.read() & !1) != 2 {}
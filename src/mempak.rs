@@ -0,0 +1,775 @@
+use crate::pak::{BLOCK_SIZE, PakError, PakMemory};
+
+pub const MEMPAK_SIZE: usize = 0x8000;
+pub const PAGE_SIZE: usize = 0x100;
+pub const PAGES: usize = MEMPAK_SIZE / PAGE_SIZE;
+pub const MAX_NOTES: usize = 16;
+pub const NOTE_NAME_LEN: usize = 16;
+pub const NOTE_EXT_LEN: usize = 4;
+
+const ID_PAGE: usize = 0;
+const INODE_PAGE: usize = 1;
+const INODE_BACKUP_PAGE: usize = 2;
+const NOTE_PAGE: usize = 3;
+const FIRST_DATA_PAGE: usize = 5;
+pub const DATA_PAGES: usize = PAGES - FIRST_DATA_PAGE;
+
+const ID_BLOCKS: [usize; 4] = [0x20, 0x60, 0x80, 0xC0];
+const ID_CHECKSUM_BASE: u16 = 0xFFF2;
+
+const INODE_END: u8 = 0x01;
+const INODE_FREE: u8 = 0x03;
+
+const NOTE_SIZE: usize = 32;
+const NOTE_STATUS_VALID: u8 = 0x02;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum MempakError {
+    Pak(PakError),
+    Corrupt,
+    Full,
+    NoFreeNote,
+    NotFound,
+    Exists,
+    OutOfRange,
+}
+
+impl From<PakError> for MempakError {
+    #[inline]
+    fn from(value: PakError) -> Self {
+        Self::Pak(value)
+    }
+}
+
+// What mount had to fix up, so the caller can tell the player about it.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct Repairs {
+    pub id_block: bool,
+    pub inode_table: bool,
+    pub inode_backup: bool,
+    pub dropped_notes: u16,
+}
+
+impl Repairs {
+    #[inline]
+    pub const fn any(&self) -> bool {
+        self.id_block || self.inode_table || self.inode_backup || self.dropped_notes != 0
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct Note {
+    pub game_code: [u8; 4],
+    pub publisher: [u8; 2],
+    pub start_page: u8,
+    pub status: u8,
+    pub extension: [u8; NOTE_EXT_LEN],
+    pub name: [u8; NOTE_NAME_LEN],
+}
+
+impl Note {
+    pub const fn from_bytes(b: &[u8; NOTE_SIZE]) -> Self {
+        let mut name = [0; NOTE_NAME_LEN];
+        let mut i = 0;
+        while i < NOTE_NAME_LEN {
+            name[i] = b[0x10 + i];
+            i += 1;
+        }
+        Self {
+            game_code: [b[0], b[1], b[2], b[3]],
+            publisher: [b[4], b[5]],
+            start_page: b[7],
+            status: b[8],
+            extension: [b[0x0C], b[0x0D], b[0x0E], b[0x0F]],
+            name,
+        }
+    }
+    pub fn to_bytes(&self) -> [u8; NOTE_SIZE] {
+        let mut b = [0; NOTE_SIZE];
+        b[0..4].copy_from_slice(&self.game_code);
+        b[4..6].copy_from_slice(&self.publisher);
+        b[7] = self.start_page;
+        b[8] = self.status;
+        b[0x0C..0x10].copy_from_slice(&self.extension);
+        b[0x10..0x20].copy_from_slice(&self.name);
+        b
+    }
+    #[inline]
+    pub const fn is_used(&self) -> bool {
+        u32::from_be_bytes(self.game_code) != 0
+            && self.start_page as usize >= FIRST_DATA_PAGE
+            && (self.start_page as usize) < PAGES
+    }
+    pub fn name_matches(&self, name: &str, extension: &str) -> bool {
+        self.name == encode_name(name) && self.extension == encode_name(extension)
+    }
+}
+
+const CHARSET: &[u8; 0x42] =
+    b"\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0 0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ!\"#'*+,-./:=?@";
+
+#[inline]
+pub const fn encode_char(c: u8) -> u8 {
+    let c = c.to_ascii_uppercase();
+    let mut i = 0x0F;
+    while i < CHARSET.len() {
+        if CHARSET[i] == c {
+            return i as u8;
+        }
+        i += 1;
+    }
+    0x0F
+}
+
+#[inline]
+pub const fn decode_char(c: u8) -> u8 {
+    match c {
+        0 => 0,
+        c if (c as usize) < CHARSET.len() && c >= 0x0F => CHARSET[c as usize],
+        _ => b'?',
+    }
+}
+
+pub fn encode_name<const N: usize>(s: &str) -> [u8; N] {
+    let mut out = [0; N];
+    for (o, c) in out.iter_mut().zip(s.bytes()) {
+        *o = encode_char(c);
+    }
+    out
+}
+
+pub fn decode_name<const N: usize>(s: &[u8; N]) -> ([u8; N], usize) {
+    let mut out = [0; N];
+    let mut len = 0;
+    for (o, &c) in out.iter_mut().zip(s) {
+        if c == 0 {
+            break;
+        }
+        *o = decode_char(c);
+        len += 1;
+    }
+    (out, len)
+}
+
+fn read_page(
+    dev: &mut impl PakMemory,
+    page: usize,
+    buf: &mut [u8; PAGE_SIZE],
+) -> Result<(), PakError> {
+    for (i, chunk) in buf.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+        let chunk: &mut [u8; BLOCK_SIZE] = chunk.try_into().unwrap();
+        dev.read_block((page * PAGE_SIZE + i * BLOCK_SIZE) as u16, chunk)?;
+    }
+    Ok(())
+}
+
+fn write_page(
+    dev: &mut impl PakMemory,
+    page: usize,
+    buf: &[u8; PAGE_SIZE],
+) -> Result<(), PakError> {
+    for (i, chunk) in buf.chunks_exact(BLOCK_SIZE).enumerate() {
+        let chunk: &[u8; BLOCK_SIZE] = chunk.try_into().unwrap();
+        dev.write_block((page * PAGE_SIZE + i * BLOCK_SIZE) as u16, chunk)?;
+    }
+    Ok(())
+}
+
+fn id_checksum(id: &[u8]) -> u16 {
+    id[..0x1C].chunks_exact(2).fold(0u16, |sum, w| {
+        sum.wrapping_add(u16::from_be_bytes([w[0], w[1]]))
+    })
+}
+
+fn id_valid(id: &[u8]) -> bool {
+    let sum = id_checksum(id);
+    u16::from_be_bytes([id[0x1C], id[0x1D]]) == sum
+        && u16::from_be_bytes([id[0x1E], id[0x1F]]) == ID_CHECKSUM_BASE.wrapping_sub(sum)
+}
+
+fn id_block(serial: &[u8; 24]) -> [u8; 32] {
+    let mut id = [0; 32];
+    id[..24].copy_from_slice(serial);
+    id[0x19] = 0x01;
+    id[0x1A] = 0x01;
+    let sum = id_checksum(&id);
+    id[0x1C..0x1E].copy_from_slice(&sum.to_be_bytes());
+    id[0x1E..0x20].copy_from_slice(&ID_CHECKSUM_BASE.wrapping_sub(sum).to_be_bytes());
+    id
+}
+
+fn inode_checksum(inodes: &[u8; PAGE_SIZE]) -> u8 {
+    (FIRST_DATA_PAGE..PAGES).fold(0u8, |sum, i| sum.wrapping_add(inodes[i * 2 + 1]))
+}
+
+fn inodes_valid(inodes: &[u8; PAGE_SIZE]) -> bool {
+    inodes[1] == inode_checksum(inodes)
+        && (FIRST_DATA_PAGE..PAGES).all(|i| {
+            let next = inodes[i * 2 + 1];
+            inodes[i * 2] == 0
+                && (next == INODE_END
+                    || next == INODE_FREE
+                    || (FIRST_DATA_PAGE..PAGES).contains(&(next as usize)))
+        })
+}
+
+pub struct Mempak<D> {
+    dev: D,
+    inodes: [u8; PAGE_SIZE],
+    notes: [Note; MAX_NOTES],
+}
+
+impl<D: PakMemory> Mempak<D> {
+    pub fn format(mut dev: D, serial: &[u8; 24]) -> Result<Self, MempakError> {
+        let mut page = [0; PAGE_SIZE];
+        let id = id_block(serial);
+        for &offset in &ID_BLOCKS {
+            page[offset..offset + 32].copy_from_slice(&id);
+        }
+        write_page(&mut dev, ID_PAGE, &page)?;
+        let mut inodes = [0; PAGE_SIZE];
+        for i in FIRST_DATA_PAGE..PAGES {
+            inodes[i * 2 + 1] = INODE_FREE;
+        }
+        let mut fs = Self {
+            dev,
+            inodes,
+            notes: [Note::default(); MAX_NOTES],
+        };
+        fs.flush_inodes()?;
+        for i in 0..MAX_NOTES {
+            fs.flush_note(i)?;
+        }
+        Ok(fs)
+    }
+
+    pub fn mount(mut dev: D) -> Result<(Self, Repairs), MempakError> {
+        let mut repairs = Repairs::default();
+        let mut page = [0; PAGE_SIZE];
+        read_page(&mut dev, ID_PAGE, &mut page)?;
+        let Some(good) = ID_BLOCKS.iter().position(|&o| id_valid(&page[o..o + 32])) else {
+            return Err(MempakError::Corrupt);
+        };
+        if good != 0 {
+            let (src, dst) = (ID_BLOCKS[good], ID_BLOCKS[0]);
+            page.copy_within(src..src + 32, dst);
+            write_page(&mut dev, ID_PAGE, &page)?;
+            repairs.id_block = true;
+        }
+
+        let mut inodes = [0; PAGE_SIZE];
+        let mut backup = [0; PAGE_SIZE];
+        read_page(&mut dev, INODE_PAGE, &mut inodes)?;
+        read_page(&mut dev, INODE_BACKUP_PAGE, &mut backup)?;
+        match (inodes_valid(&inodes), inodes_valid(&backup)) {
+            (true, _) if inodes == backup => {}
+            (true, _) => repairs.inode_backup = true,
+            (false, true) => {
+                inodes = backup;
+                repairs.inode_table = true;
+            }
+            (false, false) => return Err(MempakError::Corrupt),
+        }
+
+        let mut notes = [Note::default(); MAX_NOTES];
+        for half in 0..2 {
+            read_page(&mut dev, NOTE_PAGE + half, &mut page)?;
+            for (i, raw) in page.chunks_exact(NOTE_SIZE).enumerate() {
+                notes[half * 8 + i] = Note::from_bytes(raw.try_into().unwrap());
+            }
+        }
+
+        let mut fs = Self { dev, inodes, notes };
+        fs.check_notes(&mut repairs)?;
+        if repairs.inode_table || repairs.inode_backup || repairs.dropped_notes != 0 {
+            fs.flush_inodes()?;
+        }
+        Ok((fs, repairs))
+    }
+
+    // Drops notes whose page chains are broken or shared with another note,
+    // and frees pages that no note owns.
+    fn check_notes(&mut self, repairs: &mut Repairs) -> Result<(), MempakError> {
+        let mut owner = [u8::MAX; PAGES];
+        for i in 0..MAX_NOTES {
+            if !self.notes[i].is_used() {
+                continue;
+            }
+            let mut ok = true;
+            let mut page = self.notes[i].start_page as usize;
+            let mut count = 0;
+            loop {
+                if !(FIRST_DATA_PAGE..PAGES).contains(&page)
+                    || owner[page] != u8::MAX
+                    || count >= DATA_PAGES
+                {
+                    ok = false;
+                    break;
+                }
+                owner[page] = i as u8;
+                count += 1;
+                match self.inodes[page * 2 + 1] {
+                    INODE_END => break,
+                    next => page = next as usize,
+                }
+            }
+            if !ok {
+                owner
+                    .iter_mut()
+                    .filter(|o| **o == i as u8)
+                    .for_each(|o| *o = u8::MAX);
+                self.notes[i] = Note::default();
+                self.flush_note(i)?;
+                repairs.dropped_notes += 1;
+            }
+        }
+        for (page, &owner) in owner.iter().enumerate().skip(FIRST_DATA_PAGE) {
+            if owner == u8::MAX && self.inodes[page * 2 + 1] != INODE_FREE {
+                self.inodes[page * 2 + 1] = INODE_FREE;
+                repairs.inode_table = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush_inodes(&mut self) -> Result<(), MempakError> {
+        self.inodes[0] = 0;
+        self.inodes[1] = inode_checksum(&self.inodes);
+        write_page(&mut self.dev, INODE_PAGE, &self.inodes)?;
+        write_page(&mut self.dev, INODE_BACKUP_PAGE, &self.inodes)?;
+        Ok(())
+    }
+
+    fn flush_note(&mut self, index: usize) -> Result<(), MempakError> {
+        let raw = self.notes[index].to_bytes();
+        let addr = NOTE_PAGE * PAGE_SIZE + index * NOTE_SIZE;
+        self.dev.write_block(addr as u16, &raw)?;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> D {
+        self.dev
+    }
+
+    pub fn notes(&self) -> impl Iterator<Item = (usize, &Note)> {
+        self.notes.iter().enumerate().filter(|(_, n)| n.is_used())
+    }
+
+    #[inline]
+    pub fn note(&self, index: usize) -> Option<&Note> {
+        self.notes.get(index).filter(|n| n.is_used())
+    }
+
+    pub fn free_pages(&self) -> usize {
+        (FIRST_DATA_PAGE..PAGES)
+            .filter(|&i| self.inodes[i * 2 + 1] == INODE_FREE)
+            .count()
+    }
+
+    pub fn note_pages(&self, index: usize) -> usize {
+        self.chain(index).count()
+    }
+
+    #[inline]
+    pub fn note_size(&self, index: usize) -> usize {
+        self.note_pages(index) * PAGE_SIZE
+    }
+
+    fn chain(&self, index: usize) -> impl Iterator<Item = usize> + '_ {
+        let start = self.note(index).map(|n| n.start_page as usize);
+        core::iter::successors(start, move |&page| match self.inodes[page * 2 + 1] {
+            INODE_END => None,
+            next => Some(next as usize),
+        })
+        .take(DATA_PAGES)
+    }
+
+    pub fn find(
+        &self,
+        game_code: [u8; 4],
+        publisher: [u8; 2],
+        name: &str,
+        extension: &str,
+    ) -> Option<usize> {
+        self.notes()
+            .find(|(_, n)| {
+                n.game_code == game_code
+                    && n.publisher == publisher
+                    && n.name_matches(name, extension)
+            })
+            .map(|(i, _)| i)
+    }
+
+    pub fn create(
+        &mut self,
+        game_code: [u8; 4],
+        publisher: [u8; 2],
+        name: &str,
+        extension: &str,
+        size: usize,
+    ) -> Result<usize, MempakError> {
+        if self.find(game_code, publisher, name, extension).is_some() {
+            return Err(MempakError::Exists);
+        }
+        let pages = size.div_ceil(PAGE_SIZE).max(1);
+        if pages > self.free_pages() {
+            return Err(MempakError::Full);
+        }
+        let Some(index) = self.notes.iter().position(|n| !n.is_used()) else {
+            return Err(MempakError::NoFreeNote);
+        };
+        let mut free = (FIRST_DATA_PAGE..PAGES).filter(|&i| self.inodes[i * 2 + 1] == INODE_FREE);
+        let mut chain = [0u8; DATA_PAGES];
+        for slot in chain.iter_mut().take(pages) {
+            *slot = free.next().unwrap() as u8;
+        }
+        for w in chain[..pages].windows(2) {
+            self.inodes[w[0] as usize * 2 + 1] = w[1];
+        }
+        self.inodes[chain[pages - 1] as usize * 2 + 1] = INODE_END;
+        self.notes[index] = Note {
+            game_code,
+            publisher,
+            start_page: chain[0],
+            status: NOTE_STATUS_VALID,
+            extension: encode_name(extension),
+            name: encode_name(name),
+        };
+        self.flush_inodes()?;
+        self.flush_note(index)?;
+        Ok(index)
+    }
+
+    #[cfg(not(test))]
+    pub fn create_for_rom(
+        &mut self,
+        header: &crate::system::RomHeader,
+        name: &str,
+        extension: &str,
+        size: usize,
+    ) -> Result<usize, MempakError> {
+        self.create(header.game_code, header.publisher, name, extension, size)
+    }
+
+    pub fn delete(&mut self, index: usize) -> Result<(), MempakError> {
+        if self.note(index).is_none() {
+            return Err(MempakError::NotFound);
+        }
+        let mut pages = [0u8; DATA_PAGES];
+        let mut count = 0;
+        for page in self.chain(index) {
+            pages[count] = page as u8;
+            count += 1;
+        }
+        for &page in &pages[..count] {
+            self.inodes[page as usize * 2 + 1] = INODE_FREE;
+        }
+        self.notes[index] = Note::default();
+        self.flush_inodes()?;
+        self.flush_note(index)?;
+        Ok(())
+    }
+
+    // Calls `f` with the pak address and the range of the note's data each
+    // block of a read or write maps to.
+    fn for_each_block(
+        &mut self,
+        index: usize,
+        offset: usize,
+        len: usize,
+        mut f: impl FnMut(
+            &mut D,
+            u16,
+            core::ops::Range<usize>,
+            core::ops::Range<usize>,
+        ) -> Result<(), PakError>,
+    ) -> Result<(), MempakError> {
+        if self.note(index).is_none() {
+            return Err(MempakError::NotFound);
+        }
+        if offset + len > self.note_size(index) {
+            return Err(MempakError::OutOfRange);
+        }
+        let mut pages = [0u8; DATA_PAGES];
+        for (slot, page) in pages.iter_mut().zip(self.chain(index)) {
+            *slot = page as u8;
+        }
+        let mut pos = offset;
+        while pos < offset + len {
+            let page = pages[pos / PAGE_SIZE] as usize;
+            let in_page = pos % PAGE_SIZE;
+            let block_start = in_page & !(BLOCK_SIZE - 1);
+            let in_block = in_page - block_start;
+            let n = (BLOCK_SIZE - in_block).min(offset + len - pos);
+            let addr = (page * PAGE_SIZE + block_start) as u16;
+            f(
+                &mut self.dev,
+                addr,
+                in_block..in_block + n,
+                pos - offset..pos - offset + n,
+            )?;
+            pos += n;
+        }
+        Ok(())
+    }
+
+    pub fn read(&mut self, index: usize, offset: usize, buf: &mut [u8]) -> Result<(), MempakError> {
+        self.for_each_block(index, offset, buf.len(), |dev, addr, block, out| {
+            let mut data = [0; BLOCK_SIZE];
+            dev.read_block(addr, &mut data)?;
+            buf[out].copy_from_slice(&data[block]);
+            Ok(())
+        })
+    }
+
+    pub fn write(&mut self, index: usize, offset: usize, buf: &[u8]) -> Result<(), MempakError> {
+        self.for_each_block(index, offset, buf.len(), |dev, addr, block, src| {
+            let mut data = [0; BLOCK_SIZE];
+            if block.len() != BLOCK_SIZE {
+                dev.read_block(addr, &mut data)?;
+            }
+            data[block].copy_from_slice(&buf[src]);
+            dev.write_block(addr, &data)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{vec, vec::Vec};
+
+    const GAME: [u8; 4] = *b"NTEA";
+    const PUBLISHER: [u8; 2] = *b"01";
+
+    fn formatted() -> Vec<u8> {
+        let mut image = vec![0xA5; MEMPAK_SIZE];
+        Mempak::format(&mut image[..], &[0x42; 24]).unwrap();
+        image
+    }
+
+    fn mount(image: &mut [u8]) -> Result<(Mempak<&mut [u8]>, Repairs), MempakError> {
+        Mempak::mount(image)
+    }
+
+    // Rewrites both inode tables with a valid checksum, the way a buggy
+    // writer would leave them.
+    fn set_next(image: &mut [u8], page: usize, next: u8) {
+        for table in [INODE_PAGE, INODE_BACKUP_PAGE] {
+            let inodes: &mut [u8; PAGE_SIZE] = (&mut image
+                [table * PAGE_SIZE..(table + 1) * PAGE_SIZE])
+                .try_into()
+                .unwrap();
+            inodes[page * 2 + 1] = next;
+            inodes[1] = inode_checksum(inodes);
+        }
+    }
+
+    fn chain(fs: &Mempak<&mut [u8]>, index: usize) -> Vec<usize> {
+        fs.chain(index).collect()
+    }
+
+    #[test]
+    fn format() {
+        let mut image = formatted();
+        for &offset in &ID_BLOCKS {
+            assert!(id_valid(&image[offset..offset + 32]));
+            assert_eq!(image[offset..offset + 24], [0x42; 24]);
+        }
+        assert_eq!(
+            image[INODE_PAGE * PAGE_SIZE..(INODE_PAGE + 1) * PAGE_SIZE],
+            image[INODE_BACKUP_PAGE * PAGE_SIZE..(INODE_BACKUP_PAGE + 1) * PAGE_SIZE]
+        );
+        assert!(
+            image[NOTE_PAGE * PAGE_SIZE..FIRST_DATA_PAGE * PAGE_SIZE]
+                .iter()
+                .all(|&b| b == 0)
+        );
+        let (fs, repairs) = mount(&mut image).unwrap();
+        assert_eq!(repairs, Repairs::default());
+        assert_eq!(fs.free_pages(), DATA_PAGES);
+        assert_eq!(fs.notes().count(), 0);
+    }
+
+    #[test]
+    fn unformatted() {
+        let mut image = vec![0; MEMPAK_SIZE];
+        assert_eq!(mount(&mut image).err(), Some(MempakError::Corrupt));
+    }
+
+    #[test]
+    fn create_and_delete() {
+        let mut image = formatted();
+        let (mut fs, _) = mount(&mut image).unwrap();
+        let a = fs.create(GAME, PUBLISHER, "SAVE", "A", 1000).unwrap();
+        let b = fs.create(GAME, PUBLISHER, "SAVE", "B", 1).unwrap();
+        assert_eq!((a, b), (0, 1));
+        assert_eq!(fs.note_pages(a), 4);
+        assert_eq!(fs.note_size(b), PAGE_SIZE);
+        assert_eq!(fs.free_pages(), DATA_PAGES - 5);
+        assert_eq!(chain(&fs, a), [5, 6, 7, 8]);
+        assert_eq!(chain(&fs, b), [9]);
+        assert_eq!(
+            fs.create(GAME, PUBLISHER, "save", "a", 1),
+            Err(MempakError::Exists)
+        );
+        assert_eq!(fs.find(GAME, PUBLISHER, "SAVE", "B"), Some(b));
+        assert_eq!(fs.find(GAME, *b"02", "SAVE", "B"), None);
+
+        let data: Vec<u8> = (0..700).map(|i| (i * 7) as u8).collect();
+        fs.write(a, 100, &data).unwrap();
+        let mut out = vec![0; 700];
+        fs.read(a, 100, &mut out).unwrap();
+        assert_eq!(out, data);
+        assert_eq!(
+            fs.write(a, 4 * PAGE_SIZE - 1, &[0, 0]),
+            Err(MempakError::OutOfRange)
+        );
+
+        fs.delete(a).unwrap();
+        assert_eq!(fs.delete(a), Err(MempakError::NotFound));
+        assert_eq!(fs.read(a, 0, &mut out), Err(MempakError::NotFound));
+        assert_eq!(fs.free_pages(), DATA_PAGES - 1);
+        // Freed pages are reused first.
+        let c = fs
+            .create(GAME, PUBLISHER, "SAVE", "C", 2 * PAGE_SIZE)
+            .unwrap();
+        assert_eq!(c, a);
+        assert_eq!(chain(&fs, c), [5, 6]);
+
+        let (fs, repairs) = mount(&mut image).unwrap();
+        assert_eq!(repairs, Repairs::default());
+        assert_eq!(fs.notes().map(|(i, _)| i).collect::<Vec<_>>(), [0, 1]);
+        assert_eq!(decode_name(&fs.note(c).unwrap().name).1, 4);
+        assert_eq!(fs.note(c).unwrap().extension, encode_name("C"));
+    }
+
+    #[test]
+    fn full() {
+        let mut image = formatted();
+        let (mut fs, _) = mount(&mut image).unwrap();
+        assert_eq!(
+            fs.create(GAME, PUBLISHER, "BIG", "", (DATA_PAGES + 1) * PAGE_SIZE),
+            Err(MempakError::Full)
+        );
+        let names = ["A", "B", "C", "D", "E", "F", "G", "H"];
+        for (i, ext) in names.iter().enumerate() {
+            fs.create(GAME, PUBLISHER, "ONE", ext, 1).unwrap();
+            fs.create(GAME, PUBLISHER, "TWO", ext, 1).unwrap();
+            assert_eq!(fs.free_pages(), DATA_PAGES - 2 * (i + 1));
+        }
+        assert_eq!(
+            fs.create(GAME, PUBLISHER, "SIXTEEN", "", 1),
+            Err(MempakError::NoFreeNote)
+        );
+    }
+
+    #[test]
+    fn id_block_repair() {
+        let mut image = formatted();
+        image[ID_BLOCKS[0] + 3] ^= 0xFF;
+        let (_, repairs) = mount(&mut image).unwrap();
+        assert!(repairs.id_block);
+        assert!(id_valid(&image[ID_BLOCKS[0]..ID_BLOCKS[0] + 32]));
+        assert_eq!(mount(&mut image).unwrap().1, Repairs::default());
+
+        for &offset in &ID_BLOCKS {
+            image[offset + 0x1C] ^= 1;
+        }
+        assert_eq!(mount(&mut image).err(), Some(MempakError::Corrupt));
+    }
+
+    #[test]
+    fn inode_table_repair() {
+        let mut image = formatted();
+        {
+            let (mut fs, _) = mount(&mut image).unwrap();
+            fs.create(GAME, PUBLISHER, "SAVE", "", 3 * PAGE_SIZE)
+                .unwrap();
+        }
+        let table = INODE_PAGE * PAGE_SIZE;
+        let backup = INODE_BACKUP_PAGE * PAGE_SIZE;
+
+        image[table + 1] ^= 0x40;
+        let (fs, repairs) = mount(&mut image).unwrap();
+        assert!(repairs.inode_table);
+        assert_eq!(chain(&fs, 0), [5, 6, 7]);
+        assert_eq!(
+            image[table..table + PAGE_SIZE],
+            image[backup..backup + PAGE_SIZE]
+        );
+
+        image[backup + 7 * 2 + 1] = 0x02;
+        let (_, repairs) = mount(&mut image).unwrap();
+        assert!(repairs.inode_backup && !repairs.inode_table);
+        assert_eq!(mount(&mut image).unwrap().1, Repairs::default());
+
+        image[table + 1] ^= 0x40;
+        image[backup + 1] ^= 0x40;
+        assert_eq!(mount(&mut image).err(), Some(MempakError::Corrupt));
+    }
+
+    #[test]
+    fn chain_loop() {
+        let mut image = formatted();
+        {
+            let (mut fs, _) = mount(&mut image).unwrap();
+            fs.create(GAME, PUBLISHER, "LOOP", "", 3 * PAGE_SIZE)
+                .unwrap();
+            fs.create(GAME, PUBLISHER, "GOOD", "", PAGE_SIZE).unwrap();
+        }
+        set_next(&mut image, 7, 5);
+        let (fs, repairs) = mount(&mut image).unwrap();
+        assert_eq!(repairs.dropped_notes, 1);
+        assert!(repairs.inode_table);
+        assert!(fs.note(0).is_none());
+        assert_eq!(chain(&fs, 1), [8]);
+        assert_eq!(fs.free_pages(), DATA_PAGES - 1);
+        assert_eq!(mount(&mut image).unwrap().1, Repairs::default());
+    }
+
+    #[test]
+    fn chain_cross_linked() {
+        let mut image = formatted();
+        {
+            let (mut fs, _) = mount(&mut image).unwrap();
+            fs.create(GAME, PUBLISHER, "FIRST", "", 2 * PAGE_SIZE)
+                .unwrap();
+            fs.create(GAME, PUBLISHER, "SECOND", "", 2 * PAGE_SIZE)
+                .unwrap();
+        }
+        // The second note's tail now runs into the first note's pages.
+        set_next(&mut image, 8, 6);
+        let (fs, repairs) = mount(&mut image).unwrap();
+        assert_eq!(repairs.dropped_notes, 1);
+        assert_eq!(chain(&fs, 0), [5, 6]);
+        assert!(fs.note(1).is_none());
+        assert_eq!(fs.free_pages(), DATA_PAGES - 2);
+    }
+
+    #[test]
+    fn chain_out_of_range() {
+        let mut image = formatted();
+        {
+            let (mut fs, _) = mount(&mut image).unwrap();
+            fs.create(GAME, PUBLISHER, "SAVE", "", 2 * PAGE_SIZE)
+                .unwrap();
+        }
+        // Page numbers below the first data page are rejected with the table.
+        set_next(&mut image, 5, 4);
+        assert_eq!(mount(&mut image).err(), Some(MempakError::Corrupt));
+    }
+
+    #[test]
+    fn orphan_pages() {
+        let mut image = formatted();
+        set_next(&mut image, 40, INODE_END);
+        set_next(&mut image, 41, 42);
+        set_next(&mut image, 42, INODE_END);
+        let (fs, repairs) = mount(&mut image).unwrap();
+        assert!(repairs.inode_table);
+        assert_eq!(repairs.dropped_notes, 0);
+        assert_eq!(fs.free_pages(), DATA_PAGES);
+    }
+}
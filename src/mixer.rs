@@ -0,0 +1,605 @@
+use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
+
+pub const FRAC_BITS: u32 = 16;
+pub const PITCH_ONE: u32 = 1 << FRAC_BITS;
+pub const VOLUME_MAX: u16 = 0x8000;
+pub const PAN_CENTER: u8 = 128;
+const STREAM_BUFFER: usize = 256;
+const LEVEL_ONE: u32 = 1 << 16;
+
+// Sample data is shared, so the same sound can play on several voices and
+// be owned by whatever loaded it.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Sample {
+    pub data: Rc<[i16]>,
+    pub rate: u32,
+    pub loop_start: Option<usize>,
+}
+
+// Envelope times are in output frames, the sustain level is out of
+// VOLUME_MAX. A time of 0 moves through that stage instantly.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Adsr {
+    pub attack: u32,
+    pub decay: u32,
+    pub sustain: u16,
+    pub release: u32,
+}
+
+impl Adsr {
+    pub const NONE: Self = Self {
+        attack: 0,
+        decay: 0,
+        sustain: VOLUME_MAX,
+        release: 0,
+    };
+}
+
+impl Default for Adsr {
+    #[inline]
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Stage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Off,
+}
+
+#[inline]
+const fn step(time: u32, range: u32) -> u32 {
+    if time == 0 {
+        range
+    } else {
+        range.div_ceil(time)
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+struct Envelope {
+    stage: Stage,
+    level: u32,
+}
+
+impl Envelope {
+    const OFF: Self = Self {
+        stage: Stage::Off,
+        level: 0,
+    };
+
+    // Returns the level for the next frame out of LEVEL_ONE.
+    fn next(&mut self, adsr: &Adsr) -> u32 {
+        let sustain = (adsr.sustain.min(VOLUME_MAX) as u32) << 1;
+        match self.stage {
+            Stage::Attack => {
+                self.level += step(adsr.attack, LEVEL_ONE);
+                if self.level >= LEVEL_ONE {
+                    self.level = LEVEL_ONE;
+                    self.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                self.level = self
+                    .level
+                    .saturating_sub(step(adsr.decay, LEVEL_ONE - sustain));
+                if self.level <= sustain {
+                    self.level = sustain;
+                    self.stage = Stage::Sustain;
+                }
+            }
+            Stage::Sustain => self.level = sustain,
+            Stage::Release => {
+                self.level = self.level.saturating_sub(step(adsr.release, LEVEL_ONE));
+                if self.level == 0 {
+                    self.stage = Stage::Off;
+                }
+            }
+            Stage::Off => self.level = 0,
+        }
+        self.level
+    }
+}
+
+// Mono PCM pulled in pieces, for audio that is decoded as it plays. `read`
+// returns how many samples it wrote and 0 once the stream has ended.
+pub trait Source {
+    fn rate(&self) -> u32;
+    fn read(&mut self, out: &mut [i16]) -> usize;
+}
+
+enum Input {
+    Sample(Sample),
+    Stream {
+        source: Box<dyn Source>,
+        buf: Vec<i16>,
+        len: usize,
+    },
+}
+
+impl Input {
+    // Linear interpolation towards the next frame, wrapping into the loop
+    // when there is one. Returns None once the input has ended.
+    fn next(&mut self, pos: &mut u64, pitch: u32) -> Option<i32> {
+        let (data, loop_start) = match self {
+            Self::Sample(sample) => (&*sample.data, sample.loop_start),
+            Self::Stream { source, buf, len } => {
+                refill(&mut **source, buf, len, pos);
+                (&buf[..*len], None)
+            }
+        };
+        let len = data.len();
+        let mut index = (*pos >> FRAC_BITS) as usize;
+        if index >= len {
+            let start = loop_start.filter(|&start| start < len)?;
+            let span = ((len - start) as u64) << FRAC_BITS;
+            *pos = ((start as u64) << FRAC_BITS) + (*pos - ((len as u64) << FRAC_BITS)) % span;
+            index = (*pos >> FRAC_BITS) as usize;
+        }
+        let next = match index + 1 {
+            next if next < len => next,
+            _ => loop_start.filter(|&start| start < len).unwrap_or(index),
+        };
+        let frac = ((*pos as u32) & (PITCH_ONE - 1)) as i32 >> 1;
+        let s0 = data[index] as i32;
+        let s1 = data[next] as i32;
+        *pos += pitch as u64;
+        Some(s0 + (((s1 - s0) * frac) >> 15))
+    }
+}
+
+// Keeps the current and next frame of a stream in the buffer, moving what is
+// left to the front and reading more behind it when the position runs out.
+fn refill(source: &mut dyn Source, buf: &mut [i16], len: &mut usize, pos: &mut u64) {
+    while (*pos >> FRAC_BITS) as usize + 1 >= *len {
+        let index = (*pos >> FRAC_BITS) as usize;
+        let keep = len.saturating_sub(index);
+        buf.copy_within(*len - keep..*len, 0);
+        *pos -= ((*len - keep) as u64) << FRAC_BITS;
+        *len = keep;
+        let start = *len;
+        while *len < buf.len() {
+            let n = source.read(&mut buf[*len..]);
+            if n == 0 {
+                break;
+            }
+            *len += n;
+        }
+        if *len == start {
+            return;
+        }
+    }
+}
+
+pub struct Voice {
+    input: Option<Input>,
+    pos: u64,
+    pitch: u32,
+    volume: u16,
+    pan: u8,
+    adsr: Adsr,
+    envelope: Envelope,
+}
+
+impl Default for Voice {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Voice {
+    #[inline]
+    pub const fn new() -> Self {
+        Self {
+            input: None,
+            pos: 0,
+            pitch: PITCH_ONE,
+            volume: VOLUME_MAX,
+            pan: PAN_CENTER,
+            adsr: Adsr::NONE,
+            envelope: Envelope::OFF,
+        }
+    }
+    #[inline]
+    pub const fn is_active(&self) -> bool {
+        self.input.is_some()
+    }
+    #[inline]
+    pub const fn stage(&self) -> Stage {
+        self.envelope.stage
+    }
+    #[inline]
+    pub const fn position(&self) -> usize {
+        (self.pos >> FRAC_BITS) as usize
+    }
+    #[inline]
+    pub fn set_position(&mut self, frame: usize) {
+        self.pos = (frame as u64) << FRAC_BITS;
+    }
+    #[inline]
+    pub const fn pitch(&self) -> u32 {
+        self.pitch
+    }
+    // Step through the sample per output frame, 16.16 fixed point.
+    #[inline]
+    pub fn set_pitch(&mut self, pitch: u32) {
+        self.pitch = pitch;
+    }
+    #[inline]
+    pub fn set_volume(&mut self, volume: u16) {
+        self.volume = volume.min(VOLUME_MAX);
+    }
+    // 0 is hard left, 255 hard right.
+    #[inline]
+    pub fn set_pan(&mut self, pan: u8) {
+        self.pan = pan;
+    }
+    #[inline]
+    pub fn set_adsr(&mut self, adsr: Adsr) {
+        self.adsr = adsr;
+    }
+    pub fn start(&mut self, sample: Sample, pitch: u32) {
+        self.begin(Input::Sample(sample), pitch);
+    }
+    pub fn start_stream(&mut self, source: Box<dyn Source>, pitch: u32) {
+        let input = Input::Stream {
+            source,
+            buf: vec![0; STREAM_BUFFER],
+            len: 0,
+        };
+        self.begin(input, pitch);
+    }
+    fn begin(&mut self, input: Input, pitch: u32) {
+        self.input = Some(input);
+        self.pos = 0;
+        self.pitch = pitch;
+        self.envelope = Envelope {
+            stage: Stage::Attack,
+            level: 0,
+        };
+    }
+    #[inline]
+    pub fn release(&mut self) {
+        if self.envelope.stage != Stage::Off {
+            self.envelope.stage = Stage::Release;
+        }
+    }
+    #[inline]
+    pub fn stop(&mut self) {
+        self.input = None;
+        self.envelope = Envelope::OFF;
+    }
+    fn mix(&mut self, out: &mut [i32]) {
+        let Some(input) = &mut self.input else {
+            return;
+        };
+        // Pan 255 counts as 256 so both ends reach full volume and
+        // PAN_CENTER splits it evenly.
+        let pan = self.pan as i32 + (self.pan == 255) as i32;
+        let left = (self.volume as i32 * (256 - pan)) >> 8;
+        let right = (self.volume as i32 * pan) >> 8;
+        for frame in out.chunks_exact_mut(2) {
+            let Some(s) = input.next(&mut self.pos, self.pitch) else {
+                self.stop();
+                return;
+            };
+            let level = self.envelope.next(&self.adsr);
+            if self.envelope.stage == Stage::Off {
+                self.stop();
+                return;
+            }
+            let s = (s * (level >> 1) as i32) >> 15;
+            frame[0] += (s * left) >> 15;
+            frame[1] += (s * right) >> 15;
+        }
+    }
+}
+
+pub struct Mixer {
+    voices: Vec<Voice>,
+    rate: u32,
+    volume: u16,
+    scratch: Vec<i32>,
+}
+
+impl Mixer {
+    // The scratch buffer is sized here so mixing never allocates; `mix`
+    // takes at most `max_frames` frames at a time.
+    pub fn new(voices: usize, rate: u32, max_frames: usize) -> Self {
+        Self {
+            voices: (0..voices).map(|_| Voice::new()).collect(),
+            rate,
+            volume: VOLUME_MAX,
+            scratch: vec![0; max_frames * 2],
+        }
+    }
+    #[inline]
+    pub fn max_frames(&self) -> usize {
+        self.scratch.len() / 2
+    }
+    #[inline]
+    pub const fn rate(&self) -> u32 {
+        self.rate
+    }
+    #[inline]
+    pub fn set_volume(&mut self, volume: u16) {
+        self.volume = volume.min(VOLUME_MAX);
+    }
+    #[inline]
+    pub fn voices(&self) -> &[Voice] {
+        &self.voices
+    }
+    #[inline]
+    pub fn voice(&mut self, index: usize) -> &mut Voice {
+        &mut self.voices[index]
+    }
+    // Pitch step for playing a sample recorded at `frequency` Hz.
+    #[inline]
+    pub const fn pitch_for(&self, frequency: u32) -> u32 {
+        (((frequency as u64) << FRAC_BITS) / self.rate as u64) as u32
+    }
+    #[inline]
+    pub fn play(&mut self, index: usize, sample: Sample) {
+        let pitch = self.pitch_for(sample.rate);
+        self.voices[index].start(sample, pitch);
+    }
+    #[inline]
+    pub fn play_stream(&mut self, index: usize, source: Box<dyn Source>) {
+        let pitch = self.pitch_for(source.rate());
+        self.voices[index].start_stream(source, pitch);
+    }
+    #[inline]
+    pub fn set_frequency(&mut self, index: usize, frequency: u32) {
+        let pitch = self.pitch_for(frequency);
+        self.voices[index].set_pitch(pitch);
+    }
+    // Mixes all voices into interleaved stereo samples, as the AI takes them.
+    pub fn mix(&mut self, out: &mut [i16]) {
+        let len = out.len() & !1;
+        assert!(
+            len <= self.scratch.len(),
+            "mix buffer larger than max_frames"
+        );
+        let scratch = &mut self.scratch[..len];
+        scratch.fill(0);
+        for voice in &mut self.voices {
+            voice.mix(scratch);
+        }
+        let volume = self.volume as i64;
+        for (out, &s) in out.iter_mut().zip(&*scratch) {
+            *out = ((s as i64 * volume) >> 15).clamp(i16::MIN as i64, i16::MAX as i64) as i16;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 32000;
+
+    fn sample(data: &[i16], loop_start: Option<usize>) -> Sample {
+        Sample {
+            data: data.into(),
+            rate: RATE,
+            loop_start,
+        }
+    }
+
+    fn mix(mixer: &mut Mixer, frames: usize) -> Vec<i16> {
+        let mut out = vec![0; frames * 2];
+        mixer.mix(&mut out);
+        out
+    }
+
+    fn right(out: &[i16]) -> Vec<i16> {
+        out.chunks_exact(2).map(|f| f[1]).collect()
+    }
+
+    // Counts up from 0, handing out at most `chunk` samples per read.
+    struct Ramp {
+        next: i16,
+        end: i16,
+        chunk: usize,
+    }
+
+    impl Source for Ramp {
+        fn rate(&self) -> u32 {
+            RATE
+        }
+        fn read(&mut self, out: &mut [i16]) -> usize {
+            let n = out
+                .len()
+                .min(self.chunk)
+                .min((self.end - self.next) as usize);
+            for s in &mut out[..n] {
+                *s = self.next;
+                self.next += 1;
+            }
+            n
+        }
+    }
+
+    #[test]
+    fn pan() {
+        let mut mixer = Mixer::new(1, RATE, 1024);
+        for (pan, frame) in [
+            (PAN_CENTER, [8192, 8192]),
+            (64, [12288, 4096]),
+            (192, [4096, 12288]),
+            (0, [16384, 0]),
+            (255, [0, 16384]),
+        ] {
+            mixer.voice(0).set_pan(pan);
+            mixer.play(0, sample(&[16384; 8], None));
+            assert_eq!(mix(&mut mixer, 3), frame.repeat(3));
+        }
+    }
+
+    #[test]
+    fn volume() {
+        let mut mixer = Mixer::new(2, RATE, 1024);
+        mixer.voice(0).set_pan(0);
+        mixer.voice(0).set_volume(VOLUME_MAX / 2);
+        mixer.voice(1).set_pan(255);
+        mixer.voice(1).set_volume(u16::MAX);
+        mixer.play(0, sample(&[-20000; 4], None));
+        mixer.play(1, sample(&[20000; 4], None));
+        assert_eq!(mix(&mut mixer, 2), [-10000, 20000, -10000, 20000]);
+        mixer.set_volume(VOLUME_MAX / 4);
+        mixer.play(0, sample(&[-20000; 4], None));
+        mixer.play(1, sample(&[20000; 4], None));
+        assert_eq!(mix(&mut mixer, 1), [-2500, 5000]);
+    }
+
+    #[test]
+    fn clamp() {
+        let mut mixer = Mixer::new(2, RATE, 1024);
+        for i in 0..2 {
+            mixer.voice(i).set_pan(255);
+            mixer.play(i, sample(&[30000, -30000], None));
+        }
+        assert_eq!(right(&mix(&mut mixer, 2)), [i16::MAX, i16::MIN]);
+    }
+
+    #[test]
+    fn adsr() {
+        let mut mixer = Mixer::new(1, RATE, 1024);
+        let voice = mixer.voice(0);
+        voice.set_pan(255);
+        voice.set_adsr(Adsr {
+            attack: 4,
+            decay: 4,
+            sustain: VOLUME_MAX / 2,
+            release: 4,
+        });
+        mixer.play(0, sample(&[16384; 4], Some(0)));
+        let mut frames = Vec::new();
+        for _ in 0..10 {
+            let s = right(&mix(&mut mixer, 1))[0];
+            frames.push((s, mixer.voices()[0].stage()));
+        }
+        use Stage::*;
+        assert_eq!(
+            frames,
+            [
+                (4096, Attack),
+                (8192, Attack),
+                (12288, Attack),
+                (16384, Decay),
+                (14336, Decay),
+                (12288, Decay),
+                (10240, Decay),
+                (8192, Sustain),
+                (8192, Sustain),
+                (8192, Sustain),
+            ]
+        );
+        mixer.voice(0).release();
+        assert_eq!(mixer.voices()[0].stage(), Release);
+        assert_eq!(right(&mix(&mut mixer, 3)), [4096, 0, 0]);
+        assert_eq!(mixer.voices()[0].stage(), Off);
+        assert!(!mixer.voices()[0].is_active());
+    }
+
+    #[test]
+    fn loop_wrap() {
+        let data = [0, 100, 200, 300, 400];
+        let mut mixer = Mixer::new(1, RATE, 1024);
+        mixer.voice(0).set_pan(255);
+        mixer.play(0, sample(&data, Some(2)));
+        assert_eq!(
+            right(&mix(&mut mixer, 10)),
+            [0, 100, 200, 300, 400, 200, 300, 400, 200, 300]
+        );
+        // Between the last frame and the loop start it blends towards the
+        // loop start rather than past the end.
+        mixer.play(0, sample(&data, Some(2)));
+        mixer.voice(0).set_pitch(PITCH_ONE * 3 / 2);
+        assert_eq!(
+            right(&mix(&mut mixer, 8)),
+            [0, 150, 300, 300, 300, 300, 300, 300]
+        );
+        mixer.play(0, sample(&data, None));
+        assert_eq!(right(&mix(&mut mixer, 7)), [0, 100, 200, 300, 400, 0, 0]);
+        assert!(!mixer.voices()[0].is_active());
+    }
+
+    #[test]
+    fn stream_refill() {
+        for (chunk, pitch) in [
+            (7, PITCH_ONE),
+            (STREAM_BUFFER, PITCH_ONE),
+            (1, 2 * PITCH_ONE),
+        ] {
+            let mut mixer = Mixer::new(1, RATE, 1024);
+            mixer.voice(0).set_pan(255);
+            let ramp = Ramp {
+                next: 0,
+                end: 600,
+                chunk,
+            };
+            mixer.play_stream(0, Box::new(ramp));
+            mixer.voice(0).set_pitch(pitch);
+            let step = (pitch / PITCH_ONE) as i16;
+            let frames = 600 / step as usize;
+            let out = right(&mix(&mut mixer, frames + 2));
+            let expected: Vec<i16> = (0..frames as i16).map(|i| i * step).chain([0, 0]).collect();
+            assert_eq!(out, expected);
+            assert!(!mixer.voices()[0].is_active());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "max_frames")]
+    fn oversize() {
+        let mut mixer = Mixer::new(1, RATE, 4);
+        mix(&mut mixer, 4);
+        mix(&mut mixer, 5);
+    }
+
+    // Run with `cargo test-host --release mix_speed -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn mix_speed() {
+        const VOICES: usize = 8;
+        const FRAMES: usize = 512;
+        const ROUNDS: usize = 2000;
+        let data: Vec<i16> = (0..4096).map(|i| (i * 37 % 2000 - 1000) as i16).collect();
+        let mut mixer = Mixer::new(VOICES, RATE, FRAMES);
+        for i in 0..VOICES {
+            mixer.voice(i).set_pan(i as u8 * 32);
+            mixer.voice(i).set_adsr(Adsr {
+                attack: 100,
+                decay: 100,
+                sustain: VOLUME_MAX / 2,
+                release: 100,
+            });
+            mixer.play(i, sample(&data, Some(1024)));
+            mixer.voice(i).set_pitch(PITCH_ONE * (i as u32 + 2) / 3);
+        }
+        let mut out = [0; FRAMES * 2];
+        let start = std::time::Instant::now();
+        for _ in 0..ROUNDS {
+            mixer.mix(std::hint::black_box(&mut out));
+        }
+        let elapsed = start.elapsed().as_nanos() as f64;
+        println!(
+            "mix: {:.1} ns per voice frame",
+            elapsed / (ROUNDS * FRAMES * VOICES) as f64
+        );
+    }
+
+    #[test]
+    fn pitch_for() {
+        let mixer = Mixer::new(0, 32000, 0);
+        assert_eq!(mixer.pitch_for(32000), PITCH_ONE);
+        assert_eq!(mixer.pitch_for(16000), PITCH_ONE / 2);
+        assert_eq!(mixer.pitch_for(44100), 90316);
+    }
+}
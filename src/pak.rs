@@ -0,0 +1,630 @@
+use crate::joybus::{self, Bus, DeviceInfo, JoybusError, PifBlock, Slot};
+
+pub const BLOCK_SIZE: usize = 32;
+
+const PAK_PROBE_ADDR: u16 = 0x8000;
+const PAK_PROBE_RESET: u8 = 0xFE;
+const PAK_PROBE_RUMBLE: u8 = 0x80;
+const PAK_PROBE_TRANSFER: u8 = 0x84;
+const RUMBLE_MOTOR_ADDR: u16 = 0xC000;
+const TPAK_POWER_ADDR: u16 = 0x8000;
+const TPAK_BANK_ADDR: u16 = 0xA000;
+const TPAK_STATUS_ADDR: u16 = 0xB000;
+const TPAK_DATA_ADDR: u16 = 0xC000;
+const TPAK_BANK_SIZE: u16 = 0x4000;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum PakError {
+    Joybus(JoybusError),
+    NoPak,
+    Crc,
+    Unaligned,
+}
+
+impl From<JoybusError> for PakError {
+    #[inline]
+    fn from(value: JoybusError) -> Self {
+        Self::Joybus(value)
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum PakType {
+    None,
+    ControllerPak,
+    RumblePak,
+    TransferPak,
+    Unknown,
+}
+
+const ADDRESS_CRC_TABLE: [u8; 16] = [
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x15, 0x1F, 0x0B, 0x16, 0x19, 0x07, 0x0E, 0x1C, 0x0D, 0x1A, 0x01,
+];
+
+pub const fn address_crc(addr: u16) -> u8 {
+    let mut crc = 0;
+    let mut bit = 5;
+    while bit < 16 {
+        if addr & (1 << bit) != 0 {
+            crc ^= ADDRESS_CRC_TABLE[bit];
+        }
+        bit += 1;
+    }
+    crc
+}
+
+#[inline]
+pub const fn encode_address(addr: u16) -> [u8; 2] {
+    let addr = addr & !0x1F;
+    (addr | address_crc(addr) as u16).to_be_bytes()
+}
+
+// CRC-8 with polynomial 0x85, including the 8 zero bits of padding the pak
+// clocks through after the data.
+pub const fn data_crc(data: &[u8; BLOCK_SIZE]) -> u8 {
+    let mut crc: u8 = 0;
+    let mut i = 0;
+    while i <= BLOCK_SIZE {
+        let mut bit = 8;
+        while bit > 0 {
+            bit -= 1;
+            let xor = if crc & 0x80 != 0 { 0x85 } else { 0 };
+            crc <<= 1;
+            if i < BLOCK_SIZE && data[i] & (1 << bit) != 0 {
+                crc |= 1;
+            }
+            crc ^= xor;
+        }
+        i += 1;
+    }
+    crc
+}
+
+// A pak that is missing or was pulled answers with the inverted CRC.
+fn check_crc(data: &[u8; BLOCK_SIZE], crc: u8) -> Result<(), PakError> {
+    let expected = data_crc(data);
+    if crc == expected {
+        Ok(())
+    } else if crc == !expected {
+        Err(PakError::NoPak)
+    } else {
+        Err(PakError::Crc)
+    }
+}
+
+// The pak only takes block addresses, the low bits carry the address CRC.
+#[inline]
+fn aligned(addr: u16) -> Result<(), PakError> {
+    match addr.is_multiple_of(BLOCK_SIZE as u16) {
+        true => Ok(()),
+        false => Err(PakError::Unaligned),
+    }
+}
+
+pub fn read_command(block: &mut PifBlock, addr: u16) -> Result<Slot, JoybusError> {
+    let [hi, lo] = encode_address(addr);
+    block.command(&[joybus::CMD_PAK_READ, hi, lo], BLOCK_SIZE + 1)
+}
+
+pub fn write_command(
+    block: &mut PifBlock,
+    addr: u16,
+    data: &[u8; BLOCK_SIZE],
+) -> Result<Slot, JoybusError> {
+    let [hi, lo] = encode_address(addr);
+    let mut tx = [0; 3 + BLOCK_SIZE];
+    tx[..3].copy_from_slice(&[joybus::CMD_PAK_WRITE, hi, lo]);
+    tx[3..].copy_from_slice(data);
+    block.command(&tx, 1)
+}
+
+pub fn parse_read(rx: &[u8], data: &mut [u8; BLOCK_SIZE]) -> Result<(), PakError> {
+    let [ref block @ .., crc] = *rx else {
+        return Err(JoybusError::BadResponse.into());
+    };
+    let Ok(block) = <&[u8; BLOCK_SIZE]>::try_from(block) else {
+        return Err(JoybusError::BadResponse.into());
+    };
+    check_crc(block, crc)?;
+    data.copy_from_slice(block);
+    Ok(())
+}
+
+pub fn parse_write(rx: &[u8], data: &[u8; BLOCK_SIZE]) -> Result<(), PakError> {
+    let [crc] = *rx else {
+        return Err(JoybusError::BadResponse.into());
+    };
+    check_crc(data, crc)
+}
+
+// Block-level access to a pak's address space, so that filesystems can run
+// on a real pak or on an image in memory.
+pub trait PakMemory {
+    fn read_block(&mut self, addr: u16, data: &mut [u8; BLOCK_SIZE]) -> Result<(), PakError>;
+    fn write_block(&mut self, addr: u16, data: &[u8; BLOCK_SIZE]) -> Result<(), PakError>;
+}
+
+impl<M: PakMemory + ?Sized> PakMemory for &mut M {
+    #[inline]
+    fn read_block(&mut self, addr: u16, data: &mut [u8; BLOCK_SIZE]) -> Result<(), PakError> {
+        (**self).read_block(addr, data)
+    }
+    #[inline]
+    fn write_block(&mut self, addr: u16, data: &[u8; BLOCK_SIZE]) -> Result<(), PakError> {
+        (**self).write_block(addr, data)
+    }
+}
+
+impl PakMemory for [u8] {
+    fn read_block(&mut self, addr: u16, data: &mut [u8; BLOCK_SIZE]) -> Result<(), PakError> {
+        let addr = (addr & !0x1F) as usize;
+        let block = self.get(addr..addr + BLOCK_SIZE).ok_or(PakError::NoPak)?;
+        data.copy_from_slice(block);
+        Ok(())
+    }
+    fn write_block(&mut self, addr: u16, data: &[u8; BLOCK_SIZE]) -> Result<(), PakError> {
+        let addr = (addr & !0x1F) as usize;
+        let block = self
+            .get_mut(addr..addr + BLOCK_SIZE)
+            .ok_or(PakError::NoPak)?;
+        block.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+pub struct Pak<B> {
+    bus: B,
+    port: u8,
+}
+
+impl<B: Bus> Pak<B> {
+    #[inline]
+    pub const fn new(bus: B, port: usize) -> Self {
+        Self {
+            bus,
+            port: port as u8,
+        }
+    }
+    #[inline]
+    pub const fn port(&self) -> usize {
+        self.port as usize
+    }
+    #[inline]
+    pub fn bus(&mut self) -> &mut B {
+        &mut self.bus
+    }
+    pub fn info(&mut self) -> Result<DeviceInfo, PakError> {
+        let mut block = PifBlock::new();
+        block.skip_to(self.port())?;
+        let slot = joybus::info(&mut block)?;
+        block.finish();
+        self.bus.exchange(&mut block);
+        Ok(DeviceInfo::parse(block.response(slot)?)?)
+    }
+    pub fn read(&mut self, addr: u16, data: &mut [u8; BLOCK_SIZE]) -> Result<(), PakError> {
+        aligned(addr)?;
+        let mut block = PifBlock::new();
+        block.skip_to(self.port())?;
+        let slot = read_command(&mut block, addr)?;
+        block.finish();
+        self.bus.exchange(&mut block);
+        parse_read(block.response(slot)?, data)
+    }
+    pub fn write(&mut self, addr: u16, data: &[u8; BLOCK_SIZE]) -> Result<(), PakError> {
+        aligned(addr)?;
+        let mut block = PifBlock::new();
+        block.skip_to(self.port())?;
+        let slot = write_command(&mut block, addr, data)?;
+        block.finish();
+        self.bus.exchange(&mut block);
+        parse_write(block.response(slot)?, data)
+    }
+    #[inline]
+    fn fill(&mut self, addr: u16, value: u8) -> Result<(), PakError> {
+        self.write(addr, &[value; BLOCK_SIZE])
+    }
+    fn probe(&mut self, value: u8) -> Result<bool, PakError> {
+        let mut data = [0; BLOCK_SIZE];
+        self.fill(PAK_PROBE_ADDR, value)?;
+        self.read(PAK_PROBE_ADDR, &mut data)?;
+        Ok(data[BLOCK_SIZE - 1] == value)
+    }
+    // Same sequence libultra uses: a Controller Pak echoes its bank register,
+    // while the Rumble and Transfer Paks only latch their own enable values.
+    pub fn detect(&mut self) -> Result<PakType, PakError> {
+        if !self.info()?.pak_present() {
+            return Ok(PakType::None);
+        }
+        if self.probe(PAK_PROBE_RESET)? {
+            self.fill(PAK_PROBE_ADDR, 0)?;
+            return Ok(PakType::ControllerPak);
+        }
+        if self.probe(PAK_PROBE_RUMBLE)? {
+            return Ok(PakType::RumblePak);
+        }
+        if self.probe(PAK_PROBE_TRANSFER)? {
+            self.fill(TPAK_POWER_ADDR, PAK_PROBE_RESET)?;
+            return Ok(PakType::TransferPak);
+        }
+        Ok(PakType::Unknown)
+    }
+    pub fn rumble_init(&mut self) -> Result<(), PakError> {
+        self.fill(PAK_PROBE_ADDR, PAK_PROBE_RUMBLE)
+    }
+    pub fn rumble(&mut self, on: bool) -> Result<(), PakError> {
+        self.fill(RUMBLE_MOTOR_ADDR, on as u8)
+    }
+    pub fn transfer_pak_power(&mut self, on: bool) -> Result<(), PakError> {
+        let value = if on {
+            PAK_PROBE_TRANSFER
+        } else {
+            PAK_PROBE_RESET
+        };
+        self.fill(TPAK_POWER_ADDR, value)
+    }
+    pub fn transfer_pak_status(&mut self) -> Result<TransferPakStatus, PakError> {
+        let mut data = [0; BLOCK_SIZE];
+        self.read(TPAK_STATUS_ADDR, &mut data)?;
+        Ok(TransferPakStatus(data[0]))
+    }
+    pub fn transfer_pak_access(&mut self, on: bool) -> Result<(), PakError> {
+        self.fill(TPAK_STATUS_ADDR, on as u8)
+    }
+    fn gb_bank(&mut self, addr: u16) -> Result<u16, PakError> {
+        aligned(addr)?;
+        self.fill(TPAK_BANK_ADDR, (addr / TPAK_BANK_SIZE) as u8)?;
+        Ok(TPAK_DATA_ADDR + (addr % TPAK_BANK_SIZE))
+    }
+    pub fn gb_read(&mut self, addr: u16, data: &mut [u8; BLOCK_SIZE]) -> Result<(), PakError> {
+        let addr = self.gb_bank(addr)?;
+        self.read(addr, data)
+    }
+    pub fn gb_write(&mut self, addr: u16, data: &[u8; BLOCK_SIZE]) -> Result<(), PakError> {
+        let addr = self.gb_bank(addr)?;
+        self.write(addr, data)
+    }
+}
+
+impl<B: Bus> PakMemory for Pak<B> {
+    #[inline]
+    fn read_block(&mut self, addr: u16, data: &mut [u8; BLOCK_SIZE]) -> Result<(), PakError> {
+        self.read(addr, data)
+    }
+    #[inline]
+    fn write_block(&mut self, addr: u16, data: &[u8; BLOCK_SIZE]) -> Result<(), PakError> {
+        self.write(addr, data)
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+#[repr(transparent)]
+pub struct TransferPakStatus(pub u8);
+
+impl TransferPakStatus {
+    #[inline]
+    pub const fn access(self) -> bool {
+        self.0 & 0x01 != 0
+    }
+    #[inline]
+    pub const fn reset_detected(self) -> bool {
+        self.0 & 0x04 != 0
+    }
+    #[inline]
+    pub const fn resetting(self) -> bool {
+        self.0 & 0x08 != 0
+    }
+    #[inline]
+    pub const fn cart_removed(self) -> bool {
+        self.0 & 0x40 != 0
+    }
+    #[inline]
+    pub const fn powered(self) -> bool {
+        self.0 & 0x80 != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{vec, vec::Vec};
+
+    // A pak in a controller on one port, answering the way the hardware does.
+    struct SimPak {
+        kind: PakType,
+        port: usize,
+        memory: Vec<u8>,
+        latch: u8,
+        bank: u8,
+        access: bool,
+        motor: bool,
+        corrupt: bool,
+        bad_addresses: usize,
+    }
+
+    impl SimPak {
+        fn new(kind: PakType, port: usize) -> Self {
+            let size = match kind {
+                PakType::ControllerPak => 0x8000,
+                PakType::TransferPak => 0x10000,
+                _ => 0,
+            };
+            Self {
+                kind,
+                port,
+                memory: (0..size).map(|i| (i ^ (i >> 8)) as u8).collect(),
+                latch: 0,
+                bank: 0,
+                access: false,
+                motor: false,
+                corrupt: false,
+                bad_addresses: 0,
+            }
+        }
+        fn read(&mut self, addr: u16) -> [u8; BLOCK_SIZE] {
+            let addr = addr as usize;
+            match (self.kind, addr) {
+                (PakType::ControllerPak, 0..0x8000) => {
+                    self.memory[addr..addr + BLOCK_SIZE].try_into().unwrap()
+                }
+                (PakType::ControllerPak, 0x8000..0x9000) => [self.latch; BLOCK_SIZE],
+                (PakType::RumblePak, 0x8000..0x9000) if self.latch == PAK_PROBE_RUMBLE => {
+                    [PAK_PROBE_RUMBLE; BLOCK_SIZE]
+                }
+                (PakType::TransferPak, 0x8000..0x9000) if self.latch == PAK_PROBE_TRANSFER => {
+                    [PAK_PROBE_TRANSFER; BLOCK_SIZE]
+                }
+                (PakType::TransferPak, 0xB000..0xC000) => [0x80 | self.access as u8; BLOCK_SIZE],
+                (PakType::TransferPak, 0xC000..) if self.access => {
+                    let addr = self.bank as usize * 0x4000 + addr - 0xC000;
+                    self.memory[addr..addr + BLOCK_SIZE].try_into().unwrap()
+                }
+                _ => [0; BLOCK_SIZE],
+            }
+        }
+        fn write(&mut self, addr: u16, data: &[u8; BLOCK_SIZE]) {
+            let addr = addr as usize;
+            match (self.kind, addr) {
+                (PakType::ControllerPak, 0..0x8000) => {
+                    self.memory[addr..addr + BLOCK_SIZE].copy_from_slice(data)
+                }
+                (_, 0x8000..0x9000) => self.latch = data[0],
+                (PakType::RumblePak, 0xC000..) => self.motor = data[0] != 0,
+                (PakType::TransferPak, 0xA000..0xB000) => self.bank = data[0],
+                (PakType::TransferPak, 0xB000..0xC000) => self.access = data[0] & 1 != 0,
+                (PakType::TransferPak, 0xC000..) if self.access => {
+                    let addr = self.bank as usize * 0x4000 + addr - 0xC000;
+                    self.memory[addr..addr + BLOCK_SIZE].copy_from_slice(data)
+                }
+                _ => {}
+            }
+        }
+        fn address(&mut self, hi: u8, lo: u8) -> u16 {
+            let addr = u16::from_be_bytes([hi, lo]);
+            if addr as u8 & 0x1F != address_crc(addr) {
+                self.bad_addresses += 1;
+            }
+            addr & !0x1F
+        }
+        fn crc(&self, data: &[u8; BLOCK_SIZE]) -> u8 {
+            match (self.kind, self.corrupt) {
+                (PakType::None, _) => !data_crc(data),
+                (_, true) => data_crc(data) ^ 0x10,
+                _ => data_crc(data),
+            }
+        }
+    }
+
+    impl Bus for SimPak {
+        fn exchange(&mut self, block: &mut PifBlock) {
+            block.for_each_command(|channel, tx, rx| {
+                if channel != self.port {
+                    return false;
+                }
+                match *tx {
+                    [joybus::CMD_INFO] => {
+                        let present = self.kind != PakType::None;
+                        rx.copy_from_slice(&[0x05, 0x00, present as u8]);
+                    }
+                    [joybus::CMD_PAK_READ, hi, lo] => {
+                        let addr = self.address(hi, lo);
+                        let data = self.read(addr);
+                        rx[..BLOCK_SIZE].copy_from_slice(&data);
+                        rx[BLOCK_SIZE] = self.crc(&data);
+                    }
+                    [joybus::CMD_PAK_WRITE, hi, lo, ref data @ ..] => {
+                        let addr = self.address(hi, lo);
+                        let data = data.try_into().unwrap();
+                        self.write(addr, data);
+                        rx[0] = self.crc(data);
+                    }
+                    _ => return false,
+                }
+                true
+            });
+        }
+    }
+
+    // The usual shift-in-then-divide form of the same CRC.
+    fn reference_crc(data: &[u8]) -> u8 {
+        let mut crc = 0u8;
+        for &b in data {
+            crc ^= b;
+            for _ in 0..8 {
+                crc = if crc & 0x80 != 0 {
+                    (crc << 1) ^ 0x85
+                } else {
+                    crc << 1
+                };
+            }
+        }
+        crc
+    }
+
+    #[test]
+    fn address_crc_values() {
+        assert_eq!(encode_address(0x0000), [0x00, 0x00]);
+        assert_eq!(encode_address(0x0020), [0x00, 0x35]);
+        assert_eq!(encode_address(0x8000), [0x80, 0x01]);
+        assert_eq!(encode_address(0xC000), [0xC0, 0x1B]);
+        assert_eq!(encode_address(0x801F), encode_address(0x8000));
+        for addr in (0..=0xFFFFu16).step_by(BLOCK_SIZE) {
+            let [hi, lo] = encode_address(addr);
+            assert_eq!(u16::from_be_bytes([hi, lo]) & !0x1F, addr);
+        }
+    }
+
+    #[test]
+    fn data_crc_values() {
+        assert_eq!(data_crc(&[0; BLOCK_SIZE]), 0);
+        let patterns = [
+            [0xFF; BLOCK_SIZE],
+            [0x01; BLOCK_SIZE],
+            [0x80; BLOCK_SIZE],
+            core::array::from_fn(|i| i as u8),
+            core::array::from_fn(|i| (i * 37 + 11) as u8),
+        ];
+        for data in &patterns {
+            assert_eq!(data_crc(data), reference_crc(data));
+        }
+    }
+
+    #[test]
+    fn command_blocks() {
+        let mut block = PifBlock::new();
+        block.skip().unwrap();
+        let slot = read_command(&mut block, 0x0420).unwrap();
+        let [hi, lo] = encode_address(0x0420);
+        assert_eq!(slot.channel(), 1);
+        assert_eq!(
+            block.as_bytes()[1..6],
+            [3, BLOCK_SIZE as u8 + 1, joybus::CMD_PAK_READ, hi, lo]
+        );
+
+        let data = core::array::from_fn(|i| i as u8);
+        let mut block = PifBlock::new();
+        write_command(&mut block, 0x8000, &data).unwrap();
+        assert_eq!(
+            block.as_bytes()[..5],
+            [3 + BLOCK_SIZE as u8, 1, joybus::CMD_PAK_WRITE, 0x80, 0x01]
+        );
+        assert_eq!(block.as_bytes()[5..5 + BLOCK_SIZE], data);
+        // Only one write fits in a block.
+        assert_eq!(
+            write_command(&mut block, 0x8020, &data),
+            Err(JoybusError::BlockFull)
+        );
+    }
+
+    #[test]
+    fn parse_responses() {
+        let data: [u8; BLOCK_SIZE] = core::array::from_fn(|i| !(i as u8));
+        let crc = data_crc(&data);
+        let mut rx = [0; BLOCK_SIZE + 1];
+        rx[..BLOCK_SIZE].copy_from_slice(&data);
+        rx[BLOCK_SIZE] = crc;
+        let mut out = [0; BLOCK_SIZE];
+        assert_eq!(parse_read(&rx, &mut out), Ok(()));
+        assert_eq!(out, data);
+        rx[BLOCK_SIZE] = !crc;
+        assert_eq!(parse_read(&rx, &mut out), Err(PakError::NoPak));
+        rx[BLOCK_SIZE] = crc ^ 1;
+        assert_eq!(parse_read(&rx, &mut out), Err(PakError::Crc));
+        assert_eq!(
+            parse_read(&rx[1..], &mut out),
+            Err(PakError::Joybus(JoybusError::BadResponse))
+        );
+        assert_eq!(parse_write(&[crc], &data), Ok(()));
+        assert_eq!(parse_write(&[!crc], &data), Err(PakError::NoPak));
+        assert_eq!(
+            parse_write(&[], &data),
+            Err(PakError::Joybus(JoybusError::BadResponse))
+        );
+    }
+
+    #[test]
+    fn detect() {
+        for kind in [
+            PakType::None,
+            PakType::ControllerPak,
+            PakType::RumblePak,
+            PakType::TransferPak,
+        ] {
+            let mut pak = Pak::new(SimPak::new(kind, 2), 2);
+            assert_eq!(pak.detect(), Ok(kind));
+            assert_eq!(pak.bus().bad_addresses, 0);
+        }
+        let mut pak = Pak::new(SimPak::new(PakType::ControllerPak, 2), 1);
+        assert_eq!(pak.detect(), Err(PakError::Joybus(JoybusError::NoDevice)));
+    }
+
+    #[test]
+    fn controller_pak_blocks() {
+        let mut pak = Pak::new(SimPak::new(PakType::ControllerPak, 0), 0);
+        let mut data = [0; BLOCK_SIZE];
+        pak.read(0x1240, &mut data).unwrap();
+        assert_eq!(data[..], pak.bus().memory[0x1240..0x1260]);
+        let data = [0x5A; BLOCK_SIZE];
+        pak.write_block(0x7FE0, &data).unwrap();
+        assert_eq!(pak.bus().memory[0x7FE0..], data);
+        let mut out = [0; BLOCK_SIZE];
+        pak.read_block(0x7FE0, &mut out).unwrap();
+        assert_eq!(out, data);
+        assert_eq!(pak.bus().bad_addresses, 0);
+        assert_eq!(pak.read(0x1250, &mut out), Err(PakError::Unaligned));
+        assert_eq!(
+            pak.write(0x7FE1, &[0; BLOCK_SIZE]),
+            Err(PakError::Unaligned)
+        );
+        assert_eq!(pak.bus().memory[0x7FE0..], data);
+
+        pak.bus().corrupt = true;
+        assert_eq!(pak.read(0, &mut out), Err(PakError::Crc));
+        assert_eq!(pak.write(0, &data), Err(PakError::Crc));
+        pak.bus().kind = PakType::None;
+        assert_eq!(pak.read(0, &mut out), Err(PakError::NoPak));
+    }
+
+    #[test]
+    fn rumble() {
+        let mut pak = Pak::new(SimPak::new(PakType::RumblePak, 3), 3);
+        pak.rumble_init().unwrap();
+        pak.rumble(true).unwrap();
+        assert!(pak.bus().motor);
+        pak.rumble(false).unwrap();
+        assert!(!pak.bus().motor);
+    }
+
+    #[test]
+    fn transfer_pak() {
+        let mut pak = Pak::new(SimPak::new(PakType::TransferPak, 0), 0);
+        pak.transfer_pak_power(true).unwrap();
+        pak.transfer_pak_access(true).unwrap();
+        let status = pak.transfer_pak_status().unwrap();
+        assert!(status.powered() && status.access());
+
+        let mut data = [0; BLOCK_SIZE];
+        pak.gb_read(0x4020, &mut data).unwrap();
+        assert_eq!(pak.bus().bank, 1);
+        assert_eq!(data[..], pak.bus().memory[0x4020..0x4040]);
+        let data = [0xC3; BLOCK_SIZE];
+        pak.gb_write(0xA000, &data).unwrap();
+        assert_eq!(pak.bus().bank, 2);
+        assert_eq!(pak.bus().memory[0xA000..0xA020], data);
+
+        let mut out = [0; BLOCK_SIZE];
+        assert_eq!(pak.gb_read(0x4010, &mut out), Err(PakError::Unaligned));
+        assert_eq!(pak.gb_write(0xA001, &data), Err(PakError::Unaligned));
+        assert_eq!(pak.bus().memory[0xA000..0xA020], data);
+    }
+
+    #[test]
+    fn memory_image() {
+        let mut image = vec![0u8; 0x100];
+        let data = [7; BLOCK_SIZE];
+        image[..].write_block(0x20, &data).unwrap();
+        let mut out = [0; BLOCK_SIZE];
+        image[..].read_block(0x20, &mut out).unwrap();
+        assert_eq!(out, data);
+        assert_eq!(image[..].read_block(0x100, &mut out), Err(PakError::NoPak));
+    }
+}
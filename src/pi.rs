@@ -0,0 +1,129 @@
+use core::ptr::NonNull;
+
+use n64_pac::pi::PeripheralInterface;
+
+use crate::system::{self, PhysAddr};
+
+pub const CART_DOM1_ADDR2: PhysAddr = 0x10000000;
+
+#[inline(always)]
+pub fn wait(pi: &PeripheralInterface) {
+    loop {
+        let status = unsafe { pi.status.read().read };
+        if !status.dma_busy() && !status.io_busy() {
+            break;
+        }
+    }
+}
+
+// Audio streams DMA from ROM inside the AI interrupt, so anything that
+// programs the DMA registers does it with interrupts masked, or the interrupt
+// could swap the addresses out from under it before the length is written.
+#[inline]
+pub fn locked<R>(f: impl FnOnce(&PeripheralInterface) -> R) -> R {
+    let status = system::interrupts_disable();
+    let pi = unsafe { PeripheralInterface::new() };
+    let result = f(&pi);
+    system::interrupts_restore(status);
+    result
+}
+
+#[inline]
+pub fn io_read(addr: PhysAddr) -> u32 {
+    let pi = unsafe { PeripheralInterface::new() };
+    wait(&pi);
+    unsafe { system::virtual_uncached_addr::<u32>(addr).read_volatile() }
+}
+
+#[inline]
+pub fn io_write(addr: PhysAddr, value: u32) {
+    let pi = unsafe { PeripheralInterface::new() };
+    wait(&pi);
+    unsafe { system::virtual_uncached_addr::<u32>(addr).write_volatile(value) }
+}
+
+pub const CART_DOM2_ADDR2: PhysAddr = 0x08000000;
+
+// Bounce buffer for DMA to and from memory whose alignment is not known.
+#[derive(Clone, Debug)]
+#[repr(C, align(16))]
+pub struct DmaBuffer<const N: usize>(pub [u8; N]);
+
+impl<const N: usize> Default for DmaBuffer<N> {
+    #[inline]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> DmaBuffer<N> {
+    #[inline]
+    pub const fn new() -> Self {
+        Self([0; N])
+    }
+}
+
+const PI_REGS: NonNull<u32> = unsafe { NonNull::new_unchecked(0xA4600000 as *mut u32) };
+const PI_BSD_DOM2_LAT_REG: usize = 9;
+const PI_BSD_DOM2_PWD_REG: usize = 10;
+const PI_BSD_DOM2_PGS_REG: usize = 11;
+const PI_BSD_DOM2_RLS_REG: usize = 12;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct DomainTiming {
+    pub latency: u8,
+    pub pulse_width: u8,
+    pub page_size: u8,
+    pub release: u8,
+}
+
+pub fn set_dom2_timing(timing: DomainTiming) {
+    let pi = unsafe { PeripheralInterface::new() };
+    wait(&pi);
+    unsafe {
+        PI_REGS
+            .add(PI_BSD_DOM2_LAT_REG)
+            .write_volatile(timing.latency as u32);
+        PI_REGS
+            .add(PI_BSD_DOM2_PWD_REG)
+            .write_volatile(timing.pulse_width as u32);
+        PI_REGS
+            .add(PI_BSD_DOM2_PGS_REG)
+            .write_volatile(timing.page_size as u32);
+        PI_REGS
+            .add(PI_BSD_DOM2_RLS_REG)
+            .write_volatile(timing.release as u32);
+    }
+}
+
+// The RDRAM side of a PI DMA must be 8 byte aligned and the length even.
+pub fn dma_read(addr: PhysAddr, buf: &mut [u8]) {
+    if buf.is_empty() {
+        return;
+    }
+    system::data_cache_hit_writeback_invalidate(buf);
+    let dram = system::physical_addr(NonNull::from(&mut *buf).cast::<u8>());
+    locked(|pi| {
+        wait(pi);
+        pi.dram_addr.write(dram);
+        pi.cart_addr.write(addr);
+        pi.wr_len.write(buf.len() as u32 - 1);
+        wait(pi);
+    });
+    system::data_cache_hit_invalidate(buf);
+}
+
+pub fn dma_write(addr: PhysAddr, buf: &[u8]) {
+    if buf.is_empty() {
+        return;
+    }
+    system::data_cache_hit_writeback(buf);
+    let dram = system::physical_addr(NonNull::from(buf).cast::<u8>());
+    locked(|pi| {
+        wait(pi);
+        pi.dram_addr.write(dram);
+        pi.cart_addr.write(addr);
+        pi.rd_len.write(buf.len() as u32 - 1);
+        wait(pi);
+    });
+}
@@ -0,0 +1,437 @@
+use crate::joybus::{self, Bus, CART_CHANNEL, DeviceInfo, JoybusError, PifBlock, Slot};
+
+pub const BLOCK_SIZE: usize = 8;
+pub const BLOCK_CONTROL: u8 = 0;
+pub const BLOCK_TIME: u8 = 2;
+
+const STATUS_STOPPED: u8 = 0x80;
+const CONTROL_WRITE_PROTECT: u8 = 0x03;
+const CONTROL_STOP: u8 = 0x04;
+const HOUR_24H: u8 = 0x80;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum RtcError {
+    Joybus(JoybusError),
+    NotPresent,
+    InvalidTime,
+}
+
+impl From<JoybusError> for RtcError {
+    #[inline]
+    fn from(value: JoybusError) -> Self {
+        Self::Joybus(value)
+    }
+}
+
+#[inline]
+pub const fn bcd_encode(v: u8) -> u8 {
+    ((v / 10) << 4) | (v % 10)
+}
+
+#[inline]
+pub const fn bcd_decode(v: u8) -> Option<u8> {
+    let (hi, lo) = (v >> 4, v & 0x0F);
+    if hi > 9 || lo > 9 {
+        return None;
+    }
+    Some(hi * 10 + lo)
+}
+
+const fn is_leap(year: u16) -> bool {
+    year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400))
+}
+
+const fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days since 1970-01-01 for a proleptic Gregorian date.
+const fn days_from_civil(year: u16, month: u8, day: u8) -> u32 {
+    let y = if month <= 2 { year - 1 } else { year } as u32;
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let m = month as u32;
+    let mp = if m > 2 { m - 3 } else { m + 9 };
+    let doy = (153 * mp + 2) / 5 + day as u32 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    // Only times that fit in a 32-bit Unix time are valid, so a clock that
+    // was never set (century 0, year 00) reads as invalid.
+    pub const fn is_valid(&self) -> bool {
+        self.year >= 1970
+            && self.year < 2100
+            && self.month >= 1
+            && self.month <= 12
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+    // 0 is Sunday. Like unix_time and to_bytes, this is None for dates
+    // outside the valid range.
+    #[inline]
+    pub const fn weekday(&self) -> Option<u8> {
+        if !self.is_valid() {
+            return None;
+        }
+        Some(((days_from_civil(self.year, self.month, self.day) + 4) % 7) as u8)
+    }
+    #[inline]
+    pub const fn unix_time(&self) -> Option<u32> {
+        if !self.is_valid() {
+            return None;
+        }
+        Some(
+            days_from_civil(self.year, self.month, self.day) * 86400
+                + self.hour as u32 * 3600
+                + self.minute as u32 * 60
+                + self.second as u32,
+        )
+    }
+    pub const fn from_unix_time(time: u32) -> Self {
+        let days = time / 86400;
+        let secs = time % 86400;
+        let z = days + 719468;
+        let era = z / 146097;
+        let doe = z - era * 146097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + (month <= 2) as u32;
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: (doy - (153 * mp + 2) / 5 + 1) as u8,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+        }
+    }
+    // Block 2 layout: second, minute, hour, day, weekday, month, year and
+    // century, all BCD except the century, which counts from 1900.
+    pub fn from_bytes(b: &[u8; BLOCK_SIZE]) -> Option<Self> {
+        let time = Self {
+            year: 1900 + b[7] as u16 * 100 + bcd_decode(b[6])? as u16,
+            month: bcd_decode(b[5])?,
+            day: bcd_decode(b[3])?,
+            hour: bcd_decode(b[2] & !HOUR_24H)?,
+            minute: bcd_decode(b[1])?,
+            second: bcd_decode(b[0])?,
+        };
+        time.is_valid().then_some(time)
+    }
+    pub const fn to_bytes(&self) -> Option<[u8; BLOCK_SIZE]> {
+        let Some(weekday) = self.weekday() else {
+            return None;
+        };
+        Some([
+            bcd_encode(self.second),
+            bcd_encode(self.minute),
+            bcd_encode(self.hour) | HOUR_24H,
+            bcd_encode(self.day),
+            weekday,
+            bcd_encode(self.month),
+            bcd_encode((self.year % 100) as u8),
+            ((self.year - 1900) / 100) as u8,
+        ])
+    }
+}
+
+pub fn status_command(block: &mut PifBlock) -> Result<Slot, JoybusError> {
+    block.skip_to(CART_CHANNEL)?;
+    block.command(&[joybus::CMD_RTC_STATUS], 3)
+}
+
+pub fn read_command(block: &mut PifBlock, index: u8) -> Result<Slot, JoybusError> {
+    block.skip_to(CART_CHANNEL)?;
+    block.command(&[joybus::CMD_RTC_READ, index], BLOCK_SIZE + 1)
+}
+
+pub fn write_command(
+    block: &mut PifBlock,
+    index: u8,
+    data: &[u8; BLOCK_SIZE],
+) -> Result<Slot, JoybusError> {
+    block.skip_to(CART_CHANNEL)?;
+    let mut tx = [0; 2 + BLOCK_SIZE];
+    tx[..2].copy_from_slice(&[joybus::CMD_RTC_WRITE, index]);
+    tx[2..].copy_from_slice(data);
+    block.command(&tx, 1)
+}
+
+pub struct Rtc<B> {
+    bus: B,
+}
+
+impl<B: Bus> Rtc<B> {
+    pub fn new(mut bus: B) -> Result<Self, RtcError> {
+        if Self::status_on(&mut bus)?.is_none_or(|info| info.identifier != DeviceInfo::RTC) {
+            return Err(RtcError::NotPresent);
+        }
+        Ok(Self { bus })
+    }
+    fn status_on(bus: &mut B) -> Result<Option<DeviceInfo>, RtcError> {
+        let mut block = PifBlock::new();
+        let slot = status_command(&mut block)?;
+        block.finish();
+        bus.exchange(&mut block);
+        match block.response(slot) {
+            Ok(rx) => Ok(Some(DeviceInfo::parse(rx)?)),
+            Err(JoybusError::NoDevice) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+    #[inline]
+    pub fn bus(&mut self) -> &mut B {
+        &mut self.bus
+    }
+    pub fn stopped(&mut self) -> Result<bool, RtcError> {
+        let info = Self::status_on(&mut self.bus)?.ok_or(RtcError::NotPresent)?;
+        Ok(info.status & STATUS_STOPPED != 0)
+    }
+    pub fn read_block(&mut self, index: u8, data: &mut [u8; BLOCK_SIZE]) -> Result<(), RtcError> {
+        let mut block = PifBlock::new();
+        let slot = read_command(&mut block, index)?;
+        block.finish();
+        self.bus.exchange(&mut block);
+        data.copy_from_slice(&block.response(slot)?[..BLOCK_SIZE]);
+        Ok(())
+    }
+    pub fn write_block(&mut self, index: u8, data: &[u8; BLOCK_SIZE]) -> Result<(), RtcError> {
+        let mut block = PifBlock::new();
+        let slot = write_command(&mut block, index, data)?;
+        block.finish();
+        self.bus.exchange(&mut block);
+        block.response(slot)?;
+        Ok(())
+    }
+    pub fn read_time(&mut self) -> Result<DateTime, RtcError> {
+        let mut data = [0; BLOCK_SIZE];
+        self.read_block(BLOCK_TIME, &mut data)?;
+        DateTime::from_bytes(&data).ok_or(RtcError::InvalidTime)
+    }
+    // The clock has to be unprotected and stopped while the time block is
+    // written, then protected and restarted.
+    pub fn write_time(&mut self, time: &DateTime) -> Result<(), RtcError> {
+        let bytes = time.to_bytes().ok_or(RtcError::InvalidTime)?;
+        let mut control = [0; BLOCK_SIZE];
+        control[1] = CONTROL_STOP;
+        self.write_block(BLOCK_CONTROL, &control)?;
+        self.write_block(BLOCK_TIME, &bytes)?;
+        control[0] = CONTROL_WRITE_PROTECT;
+        control[1] = 0;
+        self.write_block(BLOCK_CONTROL, &control)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The cartridge clock: three blocks, with the time block only writable
+    // while unprotected and only latched while the clock is stopped.
+    #[derive(Default)]
+    struct SimRtc {
+        blocks: [[u8; BLOCK_SIZE]; 3],
+        absent: bool,
+        writes: usize,
+    }
+
+    impl SimRtc {
+        fn stopped(&self) -> bool {
+            self.blocks[0][1] & CONTROL_STOP != 0
+        }
+    }
+
+    impl Bus for SimRtc {
+        fn exchange(&mut self, block: &mut PifBlock) {
+            block.for_each_command(|channel, tx, rx| {
+                if channel != CART_CHANNEL || self.absent {
+                    return false;
+                }
+                match *tx {
+                    [joybus::CMD_RTC_STATUS] => {
+                        let status = if self.stopped() { STATUS_STOPPED } else { 0 };
+                        rx.copy_from_slice(&[0x10, 0x00, status]);
+                    }
+                    [joybus::CMD_RTC_READ, index @ 0..3] => {
+                        rx[..BLOCK_SIZE].copy_from_slice(&self.blocks[index as usize]);
+                        rx[BLOCK_SIZE] = 0;
+                    }
+                    [joybus::CMD_RTC_WRITE, index @ 0..3, ref data @ ..] => {
+                        let protected = match index {
+                            BLOCK_TIME => self.blocks[0][0] & 0x02 != 0 || !self.stopped(),
+                            _ => index != BLOCK_CONTROL && self.blocks[0][0] & 0x01 != 0,
+                        };
+                        if !protected {
+                            self.blocks[index as usize].copy_from_slice(data);
+                            self.writes += 1;
+                        }
+                        rx[0] = 0;
+                    }
+                    _ => return false,
+                }
+                true
+            });
+        }
+    }
+
+    const fn time(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+    }
+
+    #[test]
+    fn bcd() {
+        for v in 0..100 {
+            assert_eq!(bcd_decode(bcd_encode(v)), Some(v));
+        }
+        assert_eq!(bcd_decode(0x1A), None);
+        assert_eq!(bcd_decode(0xA1), None);
+    }
+
+    #[test]
+    fn unix_time() {
+        assert_eq!(time(1970, 1, 1, 0, 0, 0).unix_time(), Some(0));
+        assert_eq!(time(2000, 1, 1, 0, 0, 0).unix_time(), Some(946684800));
+        assert_eq!(time(2038, 1, 19, 3, 14, 8).unix_time(), Some(1 << 31));
+        assert_eq!(time(2099, 12, 31, 23, 59, 59).unix_time(), Some(4102444799));
+        assert_eq!(time(1969, 12, 31, 23, 59, 59).unix_time(), None);
+        assert_eq!(
+            DateTime::from_unix_time(951782400),
+            time(2000, 2, 29, 0, 0, 0)
+        );
+        for t in (0..4102444800u32).step_by(86400 * 7 + 3601) {
+            let date = DateTime::from_unix_time(t);
+            assert!(date.is_valid());
+            assert_eq!(date.unix_time(), Some(t));
+        }
+    }
+
+    #[test]
+    fn weekday() {
+        assert_eq!(time(1970, 1, 1, 0, 0, 0).weekday(), Some(4));
+        assert_eq!(time(2000, 1, 1, 0, 0, 0).weekday(), Some(6));
+        assert_eq!(time(2024, 2, 29, 0, 0, 0).weekday(), Some(4));
+        assert_eq!(time(2099, 12, 31, 0, 0, 0).weekday(), Some(4));
+        assert_eq!(time(1969, 12, 31, 0, 0, 0).weekday(), None);
+        assert_eq!(time(0, 1, 1, 0, 0, 0).weekday(), None);
+    }
+
+    #[test]
+    fn validity() {
+        assert!(time(1970, 1, 1, 0, 0, 0).is_valid());
+        assert!(time(2000, 2, 29, 23, 59, 59).is_valid());
+        assert!(!time(1969, 12, 31, 23, 59, 59).is_valid());
+        assert!(!time(1900, 1, 1, 0, 0, 0).is_valid());
+        assert!(!time(2100, 1, 1, 0, 0, 0).is_valid());
+        assert!(!time(2023, 2, 29, 0, 0, 0).is_valid());
+        assert!(!time(2024, 4, 31, 0, 0, 0).is_valid());
+        assert!(!time(2024, 13, 1, 0, 0, 0).is_valid());
+        assert!(!time(2024, 1, 1, 24, 0, 0).is_valid());
+    }
+
+    #[test]
+    fn bytes() {
+        let date = time(2024, 7, 9, 18, 5, 42);
+        let bytes = date.to_bytes().unwrap();
+        assert_eq!(bytes, [0x42, 0x05, 0x98, 0x09, 2, 0x07, 0x24, 1]);
+        assert_eq!(DateTime::from_bytes(&bytes), Some(date));
+        assert_eq!(
+            DateTime::from_bytes(&[0x00, 0x00, 0x12, 0x31, 5, 0x12, 0x99, 0]),
+            Some(time(1999, 12, 31, 12, 0, 0))
+        );
+        assert_eq!(time(1900, 1, 1, 0, 0, 0).to_bytes(), None);
+        assert_eq!(time(2024, 2, 30, 0, 0, 0).to_bytes(), None);
+        // A clock that was never set reads back as 1900-00-00.
+        assert_eq!(DateTime::from_bytes(&[0; BLOCK_SIZE]), None);
+        assert_eq!(
+            DateTime::from_bytes(&[0x00, 0x00, 0x00, 0x01, 1, 0x01, 0x00, 0]),
+            None
+        );
+        assert_eq!(
+            DateTime::from_bytes(&[0x6A, 0x00, 0x00, 0x01, 1, 0x01, 0x00, 1]),
+            None
+        );
+    }
+
+    #[test]
+    fn command_blocks() {
+        let mut block = PifBlock::new();
+        let slot = read_command(&mut block, BLOCK_TIME).unwrap();
+        assert_eq!(slot.channel(), CART_CHANNEL);
+        assert_eq!(
+            block.as_bytes()[..8],
+            [0, 0, 0, 0, 2, 9, joybus::CMD_RTC_READ, BLOCK_TIME]
+        );
+        let mut block = PifBlock::new();
+        write_command(&mut block, BLOCK_CONTROL, &[1; BLOCK_SIZE]).unwrap();
+        assert_eq!(
+            block.as_bytes()[4..8],
+            [10, 1, joybus::CMD_RTC_WRITE, BLOCK_CONTROL]
+        );
+        assert_eq!(block.as_bytes()[8..16], [1; BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn device() {
+        let absent = SimRtc {
+            absent: true,
+            ..Default::default()
+        };
+        assert!(matches!(Rtc::new(absent), Err(RtcError::NotPresent)));
+
+        let mut rtc = Rtc::new(SimRtc::default()).unwrap();
+        assert_eq!(rtc.read_time(), Err(RtcError::InvalidTime));
+        assert_eq!(
+            rtc.write_time(&time(1969, 12, 31, 0, 0, 0)),
+            Err(RtcError::InvalidTime)
+        );
+        assert_eq!(rtc.bus().writes, 0);
+
+        let date = time(2001, 9, 9, 1, 46, 40);
+        rtc.write_time(&date).unwrap();
+        assert_eq!(rtc.bus().writes, 3);
+        assert_eq!(rtc.read_time(), Ok(date));
+        assert_eq!(rtc.stopped(), Ok(false));
+        assert_eq!(rtc.bus().blocks[0][0], CONTROL_WRITE_PROTECT);
+
+        // Writes to a protected time block are dropped by the clock.
+        rtc.write_block(BLOCK_TIME, &time(2010, 1, 1, 0, 0, 0).to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(rtc.read_time(), Ok(date));
+
+        rtc.bus().absent = true;
+        assert_eq!(rtc.stopped(), Err(RtcError::NotPresent));
+        assert_eq!(
+            rtc.read_time(),
+            Err(RtcError::Joybus(JoybusError::NoDevice))
+        );
+    }
+}
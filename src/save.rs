@@ -0,0 +1,630 @@
+use crate::joybus::JoybusError;
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum SaveError {
+    Joybus(JoybusError),
+    NotPresent,
+    OutOfRange,
+    Busy,
+    Verify,
+    Failed,
+    Empty,
+    Version(u16),
+}
+
+impl From<JoybusError> for SaveError {
+    #[inline]
+    fn from(value: JoybusError) -> Self {
+        Self::Joybus(value)
+    }
+}
+
+// Raw access to a save chip in units of its natural block size. Offsets and
+// buffer lengths passed to the block functions are always block aligned.
+pub trait SaveMedia {
+    fn capacity(&self) -> usize;
+    fn block_size(&self) -> usize;
+    // The smallest region a write can disturb. Chips that erase in larger
+    // units than they program override this.
+    #[inline]
+    fn erase_size(&self) -> usize {
+        self.block_size()
+    }
+    fn read_blocks(&mut self, block: usize, buf: &mut [u8]) -> Result<(), SaveError>;
+    fn write_blocks(&mut self, block: usize, buf: &[u8]) -> Result<(), SaveError>;
+}
+
+impl<M: SaveMedia + ?Sized> SaveMedia for &mut M {
+    #[inline]
+    fn capacity(&self) -> usize {
+        (**self).capacity()
+    }
+    #[inline]
+    fn block_size(&self) -> usize {
+        (**self).block_size()
+    }
+    #[inline]
+    fn erase_size(&self) -> usize {
+        (**self).erase_size()
+    }
+    #[inline]
+    fn read_blocks(&mut self, block: usize, buf: &mut [u8]) -> Result<(), SaveError> {
+        (**self).read_blocks(block, buf)
+    }
+    #[inline]
+    fn write_blocks(&mut self, block: usize, buf: &[u8]) -> Result<(), SaveError> {
+        (**self).write_blocks(block, buf)
+    }
+}
+
+const MAX_BLOCK_SIZE: usize = 128;
+
+pub struct Save<M> {
+    media: M,
+}
+
+impl<M: SaveMedia> Save<M> {
+    #[inline]
+    pub const fn new(media: M) -> Self {
+        Self { media }
+    }
+    #[inline]
+    pub fn media(&mut self) -> &mut M {
+        &mut self.media
+    }
+    #[inline]
+    pub fn into_inner(self) -> M {
+        self.media
+    }
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.media.capacity()
+    }
+    fn check_range(&self, offset: usize, len: usize) -> Result<(), SaveError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.media.capacity() => Ok(()),
+            _ => Err(SaveError::OutOfRange),
+        }
+    }
+    pub fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), SaveError> {
+        self.check_range(offset, buf.len())?;
+        let bs = self.media.block_size();
+        let mut block = [0; MAX_BLOCK_SIZE];
+        let mut pos = 0;
+        while pos < buf.len() {
+            let addr = offset + pos;
+            let in_block = addr % bs;
+            let n = (bs - in_block).min(buf.len() - pos);
+            if in_block == 0 && n == bs {
+                let n = (buf.len() - pos) / bs * bs;
+                self.media.read_blocks(addr / bs, &mut buf[pos..pos + n])?;
+                pos += n;
+                continue;
+            }
+            self.media.read_blocks(addr / bs, &mut block[..bs])?;
+            buf[pos..pos + n].copy_from_slice(&block[in_block..in_block + n]);
+            pos += n;
+        }
+        Ok(())
+    }
+    pub fn write(&mut self, offset: usize, buf: &[u8]) -> Result<(), SaveError> {
+        self.check_range(offset, buf.len())?;
+        let bs = self.media.block_size();
+        let mut block = [0; MAX_BLOCK_SIZE];
+        let mut pos = 0;
+        while pos < buf.len() {
+            let addr = offset + pos;
+            let in_block = addr % bs;
+            let n = (bs - in_block).min(buf.len() - pos);
+            if in_block == 0 && n == bs {
+                let n = (buf.len() - pos) / bs * bs;
+                self.media.write_blocks(addr / bs, &buf[pos..pos + n])?;
+                pos += n;
+                continue;
+            }
+            self.media.read_blocks(addr / bs, &mut block[..bs])?;
+            block[in_block..in_block + n].copy_from_slice(&buf[pos..pos + n]);
+            self.media.write_blocks(addr / bs, &block[..bs])?;
+            pos += n;
+        }
+        Ok(())
+    }
+    pub fn verify(&mut self, offset: usize, buf: &[u8]) -> Result<(), SaveError> {
+        self.check_range(offset, buf.len())?;
+        let mut check = [0; MAX_BLOCK_SIZE];
+        for (i, chunk) in buf.chunks(MAX_BLOCK_SIZE).enumerate() {
+            let check = &mut check[..chunk.len()];
+            self.read(offset + i * MAX_BLOCK_SIZE, check)?;
+            if check != chunk {
+                return Err(SaveError::Verify);
+            }
+        }
+        Ok(())
+    }
+    pub fn write_verify(&mut self, offset: usize, buf: &[u8]) -> Result<(), SaveError> {
+        self.write(offset, buf)?;
+        self.verify(offset, buf)
+    }
+}
+
+pub const SLOT_MAGIC: u32 = 0x4E363453;
+pub const SLOT_HEADER_SIZE: usize = 20;
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+const fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    let mut i = 0;
+    while i < data.len() {
+        crc = CRC32_TABLE[((crc ^ data[i] as u32) & 0xFF) as usize] ^ (crc >> 8);
+        i += 1;
+    }
+    crc
+}
+
+#[inline]
+pub const fn crc32(data: &[u8]) -> u32 {
+    !crc32_update(!0, data)
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct SlotHeader {
+    pub version: u16,
+    pub sequence: u32,
+    pub len: u32,
+    pub crc: u32,
+}
+
+impl SlotHeader {
+    pub fn to_bytes(&self) -> [u8; SLOT_HEADER_SIZE] {
+        let mut b = [0; SLOT_HEADER_SIZE];
+        b[0..4].copy_from_slice(&SLOT_MAGIC.to_be_bytes());
+        b[4..6].copy_from_slice(&self.version.to_be_bytes());
+        b[8..12].copy_from_slice(&self.sequence.to_be_bytes());
+        b[12..16].copy_from_slice(&self.len.to_be_bytes());
+        b[16..20].copy_from_slice(&self.crc.to_be_bytes());
+        b
+    }
+    pub fn from_bytes(b: &[u8; SLOT_HEADER_SIZE]) -> Option<Self> {
+        let word = |i: usize| u32::from_be_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+        if word(0) != SLOT_MAGIC {
+            return None;
+        }
+        Some(Self {
+            version: u16::from_be_bytes([b[4], b[5]]),
+            sequence: word(8),
+            len: word(12),
+            crc: word(16),
+        })
+    }
+    // The CRC covers everything in the header before it as well as the data.
+    fn crc_start(&self) -> u32 {
+        crc32_update(!0, &self.to_bytes()[..16])
+    }
+    #[inline]
+    const fn newer_than(&self, other: &Self) -> bool {
+        (self.sequence.wrapping_sub(other.sequence) as i32) > 0
+    }
+}
+
+// Fixed size slots, each kept as two copies that are written alternately, so
+// that an interrupted commit always leaves the previous save readable. Copies
+// never share an erase unit, so slot counts the media can't fit are rejected.
+pub struct SaveSlots<M> {
+    save: Save<M>,
+    slots: usize,
+    copy_size: usize,
+    version: u16,
+}
+
+impl<M: SaveMedia> SaveSlots<M> {
+    pub fn new(media: M, slots: usize, version: u16) -> Result<Self, SaveError> {
+        let unit = media.erase_size();
+        let copy_size = match slots {
+            0 => 0,
+            _ => media.capacity() / (slots * 2) / unit * unit,
+        };
+        if copy_size <= SLOT_HEADER_SIZE {
+            return Err(SaveError::OutOfRange);
+        }
+        Ok(Self {
+            save: Save::new(media),
+            slots,
+            copy_size,
+            version,
+        })
+    }
+    #[inline]
+    pub fn save(&mut self) -> &mut Save<M> {
+        &mut self.save
+    }
+    #[inline]
+    pub fn into_inner(self) -> M {
+        self.save.into_inner()
+    }
+    #[inline]
+    pub const fn slots(&self) -> usize {
+        self.slots
+    }
+    #[inline]
+    pub const fn version(&self) -> u16 {
+        self.version
+    }
+    #[inline]
+    pub const fn slot_capacity(&self) -> usize {
+        self.copy_size - SLOT_HEADER_SIZE
+    }
+    #[inline]
+    const fn copy_offset(&self, slot: usize, copy: usize) -> usize {
+        (slot * 2 + copy) * self.copy_size
+    }
+    fn check_copy(&mut self, slot: usize, copy: usize) -> Result<Option<SlotHeader>, SaveError> {
+        let offset = self.copy_offset(slot, copy);
+        let mut header = [0; SLOT_HEADER_SIZE];
+        self.save.read(offset, &mut header)?;
+        let Some(header) = SlotHeader::from_bytes(&header) else {
+            return Ok(None);
+        };
+        if header.len as usize > self.slot_capacity() {
+            return Ok(None);
+        }
+        let mut crc = header.crc_start();
+        let mut chunk = [0; MAX_BLOCK_SIZE];
+        let mut pos = 0;
+        while pos < header.len as usize {
+            let n = (header.len as usize - pos).min(MAX_BLOCK_SIZE);
+            self.save
+                .read(offset + SLOT_HEADER_SIZE + pos, &mut chunk[..n])?;
+            crc = crc32_update(crc, &chunk[..n]);
+            pos += n;
+        }
+        Ok((!crc == header.crc).then_some(header))
+    }
+    fn latest(&mut self, slot: usize) -> Result<Option<(usize, SlotHeader)>, SaveError> {
+        if slot >= self.slots {
+            return Err(SaveError::OutOfRange);
+        }
+        let a = self.check_copy(slot, 0)?;
+        let b = self.check_copy(slot, 1)?;
+        Ok(match (a, b) {
+            (Some(a), Some(b)) if b.newer_than(&a) => Some((1, b)),
+            (Some(a), _) => Some((0, a)),
+            (None, Some(b)) => Some((1, b)),
+            (None, None) => None,
+        })
+    }
+    #[inline]
+    pub fn info(&mut self, slot: usize) -> Result<Option<SlotHeader>, SaveError> {
+        Ok(self.latest(slot)?.map(|(_, header)| header))
+    }
+    #[inline]
+    pub fn load(&mut self, slot: usize, buf: &mut [u8]) -> Result<usize, SaveError> {
+        self.load_migrate(slot, buf, |version, _, _| Err(SaveError::Version(version)))
+    }
+    // Data saved by an older schema is passed through `migrate` one version
+    // at a time; it upgrades the data in place and returns the new length.
+    pub fn load_migrate(
+        &mut self,
+        slot: usize,
+        buf: &mut [u8],
+        mut migrate: impl FnMut(u16, &mut [u8], usize) -> Result<usize, SaveError>,
+    ) -> Result<usize, SaveError> {
+        let (copy, header) = self.latest(slot)?.ok_or(SaveError::Empty)?;
+        if header.version > self.version {
+            return Err(SaveError::Version(header.version));
+        }
+        let mut len = header.len as usize;
+        if len > buf.len() {
+            return Err(SaveError::OutOfRange);
+        }
+        let offset = self.copy_offset(slot, copy) + SLOT_HEADER_SIZE;
+        self.save.read(offset, &mut buf[..len])?;
+        for version in header.version..self.version {
+            len = migrate(version, buf, len)?;
+            if len > buf.len() {
+                return Err(SaveError::OutOfRange);
+            }
+        }
+        Ok(len)
+    }
+    pub fn store(&mut self, slot: usize, data: &[u8]) -> Result<(), SaveError> {
+        if data.len() > self.slot_capacity() {
+            return Err(SaveError::OutOfRange);
+        }
+        let (copy, sequence) = match self.latest(slot)? {
+            Some((copy, header)) => (copy ^ 1, header.sequence.wrapping_add(1)),
+            None => (0, 1),
+        };
+        let mut header = SlotHeader {
+            version: self.version,
+            sequence,
+            len: data.len() as u32,
+            crc: 0,
+        };
+        header.crc = !crc32_update(header.crc_start(), data);
+        let offset = self.copy_offset(slot, copy);
+        self.save.write_verify(offset + SLOT_HEADER_SIZE, data)?;
+        self.save.write_verify(offset, &header.to_bytes())
+    }
+    pub fn erase(&mut self, slot: usize) -> Result<(), SaveError> {
+        if slot >= self.slots {
+            return Err(SaveError::OutOfRange);
+        }
+        for copy in 0..2 {
+            let offset = self.copy_offset(slot, copy);
+            self.save.write(offset, &[0; SLOT_HEADER_SIZE])?;
+        }
+        Ok(())
+    }
+}
+
+impl SaveMedia for [u8] {
+    #[inline]
+    fn capacity(&self) -> usize {
+        self.len()
+    }
+    #[inline]
+    fn block_size(&self) -> usize {
+        8
+    }
+    fn read_blocks(&mut self, block: usize, buf: &mut [u8]) -> Result<(), SaveError> {
+        let start = block * 8;
+        let src = self
+            .get(start..start + buf.len())
+            .ok_or(SaveError::OutOfRange)?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+    fn write_blocks(&mut self, block: usize, buf: &[u8]) -> Result<(), SaveError> {
+        let start = block * 8;
+        let dst = self
+            .get_mut(start..start + buf.len())
+            .ok_or(SaveError::OutOfRange)?;
+        dst.copy_from_slice(buf);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{vec, vec::Vec};
+
+    const FLASH_PAGE: usize = 128;
+    const FLASH_SECTOR: usize = 0x4000;
+
+    // FlashRAM-like media where every page write rewrites its whole sector,
+    // and a power cut during a write leaves the sector erased.
+    struct Flash {
+        data: Vec<u8>,
+        sectors: Vec<usize>,
+        writes_left: Option<usize>,
+    }
+
+    impl Flash {
+        fn new() -> Self {
+            Self {
+                data: vec![0xFF; 0x20000],
+                sectors: Vec::new(),
+                writes_left: None,
+            }
+        }
+    }
+
+    impl SaveMedia for Flash {
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+        fn block_size(&self) -> usize {
+            FLASH_PAGE
+        }
+        fn erase_size(&self) -> usize {
+            FLASH_SECTOR
+        }
+        fn read_blocks(&mut self, block: usize, buf: &mut [u8]) -> Result<(), SaveError> {
+            let start = block * FLASH_PAGE;
+            buf.copy_from_slice(&self.data[start..start + buf.len()]);
+            Ok(())
+        }
+        fn write_blocks(&mut self, block: usize, buf: &[u8]) -> Result<(), SaveError> {
+            for (i, page) in buf.chunks_exact(FLASH_PAGE).enumerate() {
+                let start = (block + i) * FLASH_PAGE;
+                let sector = start / FLASH_SECTOR;
+                self.sectors.push(sector);
+                if let Some(left) = &mut self.writes_left {
+                    if *left == 0 {
+                        self.data[sector * FLASH_SECTOR..(sector + 1) * FLASH_SECTOR].fill(0xFF);
+                        return Err(SaveError::Failed);
+                    }
+                    *left -= 1;
+                }
+                self.data[start..start + FLASH_PAGE].copy_from_slice(page);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn unaligned_access() {
+        let mut image = vec![0u8; 256];
+        let mut save = Save::new(&mut image[..]);
+        let data: Vec<u8> = (1..=45).collect();
+        save.write(3, &data).unwrap();
+        let mut out = vec![0; 45];
+        save.read(3, &mut out).unwrap();
+        assert_eq!(out, data);
+        save.verify(3, &data).unwrap();
+        assert_eq!(save.verify(4, &data), Err(SaveError::Verify));
+        assert_eq!(save.write(250, &data), Err(SaveError::OutOfRange));
+        assert_eq!(save.read(usize::MAX, &mut out), Err(SaveError::OutOfRange));
+        assert_eq!(image[..3], [0; 3]);
+        assert_eq!(image[3..48], data);
+        assert_eq!(image[48..], [0; 208]);
+    }
+
+    #[test]
+    fn slot_layout() {
+        let mut image = vec![0u8; 512];
+        let slots = SaveSlots::new(&mut image[..], 3, 1).unwrap();
+        assert_eq!(slots.slot_capacity(), 80 - SLOT_HEADER_SIZE);
+        assert_eq!(
+            SaveSlots::new(&mut image[..], 0, 1).err(),
+            Some(SaveError::OutOfRange)
+        );
+        assert_eq!(
+            SaveSlots::new(&mut image[..], 16, 1).err(),
+            Some(SaveError::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn flash_sectors() {
+        let slots = SaveSlots::new(Flash::new(), 4, 1).unwrap();
+        assert_eq!(slots.slot_capacity(), FLASH_SECTOR - SLOT_HEADER_SIZE);
+        // Two copies of five slots can't each get a sector of their own.
+        assert_eq!(
+            SaveSlots::new(Flash::new(), 5, 1).err(),
+            Some(SaveError::OutOfRange)
+        );
+
+        let mut slots = SaveSlots::new(Flash::new(), 4, 1).unwrap();
+        for i in 0..4 {
+            slots.store(3, &[i; 300]).unwrap();
+        }
+        let flash = slots.into_inner();
+        assert!(flash.sectors.iter().all(|&s| s == 6 || s == 7));
+    }
+
+    #[test]
+    fn store_and_load() {
+        let mut image = vec![0u8; 1024];
+        let mut slots = SaveSlots::new(&mut image[..], 2, 1).unwrap();
+        let mut buf = [0; 64];
+        assert_eq!(slots.load(0, &mut buf), Err(SaveError::Empty));
+        assert_eq!(slots.info(2), Err(SaveError::OutOfRange));
+
+        slots.store(0, b"first").unwrap();
+        slots.store(1, b"other").unwrap();
+        slots.store(0, b"second").unwrap();
+        assert_eq!(slots.load(0, &mut buf), Ok(6));
+        assert_eq!(&buf[..6], b"second");
+        assert_eq!(slots.info(0).unwrap().unwrap().sequence, 2);
+        assert_eq!(slots.load(1, &mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"other");
+        assert_eq!(slots.load(0, &mut buf[..2]), Err(SaveError::OutOfRange));
+
+        slots.erase(0).unwrap();
+        assert_eq!(slots.load(0, &mut buf), Err(SaveError::Empty));
+        assert_eq!(slots.load(1, &mut buf), Ok(5));
+
+        let too_big = vec![0; slots.slot_capacity() + 1];
+        assert_eq!(slots.store(1, &too_big), Err(SaveError::OutOfRange));
+    }
+
+    #[test]
+    fn sequence_wrap() {
+        let mut image = vec![0u8; 256];
+        let mut slots = SaveSlots::new(&mut image[..], 1, 1).unwrap();
+        let mut header = SlotHeader {
+            version: 1,
+            sequence: u32::MAX,
+            len: 1,
+            crc: 0,
+        };
+        header.crc = !crc32_update(header.crc_start(), b"a");
+        slots.save().write(SLOT_HEADER_SIZE, b"a").unwrap();
+        slots.save().write(0, &header.to_bytes()).unwrap();
+        slots.store(0, b"b").unwrap();
+        let mut buf = [0; 1];
+        slots.load(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"b");
+        assert_eq!(slots.info(0).unwrap().unwrap().sequence, 0);
+    }
+
+    #[test]
+    fn corrupt_copy() {
+        let mut image = vec![0u8; 256];
+        {
+            let mut slots = SaveSlots::new(&mut image[..], 1, 1).unwrap();
+            slots.store(0, b"old").unwrap();
+            slots.store(0, b"new").unwrap();
+        }
+        // The newer copy lives in the second half.
+        image[128 + SLOT_HEADER_SIZE] ^= 1;
+        let mut slots = SaveSlots::new(&mut image[..], 1, 1).unwrap();
+        let mut buf = [0; 3];
+        slots.load(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"old");
+    }
+
+    #[test]
+    fn interrupted_commit() {
+        let mut slots = SaveSlots::new(Flash::new(), 4, 1).unwrap();
+        slots.store(0, b"first").unwrap();
+        slots.store(1, b"other").unwrap();
+        slots.save().media().writes_left = Some(0);
+        assert_eq!(slots.store(0, b"second"), Err(SaveError::Failed));
+        slots.save().media().writes_left = None;
+
+        let mut buf = [0; 16];
+        assert_eq!(slots.load(0, &mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"first");
+        assert_eq!(slots.load(1, &mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"other");
+        slots.store(0, b"second").unwrap();
+        assert_eq!(slots.load(0, &mut buf), Ok(6));
+    }
+
+    #[test]
+    fn migrate() {
+        let mut image = vec![0u8; 256];
+        SaveSlots::new(&mut image[..], 1, 1)
+            .unwrap()
+            .store(0, b"v1")
+            .unwrap();
+        let mut slots = SaveSlots::new(&mut image[..], 1, 3).unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(slots.load(0, &mut buf), Err(SaveError::Version(1)));
+        let mut steps = Vec::new();
+        let len = slots
+            .load_migrate(0, &mut buf, |version, data, len| {
+                steps.push(version);
+                data[len] = b'0' + version as u8 + 1;
+                Ok(len + 1)
+            })
+            .unwrap();
+        assert_eq!(steps, [1, 2]);
+        assert_eq!(&buf[..len], b"v123");
+        assert_eq!(
+            slots.load_migrate(0, &mut buf[..3], |_, _, len| Ok(len + 1)),
+            Err(SaveError::OutOfRange)
+        );
+
+        slots.store(0, b"v3").unwrap();
+        let mut slots = SaveSlots::new(&mut image[..], 1, 2).unwrap();
+        assert_eq!(slots.load(0, &mut buf), Err(SaveError::Version(3)));
+    }
+}
@@ -0,0 +1,71 @@
+use core::ptr::NonNull;
+
+use crate::{
+    joybus::{Bus, PIF_RAM_SIZE, PifBlock},
+    system::{self, PhysAddr},
+};
+
+const SI_REGS: NonNull<u32> = unsafe { NonNull::new_unchecked(0xA4800000 as *mut u32) };
+const SI_DRAM_ADDR_REG: usize = 0;
+const SI_PIF_AD_RD64B_REG: usize = 1;
+const SI_PIF_AD_WR64B_REG: usize = 4;
+const SI_STATUS_REG: usize = 6;
+
+const SI_STATUS_DMA_BUSY: u32 = 1 << 0;
+const SI_STATUS_IO_BUSY: u32 = 1 << 1;
+
+pub const PIF_RAM: PhysAddr = 0x1FC007C0;
+
+#[inline(always)]
+fn wait() {
+    loop {
+        let status = unsafe { SI_REGS.add(SI_STATUS_REG).read_volatile() };
+        if status & (SI_STATUS_DMA_BUSY | SI_STATUS_IO_BUSY) == 0 {
+            break;
+        }
+    }
+}
+
+pub fn pif_write(data: &[u8; PIF_RAM_SIZE]) {
+    system::data_cache_hit_writeback(data);
+    let addr = system::physical_addr(NonNull::from(data));
+    unsafe {
+        wait();
+        SI_REGS.add(SI_DRAM_ADDR_REG).write_volatile(addr);
+        SI_REGS.add(SI_PIF_AD_WR64B_REG).write_volatile(PIF_RAM);
+        wait();
+        SI_REGS.add(SI_STATUS_REG).write_volatile(0);
+    }
+}
+
+pub fn pif_read(data: &mut [u8; PIF_RAM_SIZE]) {
+    system::data_cache_hit_writeback_invalidate(data);
+    let addr = system::physical_addr(NonNull::from(&mut *data));
+    unsafe {
+        wait();
+        SI_REGS.add(SI_DRAM_ADDR_REG).write_volatile(addr);
+        SI_REGS.add(SI_PIF_AD_RD64B_REG).write_volatile(PIF_RAM);
+        wait();
+        SI_REGS.add(SI_STATUS_REG).write_volatile(0);
+    }
+    system::data_cache_hit_invalidate(data);
+}
+
+// Runs a finished command block through the PIF and reads back the responses
+// into the same block.
+pub fn exchange(block: &mut PifBlock) {
+    let status = system::interrupts_disable();
+    pif_write(block.as_bytes());
+    pif_read(block.as_bytes_mut());
+    system::interrupts_restore(status);
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct Si;
+
+impl Bus for Si {
+    #[inline]
+    fn exchange(&mut self, block: &mut PifBlock) {
+        exchange(block);
+    }
+}
@@ -0,0 +1,117 @@
+use crate::{
+    pi::{self, CART_DOM2_ADDR2, DmaBuffer, DomainTiming},
+    save::{SaveError, SaveMedia},
+    system::PhysAddr,
+};
+
+pub const BLOCK_SIZE: usize = 16;
+pub const BANK_SIZE: usize = 0x8000;
+const BANK_STRIDE: PhysAddr = 0x40000;
+const DMA_CHUNK: usize = 128;
+
+const SRAM_TIMING: DomainTiming = DomainTiming {
+    latency: 0x05,
+    pulse_width: 0x0C,
+    page_size: 0x0D,
+    release: 0x02,
+};
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum SramType {
+    Sram256K,
+    Sram768K,
+}
+
+impl SramType {
+    #[inline]
+    pub const fn banks(self) -> usize {
+        match self {
+            Self::Sram256K => 1,
+            Self::Sram768K => 3,
+        }
+    }
+    #[inline]
+    pub const fn size(self) -> usize {
+        self.banks() * BANK_SIZE
+    }
+}
+
+// The 96 KiB carts are three 32 KiB chips, each decoded at its own 256 KiB
+// window of domain 2.
+#[inline]
+const fn bank_addr(offset: usize) -> PhysAddr {
+    CART_DOM2_ADDR2
+        + (offset / BANK_SIZE) as PhysAddr * BANK_STRIDE
+        + (offset % BANK_SIZE) as PhysAddr
+}
+
+pub struct Sram {
+    ty: SramType,
+}
+
+impl Sram {
+    pub fn new(ty: SramType) -> Self {
+        pi::set_dom2_timing(SRAM_TIMING);
+        Self { ty }
+    }
+    #[inline]
+    pub const fn sram_type(&self) -> SramType {
+        self.ty
+    }
+    fn check_range(&self, offset: usize, len: usize) -> Result<(), SaveError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.ty.size() => Ok(()),
+            _ => Err(SaveError::OutOfRange),
+        }
+    }
+    pub fn read(&mut self, offset: usize, buf: &mut [u8]) -> Result<(), SaveError> {
+        self.check_range(offset, buf.len())?;
+        let mut dma = DmaBuffer::<DMA_CHUNK>::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            let addr = offset + pos;
+            let n = (DMA_CHUNK - addr % DMA_CHUNK).min(buf.len() - pos);
+            let start = addr % 2;
+            let len = (start + n + 1) & !1;
+            pi::dma_read(bank_addr(addr - start), &mut dma.0[..len]);
+            buf[pos..pos + n].copy_from_slice(&dma.0[start..start + n]);
+            pos += n;
+        }
+        Ok(())
+    }
+    pub fn write(&mut self, offset: usize, buf: &[u8]) -> Result<(), SaveError> {
+        self.check_range(offset, buf.len())?;
+        if !offset.is_multiple_of(2) || !buf.len().is_multiple_of(2) {
+            return Err(SaveError::OutOfRange);
+        }
+        let mut dma = DmaBuffer::<DMA_CHUNK>::new();
+        let mut pos = 0;
+        while pos < buf.len() {
+            let addr = offset + pos;
+            let n = (DMA_CHUNK - addr % DMA_CHUNK).min(buf.len() - pos);
+            dma.0[..n].copy_from_slice(&buf[pos..pos + n]);
+            pi::dma_write(bank_addr(addr), &dma.0[..n]);
+            pos += n;
+        }
+        Ok(())
+    }
+}
+
+impl SaveMedia for Sram {
+    #[inline]
+    fn capacity(&self) -> usize {
+        self.ty.size()
+    }
+    #[inline]
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+    #[inline]
+    fn read_blocks(&mut self, block: usize, buf: &mut [u8]) -> Result<(), SaveError> {
+        self.read(block * BLOCK_SIZE, buf)
+    }
+    #[inline]
+    fn write_blocks(&mut self, block: usize, buf: &[u8]) -> Result<(), SaveError> {
+        self.write(block * BLOCK_SIZE, buf)
+    }
+}
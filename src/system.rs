@@ -1,7 +1,7 @@
 use core::{
     ffi::{c_char, c_int, c_uint, c_void},
     ptr::NonNull,
-    sync::atomic::{AtomicPtr, Ordering},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, Ordering},
 };
 
 core::arch::global_asm!(include_str!("kernel.S"), main = sym crate::main);
@@ -151,19 +151,524 @@ pub fn inst_cache_invalidate_all() {
     }
 }
 
+const IQUE_OS_MEM_SIZE: PhysAddr = 0x318;
+
+// The iQue OS only hands part of its 16 MiB to the application and reports
+// the size of that region where libultra expects osMemSize.
 #[inline]
 pub fn mem_size() -> u32 {
+    if is_ique() {
+        let size = unsafe { virtual_uncached_addr::<u32>(IQUE_OS_MEM_SIZE).read_volatile() };
+        if size != 0 {
+            return size;
+        }
+    }
     unsafe { _boot_memsize }
 }
 
 #[inline]
-pub fn tv_type() -> u8 {
-    unsafe { _boot_tvtype }
+pub fn cpu_frequency() -> u32 {
+    if is_ique() { 144_000_000 } else { 93_750_000 }
+}
+
+#[inline]
+pub fn rcp_frequency() -> u32 {
+    if is_ique() { 96_000_000 } else { 62_500_000 }
+}
+
+#[inline]
+pub fn ticks_per_second() -> u32 {
+    cpu_frequency() / 2
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+#[repr(u8)]
+pub enum TvType {
+    Pal = 0,
+    Ntsc = 1,
+    Mpal = 2,
+}
+
+#[inline]
+pub fn tv_type() -> TvType {
+    match unsafe { _boot_tvtype } {
+        1 => TvType::Ntsc,
+        2 => TvType::Mpal,
+        _ => TvType::Pal,
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum ConsoleType {
+    N64,
+    IQue,
+    Unknown(u8),
+}
+
+#[inline]
+pub fn console_type() -> ConsoleType {
+    match unsafe { _boot_consoletype } {
+        0 => ConsoleType::N64,
+        1 => ConsoleType::IQue,
+        ty => ConsoleType::Unknown(ty),
+    }
+}
+
+#[inline]
+pub fn is_ique() -> bool {
+    console_type() == ConsoleType::IQue
+}
+
+// The iQue VI needs a smaller pixel advance than the N64 to avoid artifacts.
+// It reports NTSC and takes the same timing registers, so nothing else in
+// the VI setup differs.
+#[inline]
+pub fn vi_pixel_advance() -> u8 {
+    if is_ique() { 2 } else { 3 }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum ResetType {
+    Cold,
+    Warm,
+}
+
+#[inline]
+pub fn reset_type() -> ResetType {
+    match unsafe { _boot_resettype } {
+        0 => ResetType::Cold,
+        _ => ResetType::Warm,
+    }
+}
+
+#[inline]
+pub fn is_warm_boot() -> bool {
+    reset_type() == ResetType::Warm
+}
+
+// True when this boot follows a reset button press that was seen by the
+// pre-NMI handler of the previous session.
+#[inline]
+pub fn reset_by_button() -> bool {
+    unsafe { _boot_prenmi != 0 }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum BootDevice {
+    Cartridge,
+    DiskDrive,
+}
+
+// Nintendo's IPL3 leaves the boot device, CIC seed and IPL version in
+// s3, s6 and s7 (the libultra convention). The libdragon IPL3 used by
+// `nust64 --libdragon` only passes boot flags in DMEM and leaves those
+// registers undefined, so they are only trusted when the device and seed
+// are both values the libultra IPL3 can hand over.
+fn ipl_registers() -> Option<(u8, u8, u8)> {
+    let (device, seed, version) = unsafe { (_boot_romtype, _boot_cicseed, _boot_version) };
+    let known = !matches!(Cic::from_seed(seed), Cic::Unknown(_));
+    (device <= 1 && known).then_some((device, seed, version))
+}
+
+#[inline]
+pub fn boot_device() -> Option<BootDevice> {
+    match ipl_registers()?.0 {
+        1 => Some(BootDevice::DiskDrive),
+        _ => Some(BootDevice::Cartridge),
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Cic {
+    X101X102,
+    X103,
+    X105,
+    X106,
+    X5101,
+    X8303,
+    Unknown(u8),
+}
+
+impl Cic {
+    #[inline]
+    pub const fn from_seed(seed: u8) -> Self {
+        match seed {
+            0x3F => Self::X101X102,
+            0x78 => Self::X103,
+            0x91 => Self::X105,
+            0x85 => Self::X106,
+            0xAC => Self::X5101,
+            0xDD => Self::X8303,
+            seed => Self::Unknown(seed),
+        }
+    }
+}
+
+#[inline]
+pub fn cic_seed() -> Option<u8> {
+    Some(ipl_registers()?.1)
 }
 
 #[inline]
-pub fn console_type() -> u8 {
-    unsafe { _boot_consoletype }
+pub fn ipl_version() -> Option<u8> {
+    Some(ipl_registers()?.2)
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct RomHeader {
+    pub pi_config: u32,
+    pub clock_rate: u32,
+    pub entrypoint: u32,
+    pub libultra_version: u32,
+    pub checksum: u64,
+    pub title: [u8; 20],
+    pub publisher: [u8; 2],
+    pub game_code: [u8; 4],
+    pub version: u8,
+}
+
+// The cartridge header has no maker code field of its own. Like other
+// homebrew tools, the first two reserved bytes before the game code are
+// used for it.
+impl RomHeader {
+    pub const SIZE: usize = 0x40;
+
+    pub const fn from_bytes(b: &[u8; Self::SIZE]) -> Self {
+        const fn word(b: &[u8; RomHeader::SIZE], i: usize) -> u32 {
+            u32::from_be_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]])
+        }
+        let mut title = [0; 20];
+        let mut i = 0;
+        while i < title.len() {
+            title[i] = b[0x20 + i];
+            i += 1;
+        }
+        Self {
+            pi_config: word(b, 0x00),
+            clock_rate: word(b, 0x04),
+            entrypoint: word(b, 0x08),
+            libultra_version: word(b, 0x0C),
+            checksum: ((word(b, 0x10) as u64) << 32) | word(b, 0x14) as u64,
+            title,
+            publisher: [b[0x38], b[0x39]],
+            game_code: [b[0x3B], b[0x3C], b[0x3D], b[0x3E]],
+            version: b[0x3F],
+        }
+    }
+    pub fn read() -> Self {
+        let mut b = [0; Self::SIZE];
+        for (i, chunk) in b.chunks_exact_mut(4).enumerate() {
+            let word = crate::pi::io_read(crate::pi::CART_DOM1_ADDR2 + i as u32 * 4);
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        Self::from_bytes(&b)
+    }
+    pub fn title(&self) -> &str {
+        let end = self
+            .title
+            .iter()
+            .rposition(|&c| c != b' ' && c != 0)
+            .map_or(0, |i| i + 1);
+        core::str::from_utf8(&self.title[..end]).unwrap_or("")
+    }
+    #[inline]
+    pub const fn category(&self) -> u8 {
+        self.game_code[0]
+    }
+    #[inline]
+    pub const fn id(&self) -> [u8; 2] {
+        [self.game_code[1], self.game_code[2]]
+    }
+    #[inline]
+    pub const fn region(&self) -> u8 {
+        self.game_code[3]
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct BootInfo {
+    pub mem_size: u32,
+    pub tv_type: TvType,
+    pub reset_type: ResetType,
+    pub console_type: ConsoleType,
+    // `None` unless booted by an IPL3 that passes these in registers.
+    pub boot_device: Option<BootDevice>,
+    pub cic: Option<Cic>,
+    pub cic_seed: Option<u8>,
+    pub ipl_version: Option<u8>,
+    pub reset_by_button: bool,
+    pub header: RomHeader,
+}
+
+impl BootInfo {
+    pub fn get() -> Self {
+        Self {
+            mem_size: mem_size(),
+            tv_type: tv_type(),
+            reset_type: reset_type(),
+            console_type: console_type(),
+            boot_device: boot_device(),
+            cic: cic_seed().map(Cic::from_seed),
+            cic_seed: cic_seed(),
+            ipl_version: ipl_version(),
+            reset_by_button: reset_by_button(),
+            header: RomHeader::read(),
+        }
+    }
+}
+
+pub const C0_STATUS_IE: u32 = 1 << 0;
+pub const C0_INTERRUPT_RCP: u32 = 1 << 10;
+pub const C0_INTERRUPT_CART: u32 = 1 << 11;
+pub const C0_INTERRUPT_PRENMI: u32 = 1 << 12;
+pub const C0_INTERRUPT_TIMER: u32 = 1 << 15;
+
+const MI_REGS: NonNull<u32> = unsafe { NonNull::new_unchecked(0xA4300000 as *mut u32) };
+const MI_INTERRUPT_REG: usize = 2;
+const MI_MASK_REG: usize = 3;
+
+pub const MI_INTERRUPT_SP: u32 = 1 << 0;
+pub const MI_INTERRUPT_SI: u32 = 1 << 1;
+pub const MI_INTERRUPT_AI: u32 = 1 << 2;
+pub const MI_INTERRUPT_VI: u32 = 1 << 3;
+pub const MI_INTERRUPT_PI: u32 = 1 << 4;
+pub const MI_INTERRUPT_DP: u32 = 1 << 5;
+
+// The MI mask register takes a clear/set bit pair per interrupt.
+fn mi_mask_bits(mask: u32, set: bool) -> u32 {
+    (0..6)
+        .filter(|i| mask & (1 << i) != 0)
+        .fold(0, |bits, i| bits | (1 << (i * 2 + set as u32)))
+}
+
+#[inline]
+pub fn mi_interrupts() -> u32 {
+    unsafe { MI_REGS.add(MI_INTERRUPT_REG).read_volatile() }
+}
+
+pub fn mi_interrupt_enable(mask: u32) {
+    unsafe {
+        MI_REGS
+            .add(MI_MASK_REG)
+            .write_volatile(mi_mask_bits(mask, true))
+    };
+    interrupt_enable(C0_INTERRUPT_RCP);
+}
+
+pub fn mi_interrupt_disable(mask: u32) {
+    unsafe {
+        MI_REGS
+            .add(MI_MASK_REG)
+            .write_volatile(mi_mask_bits(mask, false))
+    };
+}
+
+#[inline(always)]
+pub fn c0_status() -> u32 {
+    let status: u32;
+    unsafe { core::arch::asm!("mfc0 {0}, $12", out(reg) status) };
+    status
+}
+
+/// Writes the COP0 status register.
+///
+/// # Safety
+///
+/// Changes the interrupt enable and mask bits, the exception level and the
+/// CPU mode, so the caller has to keep those consistent with running code.
+#[inline(always)]
+pub unsafe fn c0_write_status(status: u32) {
+    unsafe { core::arch::asm!("mtc0 {0}, $12", "nop", "nop", in(reg) status) };
+}
+
+#[inline(always)]
+pub fn c0_cause() -> u32 {
+    let cause: u32;
+    unsafe { core::arch::asm!("mfc0 {0}, $13", out(reg) cause) };
+    cause
+}
+
+#[inline(always)]
+pub fn c0_count() -> u32 {
+    let count: u32;
+    unsafe { core::arch::asm!("mfc0 {0}, $9", out(reg) count) };
+    count
+}
+
+#[inline]
+pub fn interrupts_disable() -> u32 {
+    let status = c0_status();
+    unsafe { c0_write_status(status & !C0_STATUS_IE) };
+    status
+}
+
+#[inline]
+pub fn interrupts_restore(status: u32) {
+    unsafe { c0_write_status((c0_status() & !C0_STATUS_IE) | (status & C0_STATUS_IE)) };
+}
+
+#[inline]
+pub fn interrupt_enable(mask: u32) {
+    let status = interrupts_disable();
+    unsafe { c0_write_status(status | mask | C0_STATUS_IE) };
+}
+
+#[inline]
+pub fn interrupt_disable(mask: u32) {
+    let status = interrupts_disable();
+    unsafe { c0_write_status(status & !mask) };
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct WallTime {
+    pub seconds: u32,
+    pub micros: u32,
+}
+
+impl WallTime {
+    #[inline]
+    pub const fn date_time(&self) -> crate::rtc::DateTime {
+        crate::rtc::DateTime::from_unix_time(self.seconds)
+    }
+}
+
+// Count wraps after ~91s on the N64 and ~60s on the iQue, so the clock is
+// rebased from the RTC well before that, provided it is read at least that
+// often.
+const WALL_CLOCK_RESYNC_SECS: u32 = 30;
+static WALL_CLOCK_VALID: AtomicBool = AtomicBool::new(false);
+static WALL_CLOCK_SECS: AtomicU32 = AtomicU32::new(0);
+static WALL_CLOCK_COUNT: AtomicU32 = AtomicU32::new(0);
+
+#[inline]
+fn rtc_unix_time() -> Option<u32> {
+    let mut rtc = crate::rtc::Rtc::new(crate::si::Si).ok()?;
+    rtc.read_time().ok().and_then(|time| time.unix_time())
+}
+
+fn wall_clock_set(seconds: u32, count: u32) {
+    let status = interrupts_disable();
+    WALL_CLOCK_SECS.store(seconds, Ordering::Relaxed);
+    WALL_CLOCK_COUNT.store(count, Ordering::Relaxed);
+    WALL_CLOCK_VALID.store(true, Ordering::Relaxed);
+    interrupts_restore(status);
+}
+
+// Waits for the RTC to tick over to the next second, so that Count can be
+// lined up with the start of a second. Takes up to a second.
+pub fn wall_clock_init() -> bool {
+    let Some(start) = rtc_unix_time() else {
+        return false;
+    };
+    let begin = c0_count();
+    let timeout = ticks_per_second() + ticks_per_second() / 4;
+    while c0_count().wrapping_sub(begin) < timeout {
+        let count = c0_count();
+        match rtc_unix_time() {
+            Some(now) if now != start => {
+                wall_clock_set(now, count);
+                return true;
+            }
+            Some(_) => {}
+            None => return false,
+        }
+    }
+    wall_clock_set(start, begin);
+    true
+}
+
+// Current time with the RTC providing whole seconds and Count everything
+// below. Returns None until `wall_clock_init` has found an RTC.
+pub fn wall_clock() -> Option<WallTime> {
+    if !WALL_CLOCK_VALID.load(Ordering::Relaxed) {
+        return None;
+    }
+    let tps = ticks_per_second();
+    let now = c0_count();
+    let mut base = WALL_CLOCK_COUNT.load(Ordering::Relaxed);
+    let mut seconds = WALL_CLOCK_SECS.load(Ordering::Relaxed);
+    let mut elapsed = now.wrapping_sub(base);
+    if elapsed >= WALL_CLOCK_RESYNC_SECS * tps {
+        let phase = elapsed % tps;
+        match rtc_unix_time() {
+            Some(rtc) => seconds = rtc,
+            None => seconds += elapsed / tps,
+        }
+        base = now.wrapping_sub(phase);
+        elapsed = phase;
+        wall_clock_set(seconds, base);
+    }
+    Some(WallTime {
+        seconds: seconds + elapsed / tps,
+        micros: ((elapsed % tps) as u64 * 1_000_000 / tps as u64) as u32,
+    })
+}
+
+const MAX_RESET_HANDLERS: usize = 8;
+const RESET_MARKER: u32 = 0x524E4D49;
+static RESET_HANDLERS: [AtomicPtr<()>; MAX_RESET_HANDLERS] =
+    [const { AtomicPtr::new(core::ptr::null_mut()) }; MAX_RESET_HANDLERS];
+static RESET_PENDING: AtomicBool = AtomicBool::new(false);
+
+// Registers a callback to run in the ~0.5s between the reset button being
+// pressed and the NMI. Callbacks run in interrupt context, in registration
+// order, and must not block.
+pub fn on_reset(callback: fn()) {
+    let Some(slot) = RESET_HANDLERS
+        .iter()
+        .find(|slot| slot.load(Ordering::Relaxed).is_null())
+    else {
+        panic!("too many reset handlers");
+    };
+    slot.store(callback as *mut (), Ordering::Relaxed);
+    if !reset_pending() {
+        interrupt_enable(C0_INTERRUPT_PRENMI);
+    }
+}
+
+#[inline]
+pub fn reset_pending() -> bool {
+    RESET_PENDING.load(Ordering::Relaxed)
+}
+
+fn handle_prenmi() {
+    // The pre-NMI line stays asserted until the reset, so it must be masked
+    // to keep it from firing again as soon as the handler returns.
+    unsafe { c0_write_status(c0_status() & !C0_INTERRUPT_PRENMI) };
+    if RESET_PENDING.load(Ordering::Relaxed) {
+        return;
+    }
+    RESET_PENDING.store(true, Ordering::Relaxed);
+    unsafe {
+        let marker = NonNull::new_unchecked(&raw mut _reset_marker);
+        uncached_addr(marker).as_ptr().write_volatile(RESET_MARKER);
+    }
+    for slot in &RESET_HANDLERS {
+        let callback = slot.load(Ordering::Relaxed);
+        if callback.is_null() {
+            break;
+        }
+        let callback: fn() = unsafe { core::mem::transmute(callback) };
+        callback();
+    }
+}
+
+#[unsafe(no_mangle)]
+extern "C" fn _exception_dispatch(cause: u32, epc: u32) {
+    let code = (cause >> 2) & 0x1F;
+    if code != 0 {
+        panic!("unhandled exception {code} at {epc:#010X} (cause {cause:#010X})");
+    }
+    let pending = cause & c0_status() & 0xFF00;
+    if pending & C0_INTERRUPT_PRENMI != 0 {
+        handle_prenmi();
+    }
+    if pending & C0_INTERRUPT_RCP != 0 {
+        let mi = mi_interrupts() & unsafe { MI_REGS.add(MI_MASK_REG).read_volatile() };
+        if mi & MI_INTERRUPT_AI != 0 {
+            crate::ai::handle_interrupt();
+        }
+    }
 }
 
 const MIN_ALIGN: usize = size_of::<*const ()>() * 2;
@@ -233,6 +738,7 @@ unsafe impl core::alloc::GlobalAlloc for SystemAlloc {
 
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo<'_>) -> ! {
+    crate::crashlog::record_crash(format_args!("{_info}"));
     crate::println!("{_info}");
     loop {}
 }
@@ -241,7 +747,7 @@ fn panic(_info: &core::panic::PanicInfo<'_>) -> ! {
 extern "C" fn sbrk(incr: c_int) -> *mut c_void {
     let mut end = HEAP_END.load(Ordering::Relaxed);
     let heap_size = mem_size() as usize - STACK_SIZE;
-    let start = unsafe { __bss_end.as_ptr() as *mut u8 };
+    let start = unsafe { __heap_start.as_ptr() as *mut u8 };
     if end.is_null() {
         end = start;
     }
@@ -266,10 +772,16 @@ extern "C" fn getpid() -> c_int {
 }
 
 unsafe extern "C" {
-    static __bss_end: [c_char; 0usize];
+    static __heap_start: [c_char; 0usize];
     static _boot_memsize: u32;
     static _boot_tvtype: u8;
+    static _boot_resettype: u8;
+    static _boot_prenmi: u8;
+    static mut _reset_marker: u32;
     static _boot_consoletype: u8;
+    static _boot_romtype: u8;
+    static _boot_cicseed: u8;
+    static _boot_version: u8;
     fn _start();
     fn free(_: *mut c_void);
     fn aligned_alloc(_: c_uint, _: c_uint) -> *mut c_void;
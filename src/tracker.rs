@@ -0,0 +1,1451 @@
+use alloc::{rc::Rc, vec, vec::Vec};
+
+use crate::mixer::{Adsr, Mixer, Sample, VOLUME_MAX};
+
+const NOTE_KEY_OFF: u8 = 97;
+const NOTES: usize = 96;
+const MAX_VOLUME: i32 = 64;
+const ENVELOPE_POINTS: usize = 12;
+const FADEOUT_MAX: u32 = 0x10000;
+// Note 48 (C-4) plays a sample at its base rate of 8363Hz, which is Amiga
+// period 428 and linear period 4608.
+const BASE_RATE: u64 = 8363;
+const AMIGA_C4_PERIOD: u64 = 428;
+const AMIGA_NOTE0_PERIOD: u64 = AMIGA_C4_PERIOD << 4;
+const LINEAR_NOTE0_PERIOD: i32 = 7680;
+const LINEAR_C4_PERIOD: i32 = 4608;
+const MIN_PERIOD: i32 = 1;
+const MAX_PERIOD: i32 = 0x7FFF;
+
+const EXP2_SEMITONE: [u64; 12] = [
+    65536, 69433, 73562, 77936, 82570, 87480, 92682, 98193, 104032, 110218, 116772, 123715,
+];
+const EXP2_FINE: [u64; 64] = [
+    65536, 65595, 65654, 65714, 65773, 65832, 65892, 65951, 66011, 66071, 66130, 66190, 66250,
+    66309, 66369, 66429, 66489, 66549, 66609, 66670, 66730, 66790, 66850, 66911, 66971, 67032,
+    67092, 67153, 67213, 67274, 67335, 67395, 67456, 67517, 67578, 67639, 67700, 67761, 67823,
+    67884, 67945, 68007, 68068, 68129, 68191, 68252, 68314, 68376, 68438, 68499, 68561, 68623,
+    68685, 68747, 68809, 68871, 68933, 68996, 69058, 69120, 69183, 69245, 69308, 69370,
+];
+const VIBRATO_SINE: [i32; 32] = [
+    0, 24, 49, 74, 97, 120, 141, 161, 180, 197, 212, 224, 235, 244, 250, 253, 255, 253, 250, 244,
+    235, 224, 212, 197, 180, 161, 141, 120, 97, 74, 49, 24,
+];
+
+// 2^(x/768) in 16.16 fixed point, where 768 is an octave of linear periods.
+fn exp2_q16(x: i32) -> u64 {
+    let octave = x.div_euclid(768);
+    let rem = x.rem_euclid(768) as usize;
+    let v = (EXP2_SEMITONE[rem / 64] * EXP2_FINE[rem % 64]) >> 16;
+    if octave >= 0 {
+        v << octave.min(16)
+    } else {
+        v >> (-octave).min(63)
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum ModuleError {
+    Truncated,
+    Unsupported,
+    Invalid,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum ModuleKind {
+    Mod,
+    Xm,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct Cell {
+    // 0 for none, 1..=96 from C-0 and 97 for key off.
+    pub note: u8,
+    pub instrument: u8,
+    pub volume: u8,
+    pub effect: u8,
+    pub param: u8,
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Pattern {
+    pub rows: usize,
+    pub cells: Vec<Cell>,
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct SampleInfo {
+    pub sample: Option<Sample>,
+    pub volume: u8,
+    pub finetune: i8,
+    pub panning: u8,
+    pub relative_note: i8,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct Envelope {
+    pub points: [(u16, u8); ENVELOPE_POINTS],
+    pub len: u8,
+    pub sustain: Option<u8>,
+    pub looping: Option<(u8, u8)>,
+}
+
+impl Envelope {
+    pub fn value(&self, tick: u16) -> i32 {
+        let points = &self.points[..self.len as usize];
+        let Some(&(_, first)) = points.first() else {
+            return MAX_VOLUME;
+        };
+        let mut value = first as i32;
+        for pair in points.windows(2) {
+            let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+            if tick < x0 {
+                break;
+            }
+            value = if tick >= x1 || x1 == x0 {
+                y1 as i32
+            } else {
+                y0 as i32 + (y1 as i32 - y0 as i32) * (tick - x0) as i32 / (x1 - x0) as i32
+            };
+        }
+        value
+    }
+    #[inline]
+    fn point(&self, index: u8) -> u16 {
+        self.points[(index as usize).min(ENVELOPE_POINTS - 1)].0
+    }
+    fn advance(&self, tick: u16, key_on: bool) -> u16 {
+        if key_on && self.sustain.is_some_and(|point| tick == self.point(point)) {
+            return tick;
+        }
+        let next = tick.saturating_add(1);
+        match self.looping {
+            Some((start, end)) if next >= self.point(end) => self.point(start),
+            _ => next,
+        }
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Instrument {
+    pub samples: Vec<SampleInfo>,
+    pub keymap: [u8; NOTES],
+    pub volume_envelope: Option<Envelope>,
+    pub fadeout: u16,
+}
+
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub struct Module {
+    pub kind: ModuleKind,
+    pub channels: usize,
+    pub orders: Vec<u8>,
+    pub restart: usize,
+    pub patterns: Vec<Pattern>,
+    pub instruments: Vec<Instrument>,
+    pub speed: u8,
+    pub tempo: u8,
+    pub linear: bool,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    #[inline]
+    const fn new(data: &'a [u8], pos: usize) -> Self {
+        Self { data, pos }
+    }
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], ModuleError> {
+        let b = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or(ModuleError::Truncated)?;
+        self.pos += len;
+        Ok(b)
+    }
+    #[inline]
+    fn u8(&mut self) -> Result<u8, ModuleError> {
+        Ok(self.bytes(1)?[0])
+    }
+    #[inline]
+    fn u16_be(&mut self) -> Result<u16, ModuleError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+    #[inline]
+    fn u16_le(&mut self) -> Result<u16, ModuleError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+    #[inline]
+    fn u32_le(&mut self) -> Result<u32, ModuleError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+// Loop points are in samples. Data past a loop is never played so it is cut
+// off, and ping-pong loops are unrolled into a forward loop.
+fn make_sample(mut data: Vec<i16>, start: usize, len: usize, pingpong: bool) -> Option<Sample> {
+    if data.is_empty() {
+        return None;
+    }
+    let loop_start = if len > 0 && start < data.len() {
+        let end = (start + len).min(data.len());
+        data.truncate(end);
+        if pingpong && end - start > 2 {
+            for i in (start + 1..end - 1).rev() {
+                let v = data[i];
+                data.push(v);
+            }
+        }
+        Some(start)
+    } else {
+        None
+    };
+    Some(Sample {
+        data: Rc::from(data),
+        rate: BASE_RATE as u32,
+        loop_start,
+    })
+}
+
+// Nearest note to a MOD period, so that both formats run off notes.
+fn amiga_period_to_note(periods: &[i32; NOTES], period: u16) -> u8 {
+    if period == 0 {
+        return 0;
+    }
+    (0..NOTES)
+        .min_by_key(|&n| (periods[n] - period as i32).abs())
+        .map_or(0, |n| n as u8 + 1)
+}
+
+// `x` is the note in 1/64 semitones, including finetune.
+fn note_period(linear: bool, x: i32) -> i32 {
+    let period = if linear {
+        LINEAR_NOTE0_PERIOD - x
+    } else {
+        ((AMIGA_NOTE0_PERIOD << 16) / exp2_q16(x).max(1)) as i32
+    };
+    period.clamp(MIN_PERIOD, MAX_PERIOD)
+}
+
+fn period_frequency(linear: bool, period: i32) -> u32 {
+    if linear {
+        ((BASE_RATE * exp2_q16(LINEAR_C4_PERIOD - period)) >> 16) as u32
+    } else {
+        (BASE_RATE * AMIGA_C4_PERIOD / period.max(MIN_PERIOD) as u64) as u32
+    }
+}
+
+impl Module {
+    pub fn parse(data: &[u8]) -> Result<Self, ModuleError> {
+        if data.starts_with(b"Extended Module: ") {
+            Self::parse_xm(data)
+        } else {
+            Self::parse_mod(data)
+        }
+    }
+
+    fn mod_channels(signature: &[u8]) -> Option<usize> {
+        match signature {
+            b"M.K." | b"M!K!" | b"FLT4" | b"4CHN" => Some(4),
+            [n @ b'1'..=b'9', b'C', b'H', b'N'] => Some((n - b'0') as usize),
+            [a @ b'0'..=b'9', b @ b'0'..=b'9', b'C', b'H'] => {
+                Some(((a - b'0') * 10 + (b - b'0')) as usize)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn parse_mod(data: &[u8]) -> Result<Self, ModuleError> {
+        let signature = data.get(1080..1084).ok_or(ModuleError::Truncated)?;
+        let channels = Self::mod_channels(signature).ok_or(ModuleError::Unsupported)?;
+        if channels == 0 {
+            return Err(ModuleError::Invalid);
+        }
+        let mut r = Reader::new(data, 20);
+        let mut headers = [(0, 0, 0, 0, 0); 31];
+        for header in &mut headers {
+            r.bytes(22)?;
+            let len = r.u16_be()? as usize * 2;
+            let finetune = ((r.u8()? << 4) as i8) >> 4;
+            let volume = r.u8()?.min(MAX_VOLUME as u8);
+            let loop_start = r.u16_be()? as usize * 2;
+            let loop_len = r.u16_be()? as usize * 2;
+            *header = (len, finetune, volume, loop_start, loop_len);
+        }
+        let song_len = (r.u8()? as usize).clamp(1, 128);
+        let restart = r.u8()? as usize;
+        let orders = r.bytes(128)?;
+        let patterns = orders.iter().copied().max().unwrap_or(0) as usize + 1;
+        let orders = orders[..song_len].to_vec();
+        let periods = core::array::from_fn(|n| note_period(false, n as i32 * 64));
+        let mut r = Reader::new(data, 1084);
+        let mut pats = Vec::with_capacity(patterns);
+        for _ in 0..patterns {
+            let mut cells = Vec::with_capacity(64 * channels);
+            for _ in 0..64 * channels {
+                let b = r.bytes(4)?;
+                let period = u16::from_be_bytes([b[0] & 0x0F, b[1]]);
+                cells.push(Cell {
+                    note: amiga_period_to_note(&periods, period),
+                    instrument: (b[0] & 0xF0) | (b[2] >> 4),
+                    volume: 0,
+                    effect: b[2] & 0x0F,
+                    param: b[3],
+                });
+            }
+            pats.push(Pattern { rows: 64, cells });
+        }
+        let mut instruments = Vec::with_capacity(headers.len());
+        for (len, finetune, volume, loop_start, loop_len) in headers {
+            // Sample data may be cut short at the end of the file.
+            let raw = match r.bytes(len) {
+                Ok(raw) => raw,
+                Err(_) => {
+                    let raw = &data[r.pos.min(data.len())..];
+                    r.pos += len;
+                    raw
+                }
+            };
+            let pcm = raw.iter().map(|&b| ((b as i8) as i16) << 8).collect();
+            let loop_len = if loop_len > 2 { loop_len } else { 0 };
+            instruments.push(Instrument {
+                samples: vec![SampleInfo {
+                    sample: make_sample(pcm, loop_start, loop_len, false),
+                    volume,
+                    finetune: finetune.saturating_mul(16),
+                    panning: 128,
+                    relative_note: 0,
+                }],
+                keymap: [0; NOTES],
+                volume_envelope: None,
+                fadeout: 0,
+            });
+        }
+        Ok(Self {
+            kind: ModuleKind::Mod,
+            channels,
+            orders,
+            restart: if restart < song_len { restart } else { 0 },
+            patterns: pats,
+            instruments,
+            speed: 6,
+            tempo: 125,
+            linear: false,
+        })
+    }
+
+    pub fn parse_xm(data: &[u8]) -> Result<Self, ModuleError> {
+        let mut r = Reader::new(data, 58);
+        if r.u16_le()? != 0x0104 {
+            return Err(ModuleError::Unsupported);
+        }
+        let header_size = r.u32_le()? as usize;
+        let song_len = r.u16_le()? as usize;
+        let restart = r.u16_le()? as usize;
+        let channels = r.u16_le()? as usize;
+        let patterns = r.u16_le()? as usize;
+        let instruments = r.u16_le()? as usize;
+        let flags = r.u16_le()?;
+        let speed = r.u16_le()?;
+        let tempo = r.u16_le()?;
+        let orders = r.bytes(256)?;
+        if channels == 0 || song_len == 0 || song_len > 256 {
+            return Err(ModuleError::Invalid);
+        }
+        let orders = orders[..song_len].to_vec();
+
+        let mut r = Reader::new(data, 60 + header_size);
+        let mut pats = Vec::with_capacity(patterns);
+        for _ in 0..patterns {
+            let start = r.pos;
+            let len = r.u32_le()? as usize;
+            r.u8()?;
+            let rows = r.u16_le()? as usize;
+            let packed = r.u16_le()? as usize;
+            r.pos = start + len;
+            let mut p = Reader::new(r.bytes(packed)?, 0);
+            let mut cells = vec![Cell::default(); rows * channels];
+            if packed > 0 {
+                for cell in &mut cells {
+                    let b = p.u8()?;
+                    let mask = if b & 0x80 != 0 { b } else { 0x1F };
+                    let mut field = |bit: u8, first: Option<u8>| -> Result<u8, ModuleError> {
+                        match (mask & bit != 0, first) {
+                            (false, _) => Ok(0),
+                            (true, Some(v)) => Ok(v),
+                            (true, None) => p.u8(),
+                        }
+                    };
+                    let first = (b & 0x80 == 0).then_some(b);
+                    cell.note = field(0x01, first)?;
+                    cell.instrument = field(0x02, None)?;
+                    cell.volume = field(0x04, None)?;
+                    cell.effect = field(0x08, None)?;
+                    cell.param = field(0x10, None)?;
+                }
+            }
+            pats.push(Pattern { rows, cells });
+        }
+        // Orders may point at patterns that are not stored, which are empty.
+        let max_order = orders.iter().copied().max().unwrap_or(0) as usize;
+        while pats.len() <= max_order {
+            pats.push(Pattern {
+                rows: 64,
+                cells: vec![Cell::default(); 64 * channels],
+            });
+        }
+
+        let mut insts = Vec::with_capacity(instruments);
+        for _ in 0..instruments {
+            insts.push(Self::parse_xm_instrument(&mut r)?);
+        }
+        Ok(Self {
+            kind: ModuleKind::Xm,
+            channels,
+            orders,
+            restart: if restart < song_len { restart } else { 0 },
+            patterns: pats,
+            instruments: insts,
+            speed: speed.clamp(1, 31) as u8,
+            tempo: tempo.clamp(32, 255) as u8,
+            linear: flags & 1 != 0,
+        })
+    }
+
+    fn parse_xm_instrument(r: &mut Reader) -> Result<Instrument, ModuleError> {
+        let start = r.pos;
+        let size = r.u32_le()? as usize;
+        r.bytes(23)?;
+        let count = r.u16_le()? as usize;
+        let mut instrument = Instrument {
+            samples: Vec::with_capacity(count),
+            keymap: [0; NOTES],
+            volume_envelope: None,
+            fadeout: 0,
+        };
+        if count == 0 {
+            r.pos = start + size;
+            return Ok(instrument);
+        }
+        let sample_header_size = r.u32_le()? as usize;
+        instrument.keymap.copy_from_slice(r.bytes(NOTES)?);
+        let mut envelope = Envelope::default();
+        for point in &mut envelope.points {
+            *point = (r.u16_le()?, r.u16_le()?.min(MAX_VOLUME as u16) as u8);
+        }
+        r.bytes(48)?;
+        envelope.len = r.u8()?.min(ENVELOPE_POINTS as u8);
+        r.u8()?;
+        let sustain = r.u8()?;
+        let loop_start = r.u8()?;
+        let loop_end = r.u8()?;
+        r.bytes(3)?;
+        let kind = r.u8()?;
+        r.bytes(5)?;
+        instrument.fadeout = r.u16_le()?;
+        envelope.sustain = (kind & 2 != 0).then_some(sustain);
+        envelope.looping = (kind & 4 != 0).then_some((loop_start, loop_end));
+        if kind & 1 != 0 && envelope.len > 0 {
+            instrument.volume_envelope = Some(envelope);
+        }
+        r.pos = start + size;
+
+        let mut headers = Vec::with_capacity(count);
+        for _ in 0..count {
+            let header = r.pos;
+            let len = r.u32_le()? as usize;
+            let loop_start = r.u32_le()? as usize;
+            let loop_len = r.u32_le()? as usize;
+            let volume = r.u8()?.min(MAX_VOLUME as u8);
+            let finetune = r.u8()? as i8;
+            let flags = r.u8()?;
+            let panning = r.u8()?;
+            let relative_note = r.u8()? as i8;
+            r.pos = header + sample_header_size;
+            headers.push((
+                len,
+                loop_start,
+                loop_len,
+                volume,
+                finetune,
+                flags,
+                panning,
+                relative_note,
+            ));
+        }
+        for (len, loop_start, loop_len, volume, finetune, flags, panning, relative_note) in headers
+        {
+            let raw = r.bytes(len)?;
+            // Sample data is delta coded, in 8 or 16 bit steps.
+            let (pcm, width) = if flags & 0x10 != 0 {
+                let mut acc = 0i16;
+                let pcm = raw
+                    .chunks_exact(2)
+                    .map(|b| {
+                        acc = acc.wrapping_add(i16::from_le_bytes([b[0], b[1]]));
+                        acc
+                    })
+                    .collect();
+                (pcm, 2)
+            } else {
+                let mut acc = 0i8;
+                let pcm = raw
+                    .iter()
+                    .map(|&b| {
+                        acc = acc.wrapping_add(b as i8);
+                        (acc as i16) << 8
+                    })
+                    .collect();
+                (pcm, 1)
+            };
+            let looping = flags & 3;
+            let loop_len = if looping != 0 { loop_len / width } else { 0 };
+            instrument.samples.push(SampleInfo {
+                sample: make_sample(pcm, loop_start / width, loop_len, looping == 2),
+                volume,
+                finetune,
+                panning,
+                relative_note,
+            });
+        }
+        Ok(instrument)
+    }
+
+    #[inline]
+    fn cell(&self, pattern: usize, row: usize, channel: usize) -> Cell {
+        self.patterns
+            .get(pattern)
+            .and_then(|p| p.cells.get(row * self.channels + channel))
+            .copied()
+            .unwrap_or_default()
+    }
+    #[inline]
+    fn pattern_rows(&self, pattern: usize) -> usize {
+        self.patterns.get(pattern).map_or(64, |p| p.rows.max(1))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Channel {
+    instrument: Option<usize>,
+    sample: Option<(usize, usize)>,
+    note: u8,
+    period: i32,
+    target: i32,
+    volume: i32,
+    pan: u8,
+    effect: u8,
+    param: u8,
+    volume_column: u8,
+    porta_up: u8,
+    porta_down: u8,
+    porta_speed: u8,
+    vibrato_pos: u8,
+    vibrato_speed: u8,
+    vibrato_depth: u8,
+    volume_slide: u8,
+    offset: u8,
+    key_on: bool,
+    envelope_tick: u16,
+    fadeout: u32,
+}
+
+// Runs the module's sequencer and drives one mixer voice per channel. Tick
+// lengths are carried over in whole frames so rendering is exactly
+// repeatable for a given output rate.
+pub struct Player {
+    module: Module,
+    channels: Vec<Channel>,
+    order: usize,
+    row: usize,
+    tick: u8,
+    speed: u8,
+    tempo: u8,
+    global_volume: i32,
+    jump: Option<(usize, usize)>,
+    tick_frames: usize,
+    tick_remainder: u32,
+    looping: bool,
+    ended: bool,
+    finished: bool,
+}
+
+impl Player {
+    pub fn new(module: Module) -> Self {
+        let channels = (0..module.channels)
+            .map(|i| Channel {
+                pan: match module.kind {
+                    ModuleKind::Mod if matches!(i % 4, 1 | 2) => 0xC0,
+                    ModuleKind::Mod => 0x40,
+                    ModuleKind::Xm => 0x80,
+                },
+                fadeout: FADEOUT_MAX,
+                ..Channel::default()
+            })
+            .collect();
+        Self {
+            speed: module.speed,
+            tempo: module.tempo,
+            module,
+            channels,
+            order: 0,
+            row: 0,
+            tick: 0,
+            global_volume: MAX_VOLUME,
+            jump: None,
+            tick_frames: 0,
+            tick_remainder: 0,
+            looping: true,
+            ended: false,
+            finished: false,
+        }
+    }
+    #[inline]
+    pub const fn module(&self) -> &Module {
+        &self.module
+    }
+    #[inline]
+    pub fn set_looping(&mut self, looping: bool) {
+        self.looping = looping;
+    }
+    #[inline]
+    pub const fn finished(&self) -> bool {
+        self.finished
+    }
+    #[inline]
+    pub const fn position(&self) -> (usize, usize) {
+        (self.order, self.row)
+    }
+
+    // Renders interleaved stereo into `out`, running ticks as they fall due.
+    // The mixer needs at least as many voices as the module has channels,
+    // and room for as many frames as `out` holds.
+    pub fn render(&mut self, mixer: &mut Mixer, out: &mut [i16]) {
+        let mut pos = 0;
+        while out.len() - pos >= 2 {
+            if self.tick_frames == 0 {
+                self.run_tick(mixer);
+                // A tick lasts 2.5 / tempo seconds.
+                let total = mixer.rate() * 5 + self.tick_remainder;
+                let div = self.tempo as u32 * 2;
+                self.tick_frames = (total / div).max(1) as usize;
+                self.tick_remainder = total % div;
+            }
+            let n = self.tick_frames.min((out.len() - pos) / 2);
+            mixer.mix(&mut out[pos..pos + n * 2]);
+            pos += n * 2;
+            self.tick_frames -= n;
+        }
+    }
+
+    fn run_tick(&mut self, mixer: &mut Mixer) {
+        if self.finished {
+            return;
+        }
+        // The last row has played out, silence the song on the tick after.
+        if self.ended {
+            for ch in 0..self.channels.len() {
+                mixer.voice(ch).stop();
+            }
+            self.finished = true;
+            return;
+        }
+        if self.tick == 0 {
+            let pattern = self.module.orders[self.order] as usize;
+            for ch in 0..self.channels.len() {
+                let cell = self.module.cell(pattern, self.row, ch);
+                self.row_effects(mixer, ch, cell);
+            }
+        } else {
+            for ch in 0..self.channels.len() {
+                self.tick_effects(ch);
+            }
+        }
+        for ch in 0..self.channels.len() {
+            self.update_voice(mixer, ch);
+        }
+        self.tick += 1;
+        if self.tick >= self.speed {
+            self.tick = 0;
+            self.next_row();
+        }
+    }
+
+    fn next_row(&mut self) {
+        let (order, row) = match self.jump.take() {
+            Some(jump) => jump,
+            None => {
+                let pattern = self.module.orders[self.order] as usize;
+                if self.row + 1 < self.module.pattern_rows(pattern) {
+                    (self.order, self.row + 1)
+                } else {
+                    (self.order + 1, 0)
+                }
+            }
+        };
+        let (order, row) = if order >= self.module.orders.len() {
+            self.ended = !self.looping;
+            (self.module.restart, row)
+        } else {
+            (order, row)
+        };
+        let pattern = self.module.orders[order] as usize;
+        self.order = order;
+        self.row = if row < self.module.pattern_rows(pattern) {
+            row
+        } else {
+            0
+        };
+    }
+
+    #[inline]
+    fn sample_info(&self, sample: Option<(usize, usize)>) -> Option<&SampleInfo> {
+        let (inst, smp) = sample?;
+        self.module.instruments.get(inst)?.samples.get(smp)
+    }
+
+    fn lookup_sample(&self, instrument: Option<usize>, note: u8) -> Option<(usize, usize)> {
+        let inst = instrument?;
+        let keymap = &self.module.instruments.get(inst)?.keymap;
+        let smp = *keymap.get(note.checked_sub(1)? as usize)? as usize;
+        self.module.instruments[inst]
+            .samples
+            .get(smp)?
+            .sample
+            .as_ref()?;
+        Some((inst, smp))
+    }
+
+    fn note_period(&self, info: &SampleInfo, note: u8) -> i32 {
+        let n = note as i32 - 1 + info.relative_note as i32;
+        note_period(self.module.linear, n * 64 + info.finetune as i32 / 2)
+    }
+
+    fn key_off(&mut self, ch: usize) {
+        let c = &mut self.channels[ch];
+        c.key_on = false;
+        let envelope = c
+            .instrument
+            .and_then(|i| self.module.instruments.get(i))
+            .and_then(|i| i.volume_envelope);
+        if envelope.is_none() {
+            c.volume = 0;
+        }
+    }
+
+    fn row_effects(&mut self, mixer: &mut Mixer, ch: usize, cell: Cell) {
+        let linear = self.module.linear;
+        let porta = matches!(cell.effect, 0x3 | 0x5) || cell.volume >> 4 == 0xF;
+        let c = &mut self.channels[ch];
+        c.effect = cell.effect;
+        c.param = cell.param;
+        c.volume_column = cell.volume;
+        if cell.instrument != 0 {
+            c.instrument = Some(cell.instrument as usize - 1);
+        }
+        let note = if (1..=NOTES as u8).contains(&cell.note) {
+            cell.note
+        } else {
+            self.channels[ch].note
+        };
+        let sample = self.lookup_sample(self.channels[ch].instrument, note);
+
+        let mut trigger = None;
+        if (1..=NOTES as u8).contains(&cell.note)
+            && let Some(info) = self.sample_info(sample)
+        {
+            let period = self.note_period(info, cell.note);
+            let data = info.sample.clone();
+            let c = &mut self.channels[ch];
+            c.note = cell.note;
+            c.target = period;
+            if !porta || c.sample.is_none() || !mixer.voices()[ch].is_active() {
+                c.period = period;
+                c.sample = sample;
+                c.vibrato_pos = 0;
+                trigger = data;
+            }
+        }
+        if cell.instrument != 0
+            && let Some(info) = self.sample_info(sample)
+        {
+            let (volume, panning) = (info.volume as i32, info.panning);
+            let c = &mut self.channels[ch];
+            c.volume = volume;
+            if self.module.kind == ModuleKind::Xm {
+                c.pan = panning;
+            }
+            c.key_on = true;
+            c.envelope_tick = 0;
+            c.fadeout = FADEOUT_MAX;
+        }
+        if let Some(sample) = trigger {
+            let voice = mixer.voice(ch);
+            voice.set_adsr(Adsr::NONE);
+            voice.start(sample, 0);
+            if cell.effect == 0x9 {
+                let c = &mut self.channels[ch];
+                if cell.param != 0 {
+                    c.offset = cell.param;
+                }
+                mixer.voice(ch).set_position(c.offset as usize * 256);
+            }
+        }
+        if cell.note == NOTE_KEY_OFF {
+            self.key_off(ch);
+        }
+
+        let c = &mut self.channels[ch];
+        match cell.volume {
+            0x10..=0x50 => c.volume = (cell.volume - 0x10) as i32,
+            0x80..=0x8F => c.volume = (c.volume - (cell.volume & 0x0F) as i32).max(0),
+            0x90..=0x9F => c.volume = (c.volume + (cell.volume & 0x0F) as i32).min(MAX_VOLUME),
+            0xC0..=0xCF => c.pan = (cell.volume & 0x0F) * 17,
+            0xF0..=0xFF if cell.volume & 0x0F != 0 => c.porta_speed = (cell.volume & 0x0F) << 4,
+            _ => {}
+        }
+
+        let param = cell.param;
+        let (x, y) = (param >> 4, param & 0x0F);
+        match cell.effect {
+            0x1 if param != 0 => c.porta_up = param,
+            0x2 if param != 0 => c.porta_down = param,
+            0x3 if param != 0 => c.porta_speed = param,
+            0x4 => {
+                if x != 0 {
+                    c.vibrato_speed = x;
+                }
+                if y != 0 {
+                    c.vibrato_depth = y;
+                }
+            }
+            0x5 | 0x6 | 0xA if param != 0 => c.volume_slide = param,
+            0x8 => c.pan = param,
+            0xB => {
+                let row = self.jump.map_or(0, |(_, row)| row);
+                self.jump = Some((param as usize, row));
+            }
+            0xC => c.volume = (param as i32).min(MAX_VOLUME),
+            0xD => {
+                let order = self.jump.map_or(self.order + 1, |(order, _)| order);
+                self.jump = Some((order, (x * 10 + y) as usize));
+            }
+            0xE => match x {
+                0x1 => c.period = Self::slide_period(linear, c.period, -(y as i32)),
+                0x2 => c.period = Self::slide_period(linear, c.period, y as i32),
+                0xA => c.volume = (c.volume + y as i32).min(MAX_VOLUME),
+                0xB => c.volume = (c.volume - y as i32).max(0),
+                0xC if y == 0 => c.volume = 0,
+                _ => {}
+            },
+            0xF if param != 0 => {
+                if param < 0x20 {
+                    self.speed = param;
+                } else {
+                    self.tempo = param;
+                }
+            }
+            0x10 => self.global_volume = (param as i32).min(MAX_VOLUME),
+            0x14 if param == 0 => self.key_off(ch),
+            _ => {}
+        }
+    }
+
+    #[inline]
+    fn slide_period(linear: bool, period: i32, amount: i32) -> i32 {
+        let scale = if linear { 4 } else { 1 };
+        (period + amount * scale).clamp(MIN_PERIOD, MAX_PERIOD)
+    }
+
+    fn volume_slide(c: &mut Channel) {
+        let (x, y) = (c.volume_slide >> 4, c.volume_slide & 0x0F);
+        c.volume = if x != 0 {
+            (c.volume + x as i32).min(MAX_VOLUME)
+        } else {
+            (c.volume - y as i32).max(0)
+        };
+    }
+
+    fn tone_portamento(&mut self, ch: usize) {
+        let c = &self.channels[ch];
+        let step = Self::slide_period(self.module.linear, 0, c.porta_speed as i32);
+        let c = &mut self.channels[ch];
+        c.period = if c.period < c.target {
+            (c.period + step).min(c.target)
+        } else {
+            (c.period - step).max(c.target)
+        };
+    }
+
+    fn tick_effects(&mut self, ch: usize) {
+        let linear = self.module.linear;
+        let tick = self.tick;
+        let c = &mut self.channels[ch];
+        match c.volume_column {
+            0x60..=0x6F => c.volume = (c.volume - (c.volume_column & 0x0F) as i32).max(0),
+            0x70..=0x7F => c.volume = (c.volume + (c.volume_column & 0x0F) as i32).min(MAX_VOLUME),
+            0xF0..=0xFF => self.tone_portamento(ch),
+            _ => {}
+        }
+        let c = &mut self.channels[ch];
+        let (x, y) = (c.param >> 4, c.param & 0x0F);
+        match c.effect {
+            0x1 => c.period = Self::slide_period(linear, c.period, -(c.porta_up as i32)),
+            0x2 => c.period = Self::slide_period(linear, c.period, c.porta_down as i32),
+            0x3 => self.tone_portamento(ch),
+            0x4 => c.vibrato_pos = (c.vibrato_pos + c.vibrato_speed) & 63,
+            0x5 => {
+                Self::volume_slide(c);
+                self.tone_portamento(ch);
+            }
+            0x6 => {
+                Self::volume_slide(c);
+                c.vibrato_pos = (c.vibrato_pos + c.vibrato_speed) & 63;
+            }
+            0xA => Self::volume_slide(c),
+            0xE if x == 0xC && tick == y => c.volume = 0,
+            0x11 => {
+                let (x, y) = (x as i32, y as i32);
+                self.global_volume = if x != 0 {
+                    (self.global_volume + x).min(MAX_VOLUME)
+                } else {
+                    (self.global_volume - y).max(0)
+                };
+            }
+            0x14 if tick == c.param => self.key_off(ch),
+            _ => {}
+        }
+    }
+
+    fn update_voice(&mut self, mixer: &mut Mixer, ch: usize) {
+        let linear = self.module.linear;
+        let tick = self.tick;
+        let global = self.global_volume;
+        let c = &self.channels[ch];
+        let instrument = c.instrument.and_then(|i| self.module.instruments.get(i));
+        if c.sample.is_none() || !mixer.voices()[ch].is_active() {
+            return;
+        }
+
+        let mut period = c.period;
+        if matches!(c.effect, 0x4 | 0x6) {
+            let sine = VIBRATO_SINE[(c.vibrato_pos & 31) as usize];
+            let delta = (sine * c.vibrato_depth as i32) >> if linear { 5 } else { 7 };
+            period += if c.vibrato_pos & 32 != 0 {
+                -delta
+            } else {
+                delta
+            };
+        }
+        let mut frequency = period_frequency(linear, period.clamp(MIN_PERIOD, MAX_PERIOD)) as u64;
+        if c.effect == 0 && c.param != 0 {
+            let semitones = match tick % 3 {
+                0 => 0,
+                1 => c.param >> 4,
+                _ => c.param & 0x0F,
+            };
+            frequency = (frequency * exp2_q16(semitones as i32 * 64)) >> 16;
+        }
+
+        let envelope = instrument.and_then(|i| i.volume_envelope);
+        let level = envelope.map_or(MAX_VOLUME, |e| e.value(c.envelope_tick));
+        let volume = c.volume as u64 * level as u64 * global as u64 * c.fadeout as u64;
+        let volume = volume * VOLUME_MAX as u64 / (64 * 64 * 64 * FADEOUT_MAX as u64);
+        let pan = c.pan;
+
+        mixer.set_frequency(ch, frequency as u32);
+        let voice = mixer.voice(ch);
+        voice.set_volume(volume as u16);
+        voice.set_pan(pan);
+
+        let fadeout = instrument.map_or(0, |i| i.fadeout as u32);
+        let c = &mut self.channels[ch];
+        if let Some(envelope) = envelope {
+            c.envelope_tick = envelope.advance(c.envelope_tick, c.key_on);
+            if !c.key_on {
+                c.fadeout = c.fadeout.saturating_sub(fadeout);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 8000;
+    // At 8kHz and tempo 125 a tick is exactly 160 frames.
+    const TICK: usize = 160;
+    const C4: u8 = 49;
+
+    fn cell(period: u16, instrument: u8, effect: u8, param: u8) -> [u8; 4] {
+        [
+            (instrument & 0xF0) | (period >> 8) as u8,
+            period as u8,
+            (instrument << 4) | effect,
+            param,
+        ]
+    }
+
+    // Four channel M.K. module with two looped 32 byte samples of constant
+    // level, +64 and -64.
+    fn module() -> Vec<u8> {
+        let mut data = vec![0; 1084];
+        for header in [20, 50] {
+            data[header + 22..header + 30].copy_from_slice(&[0, 16, 0, 64, 0, 0, 0, 16]);
+        }
+        data[950] = 2;
+        data[952..954].copy_from_slice(&[0, 1]);
+        data[1080..1084].copy_from_slice(b"M.K.");
+        let mut patterns = vec![0; 2 * 1024];
+        let mut put = |pattern: usize, row: usize, ch: usize, c: [u8; 4]| {
+            let at = pattern * 1024 + (row * 4 + ch) * 4;
+            patterns[at..at + 4].copy_from_slice(&c);
+        };
+        put(0, 0, 0, cell(428, 1, 0xF, 2));
+        put(0, 0, 1, cell(428, 2, 0xC, 32));
+        put(0, 1, 0, cell(0, 0, 0xC, 16));
+        put(0, 2, 0, cell(0, 0, 0xD, 0));
+        put(1, 0, 0, cell(0, 0, 0xD, 0));
+        data.extend_from_slice(&patterns);
+        data.extend_from_slice(&[0x40; 32]);
+        data.extend_from_slice(&[0xC0; 32]);
+        data
+    }
+
+    fn render(module: &[u8], frames: usize, chunk: usize) -> Vec<i16> {
+        let mut player = Player::new(Module::parse(module).unwrap());
+        player.set_looping(false);
+        let mut mixer = Mixer::new(4, RATE, chunk);
+        let mut out = vec![0; frames * 2];
+        for part in out.chunks_mut(chunk * 2) {
+            player.render(&mut mixer, part);
+        }
+        assert!(player.finished());
+        out
+    }
+
+    fn sample(module: &Module, instrument: usize) -> &Sample {
+        module.instruments[instrument].samples[0]
+            .sample
+            .as_ref()
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_mod() {
+        let module = Module::parse(&module()).unwrap();
+        assert_eq!(module.kind, ModuleKind::Mod);
+        assert_eq!(module.channels, 4);
+        assert_eq!(module.orders, [0, 1]);
+        assert_eq!(module.patterns.len(), 2);
+        assert_eq!(module.instruments.len(), 31);
+        assert_eq!(*sample(&module, 0).data, [16384; 32]);
+        assert_eq!(*sample(&module, 1).data, [-16384; 32]);
+        assert_eq!(sample(&module, 1).loop_start, Some(0));
+        assert!(module.instruments[2].samples[0].sample.is_none());
+    }
+
+    #[test]
+    fn parse_truncated_sample() {
+        let mut data = module();
+        data.truncate(data.len() - 10);
+        let parsed = Module::parse(&data).unwrap();
+        assert_eq!(*sample(&parsed, 0).data, [16384; 32]);
+        assert_eq!(*sample(&parsed, 1).data, [-16384; 22]);
+        assert_eq!(
+            Module::parse(&data[..1000]).err(),
+            Some(ModuleError::Truncated)
+        );
+        let mut data = module();
+        data[1080..1084].copy_from_slice(b"????");
+        assert_eq!(Module::parse(&data).err(), Some(ModuleError::Unsupported));
+    }
+
+    // Two channel XM with a mixed packed pattern, an empty stored pattern
+    // and an order past the stored ones. The first instrument has an 8 bit
+    // ping-pong sample and a 16 bit forward looped one, the second none.
+    fn xm() -> Vec<u8> {
+        let mut data = b"Extended Module: ".to_vec();
+        data.resize(58, b' ');
+        data[37] = 0x1A;
+        data.extend_from_slice(&0x0104u16.to_le_bytes());
+        data.extend_from_slice(&276u32.to_le_bytes());
+        for v in [3u16, 1, 2, 2, 2, 1, 3, 150] {
+            data.extend_from_slice(&v.to_le_bytes());
+        }
+        let mut orders = [0; 256];
+        orders[..3].copy_from_slice(&[0, 1, 3]);
+        data.extend_from_slice(&orders);
+
+        // An unpacked cell, then packed cells with only some fields present.
+        let packed = [
+            &[C4, 1, 0x30, 0xA, 0x05][..],
+            &[0x80 | 0x01 | 0x08 | 0x10, NOTE_KEY_OFF, 0xC, 0x20],
+            &[0x80 | 0x02 | 0x04, 2, 0x9F],
+            &[0x80],
+        ]
+        .concat();
+        for (rows, cells) in [(2u16, &packed[..]), (4, &[][..])] {
+            data.extend_from_slice(&9u32.to_le_bytes());
+            data.push(0);
+            data.extend_from_slice(&rows.to_le_bytes());
+            data.extend_from_slice(&(cells.len() as u16).to_le_bytes());
+            data.extend_from_slice(cells);
+        }
+
+        let start = data.len();
+        data.extend_from_slice(&263u32.to_le_bytes());
+        data.extend_from_slice(&[0; 23]);
+        data.extend_from_slice(&2u16.to_le_bytes());
+        data.extend_from_slice(&40u32.to_le_bytes());
+        data.extend((0..NOTES).map(|n| (n >= 48) as u8));
+        for (x, y) in [(0u16, 64u16), (10, 32), (20, 80)]
+            .into_iter()
+            .chain([(0, 0); 9])
+        {
+            data.extend_from_slice(&x.to_le_bytes());
+            data.extend_from_slice(&y.to_le_bytes());
+        }
+        data.extend_from_slice(&[0; 48]);
+        data.extend_from_slice(&[3, 0, 1, 0, 2, 0, 0, 0, 1 | 2 | 4, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&0x100u16.to_le_bytes());
+        data.resize(start + 263, 0);
+        for (len, loop_start, loop_len, volume, finetune, flags, panning, note) in [
+            (4u32, 1u32, 3u32, 48, 0x10, 2, 0x40, 0),
+            (6, 2, 4, 80, 0xF0, 0x10 | 1, 0x20, 0xF4),
+        ] {
+            for v in [len, loop_start, loop_len] {
+                data.extend_from_slice(&v.to_le_bytes());
+            }
+            data.extend_from_slice(&[volume, finetune, flags, panning, note]);
+            data.extend_from_slice(&[0; 23]);
+        }
+        data.extend_from_slice(&[0x10, 0x10, 0xF0, 0x20]);
+        for delta in [1000i16, -3000, 500] {
+            data.extend_from_slice(&delta.to_le_bytes());
+        }
+
+        data.extend_from_slice(&29u32.to_le_bytes());
+        data.extend_from_slice(&[0; 25]);
+        data
+    }
+
+    #[test]
+    fn parse_xm() {
+        let module = Module::parse(&xm()).unwrap();
+        assert_eq!(module.kind, ModuleKind::Xm);
+        assert_eq!(module.channels, 2);
+        assert_eq!(module.orders, [0, 1, 3]);
+        assert_eq!(module.restart, 1);
+        assert_eq!((module.speed, module.tempo), (3, 150));
+        assert!(module.linear);
+
+        let c4 = Cell {
+            note: C4,
+            instrument: 1,
+            volume: 0x30,
+            effect: 0xA,
+            param: 0x05,
+        };
+        let off = Cell {
+            note: NOTE_KEY_OFF,
+            effect: 0xC,
+            param: 0x20,
+            ..Cell::default()
+        };
+        let volume = Cell {
+            instrument: 2,
+            volume: 0x9F,
+            ..Cell::default()
+        };
+        assert_eq!(module.patterns.len(), 4);
+        assert_eq!(module.patterns[0].rows, 2);
+        assert_eq!(module.patterns[0].cells, [c4, off, volume, Cell::default()]);
+        assert_eq!(module.patterns[1].rows, 4);
+        assert_eq!(module.patterns[1].cells, [Cell::default(); 8]);
+        assert_eq!(module.patterns[3].rows, 64);
+        assert_eq!(module.patterns[3].cells, [Cell::default(); 128]);
+    }
+
+    #[test]
+    fn parse_xm_instrument() {
+        let module = Module::parse(&xm()).unwrap();
+        assert_eq!(module.instruments.len(), 2);
+        let instrument = &module.instruments[0];
+        assert_eq!(instrument.keymap[47], 0);
+        assert_eq!(instrument.keymap[48], 1);
+        assert_eq!(instrument.fadeout, 0x100);
+        let envelope = instrument.volume_envelope.unwrap();
+        assert_eq!(envelope.points[..3], [(0, 64), (10, 32), (20, 64)]);
+        assert_eq!(envelope.len, 3);
+        assert_eq!(envelope.sustain, Some(1));
+        assert_eq!(envelope.looping, Some((0, 2)));
+
+        // Delta coded, with the ping-pong loop unrolled.
+        let [pingpong, forward] = &instrument.samples[..] else {
+            panic!("expected two samples");
+        };
+        let sample = pingpong.sample.as_ref().unwrap();
+        assert_eq!(*sample.data, [4096, 8192, 4096, 12288, 4096]);
+        assert_eq!(sample.loop_start, Some(1));
+        assert_eq!(
+            (pingpong.volume, pingpong.finetune, pingpong.panning),
+            (48, 16, 0x40)
+        );
+        let sample = forward.sample.as_ref().unwrap();
+        assert_eq!(*sample.data, [1000, -2000, -1500]);
+        assert_eq!(sample.loop_start, Some(1));
+        assert_eq!(
+            (forward.volume, forward.finetune, forward.relative_note),
+            (64, -16, -12)
+        );
+        assert!(module.instruments[1].samples.is_empty());
+    }
+
+    #[test]
+    fn parse_xm_errors() {
+        let data = xm();
+        assert_eq!(
+            Module::parse(&data[..data.len() - 30]).err(),
+            Some(ModuleError::Truncated)
+        );
+        let mut bad = data.clone();
+        bad[58] = 0x03;
+        assert_eq!(Module::parse(&bad).err(), Some(ModuleError::Unsupported));
+        let mut bad = data.clone();
+        bad[68..70].copy_from_slice(&[0, 0]);
+        assert_eq!(Module::parse(&bad).err(), Some(ModuleError::Invalid));
+    }
+
+    // At a 65536Hz output rate a voice's pitch step is its frequency in Hz.
+    const HZ: u32 = 1 << 16;
+
+    fn fx(note: u8, effect: u8, param: u8) -> Cell {
+        Cell {
+            note,
+            instrument: (note != 0) as u8,
+            volume: 0,
+            effect,
+            param,
+        }
+    }
+
+    // One pattern per slice, played in order at speed 6 with a single
+    // looped instrument at full volume.
+    fn effects(kind: ModuleKind, channels: usize, patterns: &[&[Cell]]) -> (Player, Mixer) {
+        let sample = Sample {
+            data: Rc::from([0; 4]),
+            rate: BASE_RATE as u32,
+            loop_start: Some(0),
+        };
+        let module = Module {
+            kind,
+            channels,
+            orders: (0..patterns.len() as u8).collect(),
+            restart: 0,
+            patterns: patterns
+                .iter()
+                .map(|cells| Pattern {
+                    rows: cells.len() / channels,
+                    cells: cells.to_vec(),
+                })
+                .collect(),
+            instruments: vec![Instrument {
+                samples: vec![SampleInfo {
+                    sample: Some(sample),
+                    volume: 64,
+                    finetune: 0,
+                    panning: 128,
+                    relative_note: 0,
+                }],
+                keymap: [0; NOTES],
+                volume_envelope: None,
+                fadeout: 0,
+            }],
+            speed: 6,
+            tempo: 125,
+            linear: kind == ModuleKind::Xm,
+        };
+        (Player::new(module), Mixer::new(channels, HZ, 0))
+    }
+
+    // Channel 0's period and voice frequency after each of `n` ticks.
+    fn ticks(player: &mut Player, mixer: &mut Mixer, n: usize) -> Vec<(i32, u32)> {
+        (0..n)
+            .map(|_| {
+                player.run_tick(mixer);
+                (player.channels[0].period, mixer.voices()[0].pitch())
+            })
+            .collect()
+    }
+
+    fn frequencies(player: &mut Player, mixer: &mut Mixer, n: usize) -> Vec<u32> {
+        ticks(player, mixer, n)
+            .into_iter()
+            .map(|(_, f)| f)
+            .collect()
+    }
+
+    #[test]
+    fn arpeggio() {
+        let (mut player, mut mixer) =
+            effects(ModuleKind::Xm, 1, &[&[fx(C4, 0x0, 0x47), fx(0, 0, 0)]]);
+        assert_eq!(
+            frequencies(&mut player, &mut mixer, 7),
+            [8363, 10536, 12530, 8363, 10536, 12530, 8363]
+        );
+    }
+
+    #[test]
+    fn porta() {
+        // Linear periods slide four units per step, Amiga periods one.
+        let (mut player, mut mixer) = effects(
+            ModuleKind::Xm,
+            1,
+            &[&[fx(C4, 0x1, 4), fx(0, 0x1, 0), fx(0, 0, 0)]],
+        );
+        let slide = ticks(&mut player, &mut mixer, 13);
+        assert_eq!(
+            slide[..4],
+            [(4608, 8363), (4592, 8484), (4576, 8608), (4560, 8733)]
+        );
+        // The parameter is remembered, and the slide stops with the effect.
+        assert_eq!(slide[6].0, 4608 - 5 * 16);
+        assert_eq!(slide[11].0, 4608 - 10 * 16);
+        assert_eq!(slide[12].0, 4608 - 10 * 16);
+
+        let (mut player, mut mixer) = effects(ModuleKind::Mod, 1, &[&[fx(C4, 0x2, 3)]]);
+        assert_eq!(
+            ticks(&mut player, &mut mixer, 4),
+            [(428, 8363), (431, 8304), (434, 8247), (437, 8190)]
+        );
+    }
+
+    #[test]
+    fn tone_portamento() {
+        let d4 = C4 + 2;
+        let (mut player, mut mixer) = effects(
+            ModuleKind::Xm,
+            1,
+            &[&[fx(C4, 0, 0), fx(d4, 0x3, 8), fx(0, 0x3, 0)]],
+        );
+        ticks(&mut player, &mut mixer, 6);
+        // The note sets the target without retriggering, then each tick
+        // moves towards it and stops there.
+        assert_eq!(
+            ticks(&mut player, &mut mixer, 7),
+            [
+                (4608, 8363),
+                (4576, 8608),
+                (4544, 8860),
+                (4512, 9119),
+                (4480, 9387),
+                (4480, 9387),
+                (4480, 9387),
+            ]
+        );
+        assert_eq!(player.channels[0].target, 4480);
+        assert_eq!(player.channels[0].note, d4);
+    }
+
+    #[test]
+    fn vibrato() {
+        let (mut player, mut mixer) = effects(
+            ModuleKind::Xm,
+            1,
+            &[&[fx(C4, 0x4, 0x48), fx(0, 0x6, 0x02), fx(0, 0, 0)]],
+        );
+        // The base period is left alone, only the voice is bent.
+        let bend = [
+            (4608, 8363),
+            (4608, 8183),
+            (4608, 8030),
+            (4608, 7936),
+            (4608, 7900),
+            (4608, 7936),
+        ];
+        assert_eq!(ticks(&mut player, &mut mixer, 6), bend);
+        // Vibrato with volume slide carries on from the same position.
+        let row = ticks(&mut player, &mut mixer, 6);
+        assert_eq!(row[0], (4608, 7936));
+        assert_eq!(player.channels[0].vibrato_pos, 40);
+        assert_eq!(player.channels[0].volume, 64 - 5 * 2);
+        assert_eq!(ticks(&mut player, &mut mixer, 1), [(4608, 8363)]);
+    }
+
+    #[test]
+    fn volume_slide() {
+        let start = Cell {
+            volume: 0x10 + 32,
+            ..fx(C4, 0xA, 0x30)
+        };
+        let (mut player, mut mixer) = effects(
+            ModuleKind::Xm,
+            1,
+            &[&[start, fx(0, 0xA, 0), fx(0, 0xA, 0x0F)]],
+        );
+        let mut volumes = Vec::new();
+        for _ in 0..18 {
+            player.run_tick(&mut mixer);
+            volumes.push(player.channels[0].volume);
+        }
+        assert_eq!(
+            volumes,
+            [
+                32, 35, 38, 41, 44, 47, // up 3 a tick
+                47, 50, 53, 56, 59, 62, // remembered
+                62, 47, 32, 17, 2, 0, // down 15, floored
+            ]
+        );
+    }
+
+    #[test]
+    fn jump_and_break() {
+        let mut first = [Cell::default(); 2 * 16];
+        first[2] = fx(0, 0xD, 0x12);
+        let mut second = [Cell::default(); 2 * 16];
+        second[2 * 13] = fx(0, 0xB, 0);
+        second[2 * 13 + 1] = fx(0, 0xD, 0x01);
+        let (mut player, mut mixer) = effects(ModuleKind::Xm, 2, &[&first, &second]);
+        player.speed = 1;
+        let mut positions = Vec::new();
+        for _ in 0..6 {
+            player.run_tick(&mut mixer);
+            positions.push(player.position());
+        }
+        // The break's row is BCD, and a jump and break on the same row
+        // combine into one position.
+        assert_eq!(
+            positions,
+            [(0, 1), (1, 12), (1, 13), (0, 1), (1, 12), (1, 13)]
+        );
+    }
+
+    // Channel 0 is panned left and channel 1 right, as on the Amiga. Worked
+    // out by hand from the mixer's pan and volume scaling.
+    #[test]
+    fn reference_render() {
+        let full = [10240, -2048];
+        let quiet = [1024, -5120];
+        let mut expected = full.repeat(2 * TICK);
+        // Rows 1 and 2 of the first pattern, then the only row played of the
+        // second before the song ends.
+        expected.extend(quiet.repeat(3 * 2 * TICK));
+        expected.extend([0; 2].repeat(TICK));
+        assert_eq!(render(&module(), 9 * TICK, 9 * TICK), expected);
+    }
+
+    #[test]
+    fn deterministic() {
+        let data = module();
+        let whole = render(&data, 9 * TICK, 9 * TICK);
+        assert_eq!(render(&data, 9 * TICK, 7), whole);
+        assert_eq!(render(&data, 9 * TICK, 1), whole);
+    }
+}